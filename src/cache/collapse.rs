@@ -0,0 +1,377 @@
+extern crate mioco;
+
+use std::collections::HashMap;
+use std::io::{self, Read};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use http::headers::Headers;
+use http::reply::Reply;
+use http::timeout;
+
+use self::mioco::sync::mpsc::{channel, Receiver, Sender};
+
+use super::Key;
+use super::vary::{self, Variant};
+
+/// Fetches from the origin that are in progress, so that concurrent misses
+/// for the same thing can share one instead of all going to the origin.
+pub struct Collapser {
+    fetches: Mutex<HashMap<Key, Arc<Fetch>>>,
+}
+
+/// How a request takes part in collapsed forwarding.
+pub enum Collapse {
+    /// Nobody else is fetching this, so it's up to this request.
+    Leader(Leader),
+    /// Someone else already is, so this request can wait for their reply.
+    Follower(Follower),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Status {
+    // The leader is still waiting for the origin to reply.
+    Waiting,
+    // The reply can't be shared, so followers have to fetch it themselves.
+    Declined,
+    // The reply head is available and the body is on its way.
+    Streaming,
+    Complete,
+    // The body was cut off partway through.
+    Aborted,
+}
+
+struct State {
+    status: Status,
+    reply: Option<(Reply, Variant)>,
+    body: Vec<u8>,
+    // Followers are woken up whenever anything changes.
+    waiters: Vec<Sender<()>>,
+}
+
+struct Fetch {
+    state: Mutex<State>,
+}
+
+impl Fetch {
+    fn update<F: FnOnce(&mut State)>(&self, f: F) {
+        let mut state = self.state.lock().unwrap();
+        f(&mut state);
+        state.waiters.retain(|waiter| waiter.send(()).is_ok());
+    }
+}
+
+impl Collapser {
+    pub fn new() -> Collapser {
+        Collapser {
+            fetches: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Join the fetch in progress for `key`, or start one.
+    pub fn join(collapser: &Arc<Collapser>, key: Key) -> Collapse {
+        let mut fetches = collapser.fetches.lock().unwrap();
+
+        if let Some(fetch) = fetches.get(&key) {
+            let (sender, receiver) = channel();
+            fetch.state.lock().unwrap().waiters.push(sender);
+
+            return Collapse::Follower(Follower {
+                fetch: fetch.clone(),
+                receiver: receiver,
+                timeout: None,
+                offset: 0,
+            });
+        }
+
+        let fetch = Arc::new(Fetch {
+            state: Mutex::new(State {
+                status: Status::Waiting,
+                reply: None,
+                body: Vec::new(),
+                waiters: Vec::new(),
+            }),
+        });
+
+        fetches.insert(key.clone(), fetch.clone());
+
+        Collapse::Leader(Leader {
+            collapser: collapser.clone(),
+            key: key,
+            fetch: fetch,
+        })
+    }
+
+    /// The number of fetches in progress.
+    pub fn len(&self) -> usize {
+        self.fetches.lock().unwrap().len()
+    }
+}
+
+/// The request that actually goes to the origin, on behalf of any followers.
+///
+/// Dropping it before the reply is published lets the followers go to the
+/// origin themselves. Dropping it before the body is finished cuts them off.
+pub struct Leader {
+    collapser: Arc<Collapser>,
+    key: Key,
+    fetch: Arc<Fetch>,
+}
+
+impl Leader {
+    /// Share `reply`, the reply to a request with `request_headers`.
+    pub fn publish(&self, reply: &Reply, request_headers: &Headers) {
+        let names = vary::vary_names(reply).unwrap_or(Vec::new());
+        let variant = Variant::select(&names, request_headers);
+
+        self.fetch.update(|state| {
+            state.status = Status::Streaming;
+            state.reply = Some((reply.clone(), variant));
+        });
+    }
+
+    /// Pass the next part of the body on.
+    pub fn send(&self, data: &[u8]) {
+        self.fetch.update(|state| state.body.extend(data));
+    }
+
+    /// Mark the body as finished, returning it.
+    pub fn finish(self) -> Vec<u8> {
+        let mut body = Vec::new();
+
+        self.fetch.update(|state| {
+            state.status = Status::Complete;
+            body = state.body.clone();
+        });
+
+        body
+    }
+}
+
+impl Drop for Leader {
+    fn drop(&mut self) {
+        {
+            let mut fetches = self.collapser.fetches.lock().unwrap();
+            let ours = fetches.get(&self.key).map_or(false, |fetch| Arc::ptr_eq(fetch, &self.fetch));
+            if ours {
+                fetches.remove(&self.key);
+            }
+        }
+
+        self.fetch.update(|state| {
+            state.status = match state.status {
+                Status::Waiting => Status::Declined,
+                Status::Streaming => Status::Aborted,
+                status => status,
+            };
+        });
+    }
+}
+
+/// A request waiting on the reply a leader is fetching. The body is read
+/// as it arrives.
+pub struct Follower {
+    fetch: Arc<Fetch>,
+    receiver: Receiver<()>,
+    timeout: Option<Duration>,
+    offset: usize,
+}
+
+impl Follower {
+    /// Stop waiting if the leader goes `timeout` without getting anywhere.
+    pub fn set_timeout(&mut self, timeout: Option<Duration>) {
+        self.timeout = timeout;
+    }
+
+    fn wait(&self) -> io::Result<()> {
+        if let Some(timeout) = self.timeout {
+            if !timeout::readable(&self.receiver, timeout) {
+                return Err(io::Error::new(io::ErrorKind::TimedOut, format!("collapsed fetch stalled for {}s", timeout.as_secs())));
+            }
+        }
+
+        match self.receiver.recv() {
+            Ok(()) => Ok(()),
+            Err(_) => Err(io::Error::new(io::ErrorKind::UnexpectedEof, "collapsed fetch was abandoned")),
+        }
+    }
+
+    /// Wait for the leader's reply, and the variant it was selected as.
+    ///
+    /// Returns None if it can't be shared, or doesn't arrive in time.
+    pub fn reply(&mut self) -> Option<(Reply, Variant)> {
+        loop {
+            {
+                let state = self.fetch.state.lock().unwrap();
+                match state.status {
+                    Status::Waiting => {},
+                    Status::Declined => return None,
+                    _ => return state.reply.clone(),
+                }
+            }
+
+            if self.wait().is_err() {
+                return None;
+            }
+        }
+    }
+}
+
+impl Read for Follower {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            {
+                let state = self.fetch.state.lock().unwrap();
+
+                if self.offset < state.body.len() {
+                    let available = &state.body[self.offset..];
+                    let n = if available.len() < buf.len() { available.len() } else { buf.len() };
+                    buf[..n].copy_from_slice(&available[..n]);
+                    self.offset += n;
+                    return Ok(n);
+                }
+
+                match state.status {
+                    Status::Complete => return Ok(0),
+                    Status::Aborted | Status::Declined => {
+                        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "collapsed fetch was cut off"));
+                    },
+                    Status::Waiting | Status::Streaming => {},
+                }
+            }
+
+            try!(self.wait());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate mioco;
+    extern crate url;
+
+    use std::io::{self, Read};
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+
+    use http::headers::Headers;
+    use http::reply::Reply;
+
+    use super::{Collapse, Collapser, Follower, Leader};
+    use super::super::Key;
+
+    fn key(path: &str) -> Key {
+        Key::new("GET", &url::Url::parse(&format!("http://example.com{}", path)).unwrap())
+    }
+
+    fn join(collapser: &Arc<Collapser>, path: &str) -> Result<Leader, Follower> {
+        match Collapser::join(collapser, key(path)) {
+            Collapse::Leader(leader) => Ok(leader),
+            Collapse::Follower(follower) => Err(follower),
+        }
+    }
+
+    fn reply() -> Reply {
+        let mut headers = Headers::new();
+        headers.insert("Vary", &b"Accept".to_vec());
+
+        Reply {
+            version: 1,
+            code: 200,
+            reason: String::from("OK"),
+            headers: headers,
+        }
+    }
+
+    #[test]
+    fn test_share() {
+        let collapser = Arc::new(Collapser::new());
+
+        let leader = join(&collapser, "/a").ok().unwrap();
+        let mut follower = join(&collapser, "/a").err().unwrap();
+        assert!(join(&collapser, "/b").is_ok());
+
+        let waiting = thread::spawn(move || {
+            let (reply, variant) = follower.reply().unwrap();
+            let mut body = Vec::new();
+            follower.read_to_end(&mut body).unwrap();
+            (reply.code, variant.names(), body)
+        });
+
+        leader.publish(&reply(), &Headers::new());
+        leader.send(b"Hello, ");
+
+        // Latecomers still get the whole body.
+        let mut late = join(&collapser, "/a").err().unwrap();
+
+        leader.send(b"world");
+        assert_eq!(leader.finish(), b"Hello, world");
+        assert_eq!(collapser.len(), 0);
+
+        let (code, names, body) = waiting.join().unwrap();
+        assert_eq!(code, 200);
+        assert_eq!(names, vec![String::from("accept")]);
+        assert_eq!(body, b"Hello, world");
+
+        assert!(late.reply().is_some());
+        let mut body = Vec::new();
+        late.read_to_end(&mut body).unwrap();
+        assert_eq!(body, b"Hello, world");
+    }
+
+    #[test]
+    fn test_declined() {
+        let collapser = Arc::new(Collapser::new());
+
+        let leader = join(&collapser, "/a").ok().unwrap();
+        let mut follower = join(&collapser, "/a").err().unwrap();
+
+        drop(leader);
+        assert!(follower.reply().is_none());
+
+        // The next request leads a new fetch.
+        assert!(join(&collapser, "/a").is_ok());
+    }
+
+    #[test]
+    fn test_aborted() {
+        let collapser = Arc::new(Collapser::new());
+
+        let leader = join(&collapser, "/a").ok().unwrap();
+        let mut follower = join(&collapser, "/a").err().unwrap();
+
+        leader.publish(&reply(), &Headers::new());
+        leader.send(b"Hel");
+        drop(leader);
+
+        assert!(follower.reply().is_some());
+        let mut body = Vec::new();
+        assert!(follower.read_to_end(&mut body).is_err());
+        assert_eq!(body, b"Hel");
+    }
+
+    #[test]
+    fn test_stalled() {
+        mioco::start(|| {
+            let collapser = Arc::new(Collapser::new());
+
+            let leader = join(&collapser, "/a").ok().unwrap();
+            let mut follower = join(&collapser, "/a").err().unwrap();
+            follower.set_timeout(Some(Duration::from_millis(10)));
+
+            // Nothing from the origin yet, so the follower gives up waiting.
+            assert!(follower.reply().is_none());
+
+            let mut follower = join(&collapser, "/a").err().unwrap();
+            follower.set_timeout(Some(Duration::from_millis(10)));
+            leader.publish(&reply(), &Headers::new());
+            leader.send(b"Hel");
+
+            assert!(follower.reply().is_some());
+            let mut body = Vec::new();
+            assert_eq!(follower.read_to_end(&mut body).unwrap_err().kind(), io::ErrorKind::TimedOut);
+            assert_eq!(body, b"Hel");
+        }).unwrap();
+    }
+}
@@ -0,0 +1,202 @@
+use std::str;
+use std::time::Duration;
+
+use http::headers::Headers;
+
+/// The directives of the Cache-Control headers of a message, as per RFC 7234
+/// section 5.2.
+///
+/// Directives that weren't given are `false` or `None`. Unknown directives are
+/// ignored, as are ones with invalid arguments.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct CacheControl {
+    pub max_age: Option<Duration>,
+    pub s_maxage: Option<Duration>,
+    pub min_fresh: Option<Duration>,
+    /// `Some(None)` for a bare `max-stale`, meaning any staleness is fine.
+    pub max_stale: Option<Option<Duration>>,
+    pub no_cache: bool,
+    pub no_store: bool,
+    pub no_transform: bool,
+    pub only_if_cached: bool,
+    pub must_revalidate: bool,
+    pub proxy_revalidate: bool,
+    pub public: bool,
+    pub private: bool,
+    /// How long a stale reply may be served while it's revalidated in the
+    /// background, as per RFC 5861 section 3.
+    pub stale_while_revalidate: Option<Duration>,
+    /// How long a stale reply may be served if revalidating it fails, as per
+    /// RFC 5861 section 4.
+    pub stale_if_error: Option<Duration>,
+}
+
+impl CacheControl {
+    pub fn from_headers(headers: &Headers) -> CacheControl {
+        let mut control = CacheControl::default();
+
+        for value in headers.get_all("cache-control") {
+            let value = match str::from_utf8(value) {
+                Ok(value) => value,
+                Err(_) => continue,
+            };
+
+            for directive in split_directives(value) {
+                control.apply(&directive);
+            }
+        }
+
+        control
+    }
+
+    fn apply(&mut self, directive: &str) {
+        let (name, argument) = match directive.find('=') {
+            Some(i) => (directive[..i].trim(), Some(unquote(directive[i + 1..].trim()))),
+            None => (directive.trim(), None),
+        };
+
+        let seconds = argument.as_ref().and_then(|argument| parse_seconds(argument));
+
+        match &name.to_lowercase()[..] {
+            "max-age" => self.max_age = seconds,
+            "s-maxage" => self.s_maxage = seconds,
+            "min-fresh" => self.min_fresh = seconds,
+            "max-stale" => {
+                self.max_stale = match argument {
+                    Some(_) => seconds.map(Some),
+                    None => Some(None),
+                };
+            },
+            // The field-name forms of no-cache and private only restrict
+            // some headers, but treating them like the plain directives errs
+            // on the safe side.
+            "no-cache" => self.no_cache = true,
+            "private" => self.private = true,
+            "no-store" => self.no_store = true,
+            "no-transform" => self.no_transform = true,
+            "only-if-cached" => self.only_if_cached = true,
+            "must-revalidate" => self.must_revalidate = true,
+            "proxy-revalidate" => self.proxy_revalidate = true,
+            "public" => self.public = true,
+            "stale-while-revalidate" => self.stale_while_revalidate = seconds,
+            "stale-if-error" => self.stale_if_error = seconds,
+            _ => {},
+        }
+    }
+}
+
+/// Parse a delta-seconds value. Values too large to represent are capped, as
+/// RFC 7234 section 1.2.1 requires.
+pub fn parse_seconds(value: &str) -> Option<Duration> {
+    if value.is_empty() || !value.bytes().all(|c| c >= b'0' && c <= b'9') {
+        return None;
+    }
+
+    match value.parse() {
+        Ok(seconds) => Some(Duration::from_secs(seconds)),
+        Err(_) => Some(Duration::from_secs(u32::max_value() as u64)),
+    }
+}
+
+/// Split a header value on commas that aren't inside a quoted string.
+fn split_directives(value: &str) -> Vec<String> {
+    let mut directives = Vec::new();
+    let mut current = String::new();
+    let mut quoted = false;
+    let mut escaped = false;
+
+    for c in value.chars() {
+        if escaped {
+            escaped = false;
+        } else if quoted && c == '\\' {
+            escaped = true;
+        } else if c == '"' {
+            quoted = !quoted;
+        } else if c == ',' && !quoted {
+            directives.push(current);
+            current = String::new();
+            continue;
+        }
+
+        current.push(c);
+    }
+
+    directives.push(current);
+    directives.into_iter().filter(|d| !d.trim().is_empty()).collect()
+}
+
+fn unquote(value: &str) -> String {
+    if value.len() < 2 || !value.starts_with('"') || !value.ends_with('"') {
+        return String::from(value);
+    }
+
+    let mut unquoted = String::with_capacity(value.len() - 2);
+    let mut escaped = false;
+
+    for c in value[1..value.len() - 1].chars() {
+        if !escaped && c == '\\' {
+            escaped = true;
+            continue;
+        }
+
+        escaped = false;
+        unquoted.push(c);
+    }
+
+    unquoted
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use http::headers::Headers;
+
+    use super::CacheControl;
+
+    fn control(values: &[&str]) -> CacheControl {
+        let mut headers = Headers::new();
+        for value in values {
+            headers.insert("Cache-Control", &value.as_bytes().to_vec());
+        }
+        CacheControl::from_headers(&headers)
+    }
+
+    #[test]
+    fn test_parse() {
+        let cc = control(&["public, max-age=60", "S-MaxAge=\"120\", must-revalidate"]);
+
+        assert!(cc.public);
+        assert!(cc.must_revalidate);
+        assert!(!cc.no_store);
+        assert_eq!(cc.max_age, Some(Duration::from_secs(60)));
+        assert_eq!(cc.s_maxage, Some(Duration::from_secs(120)));
+    }
+
+    #[test]
+    fn test_parse_quoted_field_names() {
+        let cc = control(&["private=\"Set-Cookie, X-Foo\", no-cache=\"Set-Cookie\", max-age=5"]);
+
+        assert!(cc.private);
+        assert!(cc.no_cache);
+        assert_eq!(cc.max_age, Some(Duration::from_secs(5)));
+    }
+
+    #[test]
+    fn test_parse_invalid() {
+        let cc = control(&["max-age=-1, s-maxage=abc, min-fresh=99999999999999999999999, max-stale"]);
+
+        assert_eq!(cc.max_age, None);
+        assert_eq!(cc.s_maxage, None);
+        assert_eq!(cc.min_fresh, Some(Duration::from_secs(u32::max_value() as u64)));
+        assert_eq!(cc.max_stale, Some(None));
+    }
+
+    #[test]
+    fn test_parse_stale_extensions() {
+        let cc = control(&["max-age=600, stale-while-revalidate=30, stale-if-error=86400"]);
+
+        assert_eq!(cc.stale_while_revalidate, Some(Duration::from_secs(30)));
+        assert_eq!(cc.stale_if_error, Some(Duration::from_secs(86400)));
+    }
+}
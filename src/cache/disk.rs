@@ -0,0 +1,943 @@
+extern crate httparse;
+extern crate sha2;
+
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::str::{self, FromStr};
+use std::sync::{mpsc, Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use self::sha2::{Digest, Sha256};
+
+use http::reply::{self, Reply};
+
+use super::{Entry, Key};
+
+const RECORD_MAGIC: &'static str = "octopus-cache-record 1";
+
+// Stored replies were already parsed once, so this only needs to be generous.
+const MAX_STORED_HEADERS: usize = 256;
+
+// Makes temporary file names unique within this process.
+static TMP_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+/// What the index knows about an entry without reading it from disk.
+struct Record {
+    object: String,
+    object_size: u64,
+    record_size: u64,
+    last_used: u64,
+    // Tells this record apart from any stored under the same key later.
+    id: u64,
+}
+
+/// The metadata at the start of a record file.
+struct RecordHead {
+    key: Key,
+    object: String,
+    object_size: u64,
+    request_time: SystemTime,
+    response_time: SystemTime,
+}
+
+/// What the writer thread is asked to do.
+enum Job {
+    /// Store an entry, if it's still wanted by the time it's written.
+    Insert(Key, Entry, u64),
+    /// Remove the files of an entry that's been taken out of the index, if
+    /// nothing has taken their place since.
+    Remove(Key, String),
+    #[cfg(test)]
+    Flush(mpsc::Sender<()>),
+}
+
+/// Cache entries stored on disk, so they survive a restart.
+///
+/// Bodies are kept under `objects/`, named by the SHA-256 of their content, so
+/// identical bodies stored under different keys are only kept once. Each
+/// entry has a record under `records/`, named by the SHA-256 of its key,
+/// holding the key, the reply head and which object has its body.
+///
+/// Everything is written to `tmp/` and then renamed into place, so a crash
+/// can't leave a partially written file behind. Objects are written before
+/// the records referring to them, and the index is rebuilt from the records
+/// when the store is opened, discarding anything that doesn't add up.
+///
+/// Files are only written and removed by a thread of the store's own, and
+/// read without holding the index, so nothing waits on the disk for anyone
+/// else. An entry only joins the index once it's safely written, so until
+/// then it has to be found elsewhere, e.g. in memory.
+pub struct DiskStore {
+    index: Arc<Mutex<Index>>,
+    jobs: Mutex<Option<mpsc::Sender<Job>>>,
+    writer: Option<thread::JoinHandle<()>>,
+}
+
+struct Index {
+    dir: PathBuf,
+    records: HashMap<Key, Record>,
+    // Keys by when they were last used, oldest first.
+    recency: BTreeMap<u64, Key>,
+    // How many records refer to each object.
+    references: HashMap<String, usize>,
+    // Entries waiting to be written, by the tick they were stored at. Ones
+    // removed from here before then aren't written after all.
+    pending: HashMap<Key, u64>,
+    clock: u64,
+    size: u64,
+    max_size: u64,
+}
+
+impl DiskStore {
+    /// Open the store in `dir`, creating it if necessary, and rebuild the
+    /// index from what's there.
+    pub fn open(dir: &Path, max_size: u64) -> io::Result<DiskStore> {
+        for sub in &["objects", "records", "tmp"] {
+            try!(fs::create_dir_all(dir.join(sub)));
+        }
+
+        let mut index = Index {
+            dir: dir.to_path_buf(),
+            records: HashMap::new(),
+            recency: BTreeMap::new(),
+            references: HashMap::new(),
+            pending: HashMap::new(),
+            clock: 0,
+            size: 0,
+            max_size: max_size,
+        };
+
+        try!(index.rebuild());
+
+        let index = Arc::new(Mutex::new(index));
+        let (sender, receiver) = mpsc::channel();
+        let writing = index.clone();
+        let writer = try!(thread::Builder::new().name(String::from("disk cache")).spawn(move || {
+            write(&writing, receiver);
+        }));
+
+        Ok(DiskStore {
+            index: index,
+            jobs: Mutex::new(Some(sender)),
+            writer: Some(writer),
+        })
+    }
+
+    /// Look up `key`, marking it as recently used.
+    pub fn get(&self, key: &Key) -> io::Result<Option<Entry>> {
+        let (id, object, record_path, object_path) = {
+            let mut guard = self.index.lock().unwrap();
+            let index = &mut *guard;
+            let now = index.tick();
+
+            let (id, object) = match index.records.get_mut(key) {
+                Some(record) => {
+                    index.recency.remove(&record.last_used);
+                    index.recency.insert(now, key.clone());
+                    record.last_used = now;
+                    (record.id, record.object.clone())
+                },
+                None => return Ok(None),
+            };
+
+            let object_path = index.object_path(&object);
+            (id, object, index.record_path(key), object_path)
+        };
+
+        match read_entry(key, &object, &record_path, &object_path) {
+            Ok(entry) => Ok(Some(entry)),
+            Err(e) => {
+                // Whatever happened to it, it's no use to anyone now.
+                self.forget(key, Some(id));
+                Err(e)
+            }
+        }
+    }
+
+    /// Look up `key` without reading its body or it counting as a use.
+    ///
+    /// The entry is returned with an empty body, along with how much space
+    /// it takes up on disk.
+    pub fn peek(&self, key: &Key) -> io::Result<Option<(Entry, u64)>> {
+        let (object, size, record_path) = {
+            let index = self.index.lock().unwrap();
+            match index.records.get(key) {
+                Some(record) => (record.object.clone(), record.object_size + record.record_size, index.record_path(key)),
+                None => return Ok(None),
+            }
+        };
+
+        let (head, reply) = try!(read_record(key, &object, &record_path));
+
+        let entry = Entry {
+            reply: reply,
+            body: Arc::new(Vec::new()),
+            request_time: head.request_time,
+            response_time: head.response_time,
+        };
+
+        Ok(Some((entry, size)))
+    }
+
+    /// Store `entry` in the background, replacing any existing entry for
+    /// `key` and evicting others as necessary.
+    ///
+    /// Entries too big to ever fit are quietly dropped.
+    pub fn insert(&self, key: Key, entry: Entry) {
+        let stored = {
+            let mut index = self.index.lock().unwrap();
+            let now = index.tick();
+            index.pending.insert(key.clone(), now);
+            now
+        };
+
+        self.send(Job::Insert(key, entry, stored));
+    }
+
+    /// Remove the entry for `key`, including one still being stored,
+    /// returning whether there was one.
+    pub fn remove(&self, key: &Key) -> bool {
+        self.forget(key, None)
+    }
+
+    /// Remove every entry whose key `matches`, including ones still being
+    /// stored, returning their keys.
+    pub fn remove_matching<F: Fn(&Key) -> bool>(&self, matches: F) -> Vec<Key> {
+        let mut removed = Vec::new();
+        let mut jobs = Vec::new();
+
+        {
+            let mut index = self.index.lock().unwrap();
+
+            let keys: Vec<Key> = index.records.keys().filter(|key| matches(key)).cloned().collect();
+            for key in keys {
+                if let Some(record) = index.forget(&key) {
+                    jobs.push(Job::Remove(key.clone(), record.object));
+                }
+                removed.push(key);
+            }
+
+            let pending: Vec<Key> = index.pending.keys().filter(|key| matches(key)).cloned().collect();
+            for key in pending {
+                index.pending.remove(&key);
+                if !removed.contains(&key) {
+                    removed.push(key);
+                }
+            }
+        }
+
+        for job in jobs {
+            self.send(job);
+        }
+
+        removed
+    }
+
+    pub fn keys(&self) -> Vec<Key> {
+        self.index.lock().unwrap().records.keys().cloned().collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.index.lock().unwrap().records.len()
+    }
+
+    /// The total size of every record and object, in bytes.
+    pub fn size(&self) -> u64 {
+        self.index.lock().unwrap().size
+    }
+
+    /// Wait for everything asked of the writer so far to be done.
+    #[cfg(test)]
+    pub fn flush(&self) {
+        let (sender, receiver) = mpsc::channel();
+        self.send(Job::Flush(sender));
+        let _ = receiver.recv();
+    }
+
+    /// Take `key` out of the index, or just record `id` of it if that's
+    /// given, and have its files removed. Returns whether anything was.
+    fn forget(&self, key: &Key, id: Option<u64>) -> bool {
+        let (pending, record) = {
+            let mut index = self.index.lock().unwrap();
+            let pending = id.is_none() && index.pending.remove(key).is_some();
+
+            let current = index.records.get(key).map(|record| record.id);
+            let record = if current.is_some() && (id.is_none() || current == id) {
+                index.forget(key)
+            } else {
+                None
+            };
+
+            (pending, record)
+        };
+
+        match record {
+            Some(record) => {
+                self.send(Job::Remove(key.clone(), record.object));
+                true
+            },
+            None => pending,
+        }
+    }
+
+    fn send(&self, job: Job) {
+        if let Some(ref jobs) = *self.jobs.lock().unwrap() {
+            let _ = jobs.send(job);
+        }
+    }
+}
+
+impl Drop for DiskStore {
+    fn drop(&mut self) {
+        // Let the writer finish what it was given before going.
+        self.jobs.lock().unwrap().take();
+        if let Some(writer) = self.writer.take() {
+            let _ = writer.join();
+        }
+    }
+}
+
+impl Index {
+    fn tick(&mut self) -> u64 {
+        self.clock += 1;
+        self.clock
+    }
+
+    /// Count a record for `object` towards the size, returning whether the
+    /// object is new.
+    fn reserve(&mut self, object: &str, object_size: u64, record_size: u64) -> bool {
+        let references = self.references.entry(String::from(object)).or_insert(0);
+        *references += 1;
+
+        self.size += record_size;
+        if *references == 1 {
+            self.size += object_size;
+        }
+
+        *references == 1
+    }
+
+    /// Undo `reserve()` for `record`.
+    fn release(&mut self, record: &Record) {
+        self.size -= record.record_size;
+
+        let unreferenced = match self.references.get_mut(&record.object) {
+            Some(count) => {
+                *count -= 1;
+                *count == 0
+            },
+            None => false,
+        };
+
+        if unreferenced {
+            self.references.remove(&record.object);
+            self.size -= record.object_size;
+        }
+    }
+
+    fn admit(&mut self, key: Key, mut record: Record) {
+        let now = self.tick();
+        record.last_used = now;
+        record.id = now;
+        self.recency.insert(now, key.clone());
+        self.records.insert(key, record);
+    }
+
+    /// Take `key` out of the index, leaving its files for the caller.
+    fn forget(&mut self, key: &Key) -> Option<Record> {
+        let record = match self.records.remove(key) {
+            Some(record) => record,
+            None => return None,
+        };
+
+        self.recency.remove(&record.last_used);
+        self.release(&record);
+        Some(record)
+    }
+
+    /// Make room for `needed` more bytes, returning what was evicted.
+    fn evict(&mut self, needed: u64) -> Vec<(Key, Record)> {
+        let mut evicted = Vec::new();
+
+        while self.size + needed > self.max_size {
+            let oldest = match self.recency.keys().next() {
+                Some(&oldest) => oldest,
+                None => break,
+            };
+
+            if let Some(key) = self.recency.remove(&oldest) {
+                debug!("Evicting {} from the disk cache", key);
+                if let Some(record) = self.forget(&key) {
+                    evicted.push((key, record));
+                }
+            }
+        }
+
+        evicted
+    }
+
+    /// Rebuild the index from the records on disk, cleaning up anything left
+    /// behind by a crash along the way.
+    fn rebuild(&mut self) -> io::Result<()> {
+        for path in try!(list_files(&self.dir.join("tmp"))) {
+            try!(remove_if_exists(&path));
+        }
+
+        let mut found = Vec::new();
+
+        for path in try!(list_files(&self.dir.join("records"))) {
+            match self.check_record(&path) {
+                Ok(Some((head, record_size))) => found.push((head, record_size)),
+                Ok(None) => {
+                    warn!("Discarding invalid cache record {}", path.display());
+                    try!(remove_if_exists(&path));
+                },
+                Err(e) => {
+                    warn!("Discarding unreadable cache record {}: {}", path.display(), e);
+                    try!(remove_if_exists(&path));
+                }
+            }
+        }
+
+        // Nothing records when entries were last used, so assume the oldest
+        // were used least recently.
+        found.sort_by_key(|&(ref head, _)| head.response_time);
+
+        for (head, record_size) in found {
+            self.reserve(&head.object, head.object_size, record_size);
+            self.admit(head.key, Record {
+                object: head.object,
+                object_size: head.object_size,
+                record_size: record_size,
+                last_used: 0,
+                id: 0,
+            });
+        }
+
+        for path in try!(list_files(&self.dir.join("objects"))) {
+            let referenced = match path.file_name().and_then(|name| name.to_str()) {
+                Some(name) => self.references.contains_key(name),
+                None => false,
+            };
+
+            if !referenced {
+                try!(remove_if_exists(&path));
+            }
+        }
+
+        // The maximum size may have been lowered since the last run.
+        for (key, record) in self.evict(0) {
+            try!(remove_if_exists(&self.record_path(&key)));
+            if !self.references.contains_key(&record.object) {
+                try!(remove_if_exists(&self.object_path(&record.object)));
+            }
+        }
+
+        info!("Disk cache has {} entries taking {} bytes", self.records.len(), self.size);
+        Ok(())
+    }
+
+    /// Read the record at `path`, and check it's where it should be and that
+    /// its object is intact.
+    fn check_record(&self, path: &Path) -> io::Result<Option<(RecordHead, u64)>> {
+        let data = try!(read_file(path));
+
+        let head = match decode_record_head(&data) {
+            Some((head, _)) => head,
+            None => return Ok(None),
+        };
+
+        if self.record_path(&head.key) != path {
+            return Ok(None);
+        }
+
+        match fs::metadata(self.object_path(&head.object)) {
+            Ok(ref metadata) if metadata.len() == head.object_size => {},
+            _ => return Ok(None),
+        }
+
+        Ok(Some((head, data.len() as u64)))
+    }
+
+    fn object_path(&self, object: &str) -> PathBuf {
+        self.dir.join("objects").join(&object[..2]).join(object)
+    }
+
+    fn record_path(&self, key: &Key) -> PathBuf {
+        let name = hash(key.to_string().as_bytes());
+        self.dir.join("records").join(&name[..2]).join(&name)
+    }
+}
+
+/// Work through `jobs` until the store is dropped.
+fn write(index: &Mutex<Index>, jobs: mpsc::Receiver<Job>) {
+    for job in jobs {
+        match job {
+            Job::Insert(key, entry, stored) => {
+                if let Err(e) = store(index, &key, &entry, stored) {
+                    error!("Error writing {} to the disk cache: {}", key, e);
+                }
+            },
+            Job::Remove(key, object) => discard(index, &key, &object),
+            #[cfg(test)]
+            Job::Flush(done) => {
+                let _ = done.send(());
+            },
+        }
+    }
+}
+
+/// Write `entry` to disk and add it to the index, unless it's been removed or
+/// stored again since it was `stored`.
+///
+/// Room is made for it first, so the store stays within its size while the
+/// files are written. The index isn't locked meanwhile.
+fn store(index: &Mutex<Index>, key: &Key, entry: &Entry, stored: u64) -> io::Result<()> {
+    let object = hash(&entry.body);
+    let data = encode_record(key, &object, entry);
+    let record = Record {
+        object: object.clone(),
+        object_size: entry.body.len() as u64,
+        record_size: data.len() as u64,
+        last_used: 0,
+        id: 0,
+    };
+
+    let mut removed = Vec::new();
+    let (fits, new_object, dir, object_path, record_path) = {
+        let mut index = index.lock().unwrap();
+        if index.pending.get(key) != Some(&stored) {
+            return Ok(());
+        }
+
+        if let Some(old) = index.forget(key) {
+            removed.push((key.clone(), old.object));
+        }
+
+        let needed = record.record_size + if index.references.contains_key(&object) { 0 } else { record.object_size };
+        let fits = needed <= index.max_size;
+
+        if fits {
+            for (evicted, record) in index.evict(needed) {
+                removed.push((evicted, record.object));
+            }
+        } else {
+            index.pending.remove(key);
+        }
+
+        // Eviction may have just removed the last other reference to it.
+        let new_object = fits && index.reserve(&object, record.object_size, record.record_size);
+        (fits, new_object, index.dir.clone(), index.object_path(&object), index.record_path(key))
+    };
+
+    // These have to go before anything is written, as one could be an
+    // earlier record for this same key.
+    for (key, object) in removed {
+        discard(index, &key, &object);
+    }
+
+    if !fits {
+        debug!("Not writing {} to the disk cache, as it would never fit", key);
+        return Ok(());
+    }
+
+    let written = if new_object {
+        write_atomically(&dir, &object_path, &entry.body)
+    } else {
+        Ok(())
+    };
+    let written = written.and_then(|_| write_atomically(&dir, &record_path, &data));
+
+    let admitted = {
+        let mut index = index.lock().unwrap();
+        let wanted = index.pending.get(key) == Some(&stored);
+        if wanted {
+            index.pending.remove(key);
+        }
+
+        if wanted && written.is_ok() {
+            index.admit(key.clone(), record);
+            true
+        } else {
+            index.release(&record);
+            false
+        }
+    };
+
+    if !admitted {
+        discard(index, key, &object);
+    }
+
+    written
+}
+
+/// Remove the files of `key`, whose body is `object`, unless they're in use
+/// again by now.
+fn discard(index: &Mutex<Index>, key: &Key, object: &str) {
+    let (record_path, object_path) = {
+        let index = index.lock().unwrap();
+        let record_path = if index.records.contains_key(key) { None } else { Some(index.record_path(key)) };
+        let object_path = if index.references.contains_key(object) { None } else { Some(index.object_path(object)) };
+        (record_path, object_path)
+    };
+
+    for path in record_path.iter().chain(object_path.iter()) {
+        if let Err(e) = remove_if_exists(path) {
+            error!("Error removing {} from the disk cache: {}", key, e);
+        }
+    }
+}
+
+fn read_entry(key: &Key, object: &str, record_path: &Path, object_path: &Path) -> io::Result<Entry> {
+    let (head, reply) = try!(read_record(key, object, record_path));
+
+    let body = try!(read_file(object_path));
+    if body.len() as u64 != head.object_size {
+        return Err(invalid_data("cached object is the wrong size"));
+    }
+
+    Ok(Entry {
+        reply: reply,
+        body: Arc::new(body),
+        request_time: head.request_time,
+        response_time: head.response_time,
+    })
+}
+
+fn read_record(key: &Key, object: &str, path: &Path) -> io::Result<(RecordHead, Reply)> {
+    let data = try!(read_file(path));
+
+    let (head, reply_head) = match decode_record_head(&data) {
+        Some(decoded) => decoded,
+        None => return Err(invalid_data("invalid cache record")),
+    };
+
+    if head.key != *key || head.object != object {
+        return Err(invalid_data("cache record doesn't match the index"));
+    }
+
+    let reply_head = reply_head.to_vec();
+    let mut headers = [httparse::EMPTY_HEADER; MAX_STORED_HEADERS];
+    let reply = match reply::parse(&reply_head, &mut headers, reply_head.len()) {
+        Ok(Some((reply, _))) => reply,
+        _ => return Err(invalid_data("invalid reply in cache record")),
+    };
+
+    Ok((head, reply))
+}
+
+/// Write `data` to `path`, by way of the store in `dir`, such that `path`
+/// either holds all of it or is left as it was, even if we crash halfway
+/// through.
+fn write_atomically(dir: &Path, path: &Path, data: &[u8]) -> io::Result<()> {
+    let tmp = dir.join("tmp").join(format!("{}.{}", process::id(), TMP_COUNTER.fetch_add(1, Ordering::SeqCst)));
+
+    let result = File::create(&tmp).and_then(|mut file| {
+        try!(file.write_all(data));
+        file.sync_all()
+    });
+
+    if let Err(e) = result {
+        let _ = fs::remove_file(&tmp);
+        return Err(e);
+    }
+
+    if let Some(parent) = path.parent() {
+        try!(fs::create_dir_all(parent));
+    }
+
+    try!(fs::rename(&tmp, path));
+
+    // Make sure the rename itself survives a crash too.
+    if let Some(parent) = path.parent() {
+        try!(File::open(parent).and_then(|dir| dir.sync_all()));
+    }
+
+    Ok(())
+}
+
+fn encode_record(key: &Key, object: &str, entry: &Entry) -> Vec<u8> {
+    let mut record = format!("{}\nkey: {}\nobject: {}\nobject-size: {}\nrequest-time: {}\nresponse-time: {}\n\n",
+                             RECORD_MAGIC, key, object, entry.body.len(),
+                             to_timestamp(entry.request_time), to_timestamp(entry.response_time)).into_bytes();
+
+    let head: Vec<u8> = entry.reply.clone().into();
+    record.extend(head);
+    record
+}
+
+/// Split a record into its metadata and the reply head that follows it.
+fn decode_record_head(data: &[u8]) -> Option<(RecordHead, &[u8])> {
+    let end = match data.windows(2).position(|w| w == b"\n\n") {
+        Some(end) => end,
+        None => return None,
+    };
+
+    let meta = match str::from_utf8(&data[..end]) {
+        Ok(meta) => meta,
+        Err(_) => return None,
+    };
+
+    let mut lines = meta.lines();
+    if lines.next() != Some(RECORD_MAGIC) {
+        return None;
+    }
+
+    let mut fields = HashMap::new();
+    for line in lines {
+        match line.find(": ") {
+            Some(i) => fields.insert(&line[..i], &line[i + 2..]),
+            None => return None,
+        };
+    }
+
+    let key = fields.get("key").and_then(|key| Key::from_str(key).ok());
+    let object = fields.get("object").map(|object| object.to_string());
+    let object_size = fields.get("object-size").and_then(|size| size.parse().ok());
+    let request_time = fields.get("request-time").and_then(|time| time.parse().ok());
+    let response_time = fields.get("response-time").and_then(|time| time.parse().ok());
+
+    match (key, object, object_size, request_time, response_time) {
+        (Some(key), Some(object), Some(object_size), Some(request_time), Some(response_time)) => {
+            if object.len() != 64 || !object.bytes().all(|c| (c as char).is_digit(16)) {
+                return None;
+            }
+
+            let head = RecordHead {
+                key: key,
+                object: object,
+                object_size: object_size,
+                request_time: from_timestamp(request_time),
+                response_time: from_timestamp(response_time),
+            };
+
+            Some((head, &data[end + 2..]))
+        },
+        _ => None,
+    }
+}
+
+fn hash(data: &[u8]) -> String {
+    let mut hasher = Sha256::default();
+    hasher.input(data);
+    format!("{:x}", hasher.result())
+}
+
+fn to_timestamp(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+fn from_timestamp(timestamp: u64) -> SystemTime {
+    UNIX_EPOCH + Duration::from_secs(timestamp)
+}
+
+fn read_file(path: &Path) -> io::Result<Vec<u8>> {
+    let mut data = Vec::new();
+    try!(try!(File::open(path)).read_to_end(&mut data));
+    Ok(data)
+}
+
+fn remove_if_exists(path: &Path) -> io::Result<()> {
+    match fs::remove_file(path) {
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        r => r,
+    }
+}
+
+/// Every file in `dir` and its immediate subdirectories.
+fn list_files(dir: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+
+    for entry in try!(fs::read_dir(dir)) {
+        let path = try!(entry).path();
+
+        if path.is_dir() {
+            for entry in try!(fs::read_dir(&path)) {
+                files.push(try!(entry).path());
+            }
+        } else {
+            files.push(path);
+        }
+    }
+
+    Ok(files)
+}
+
+fn invalid_data(reason: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, reason)
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate url;
+
+    use std::env;
+    use std::fs;
+    use std::path::PathBuf;
+    use std::process;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use http::headers::Headers;
+    use http::reply::Reply;
+
+    use super::{list_files, DiskStore};
+    use super::super::{Entry, Key};
+
+    fn scratch_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("octopus-disk-test-{}-{}", process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn key(path: &str) -> Key {
+        Key::new("GET", &url::Url::parse(&format!("http://example.com{}", path)).unwrap())
+    }
+
+    fn entry(body: &[u8]) -> Entry {
+        let mut headers = Headers::new();
+        headers.insert("Cache-Control", &b"max-age=60".to_vec());
+
+        let reply = Reply {
+            version: 1,
+            code: 200,
+            reason: String::from("OK"),
+            headers: headers,
+        };
+
+        let time = UNIX_EPOCH + Duration::from_secs(1500000000);
+        Entry::new(reply, body.to_vec(), time, time)
+    }
+
+    #[test]
+    fn test_survives_reopening() {
+        let dir = scratch_dir("reopen");
+
+        {
+            let store = DiskStore::open(&dir, 1024 * 1024).unwrap();
+            store.insert(key("/a"), entry(b"Hello"));
+            store.insert(key("/b"), entry(b"World"));
+        }
+
+        let store = DiskStore::open(&dir, 1024 * 1024).unwrap();
+        assert_eq!(store.len(), 2);
+
+        let a = store.get(&key("/a")).unwrap().unwrap();
+        assert_eq!(&a.body[..], b"Hello");
+        assert_eq!(a.reply.code, 200);
+        assert_eq!(a.reply.headers.get("cache-control").unwrap(), b"max-age=60");
+        assert_eq!(a.response_time, entry(b"").response_time);
+
+        assert!(store.remove(&key("/a")));
+        assert!(store.get(&key("/a")).unwrap().is_none());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_shared_objects() {
+        let dir = scratch_dir("shared");
+        let store = DiskStore::open(&dir, 1024 * 1024).unwrap();
+
+        store.insert(key("/a"), entry(b"Same"));
+        store.insert(key("/b"), entry(b"Same"));
+        store.flush();
+        assert_eq!(list_files(&dir.join("objects")).unwrap().len(), 1);
+
+        // The object is only removed along with the last record using it.
+        store.remove(&key("/a"));
+        store.flush();
+        assert_eq!(&store.get(&key("/b")).unwrap().unwrap().body[..], b"Same");
+        store.remove(&key("/b"));
+        store.flush();
+        assert_eq!(list_files(&dir.join("objects")).unwrap().len(), 0);
+        assert_eq!(store.size(), 0);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_eviction() {
+        let dir = scratch_dir("eviction");
+        let body = vec![b'a'; 1000];
+        let other = vec![b'b'; 1000];
+        let third = vec![b'c'; 1000];
+
+        let store = DiskStore::open(&dir, 3000).unwrap();
+        store.insert(key("/a"), entry(&body));
+        store.insert(key("/b"), entry(&other));
+        store.flush();
+
+        // Using /a makes /b the least recently used.
+        store.get(&key("/a")).unwrap();
+        store.insert(key("/c"), entry(&third));
+        store.flush();
+
+        assert!(store.get(&key("/b")).unwrap().is_none());
+        assert!(store.get(&key("/a")).unwrap().is_some());
+        assert!(store.get(&key("/c")).unwrap().is_some());
+        assert!(store.size() <= 3000);
+
+        store.insert(key("/d"), entry(&vec![b'd'; 5000]));
+        store.flush();
+        assert!(store.get(&key("/d")).unwrap().is_none());
+        assert_eq!(store.len(), 2);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_rebuild_discards_damage() {
+        let dir = scratch_dir("damage");
+
+        {
+            let store = DiskStore::open(&dir, 1024 * 1024).unwrap();
+            store.insert(key("/a"), entry(b"Hello"));
+            store.insert(key("/b"), entry(b"World"));
+        }
+
+        // A crash mid-write, an object whose record never made it, and a
+        // record whose object went missing.
+        fs::write(dir.join("tmp").join("1.1"), b"half written").unwrap();
+        fs::create_dir_all(dir.join("objects").join("00")).unwrap();
+        fs::write(dir.join("objects").join("00").join("00orphan"), b"orphan").unwrap();
+
+        let store = DiskStore::open(&dir, 1024 * 1024).unwrap();
+        let world = store.index.lock().unwrap().records.get(&key("/b")).unwrap().object.clone();
+        drop(store);
+        fs::remove_file(dir.join("objects").join(&world[..2]).join(&world)).unwrap();
+
+        let store = DiskStore::open(&dir, 1024 * 1024).unwrap();
+        assert_eq!(store.len(), 1);
+        assert!(store.get(&key("/a")).unwrap().is_some());
+        assert!(store.get(&key("/b")).unwrap().is_none());
+        assert_eq!(list_files(&dir.join("tmp")).unwrap().len(), 0);
+        assert_eq!(list_files(&dir.join("objects")).unwrap().len(), 1);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_removed_while_pending() {
+        let dir = scratch_dir("pending");
+        let store = DiskStore::open(&dir, 1024 * 1024).unwrap();
+
+        // Anything removed before it's written isn't written after all.
+        store.insert(key("/a"), entry(b"Hello"));
+        store.insert(key("/b"), entry(b"World"));
+        assert!(store.remove(&key("/a")));
+        assert_eq!(store.remove_matching(|key| key.url().ends_with("/b")), vec![key("/b")]);
+        store.flush();
+
+        assert_eq!(store.len(), 0);
+        assert_eq!(store.size(), 0);
+        assert_eq!(list_files(&dir.join("records")).unwrap().len(), 0);
+        assert_eq!(list_files(&dir.join("objects")).unwrap().len(), 0);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
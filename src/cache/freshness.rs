@@ -0,0 +1,173 @@
+extern crate httpdate;
+
+use std::str;
+use std::time::{Duration, SystemTime};
+
+use http::headers::Headers;
+use http::reply::Reply;
+
+use super::control::{self, CacheControl};
+
+// Replies without explicit freshness are considered fresh for this fraction
+// of the time since they were last modified, as suggested by RFC 7234 section
+// 4.2.2...
+const HEURISTIC_FRACTION: u32 = 10;
+// ...but no longer than this, beyond which a Warning would be required.
+const MAX_HEURISTIC_LIFETIME: u64 = 24 * 60 * 60;
+
+/// Whether replies with this status code can be given a heuristic freshness
+/// lifetime, as per RFC 7231 section 6.1.
+pub fn is_heuristically_cacheable(code: u16) -> bool {
+    match code {
+        200 | 203 | 204 | 206 | 300 | 301 | 404 | 405 | 410 | 414 | 501 => true,
+        _ => false,
+    }
+}
+
+/// Parse the HTTP-date in the `name` header, if there is a valid one.
+pub fn parse_date(headers: &Headers, name: &str) -> Option<SystemTime> {
+    match headers.get(name) {
+        Some(value) => {
+            match str::from_utf8(value) {
+                Ok(value) => httpdate::parse_http_date(value.trim()).ok(),
+                Err(_) => None,
+            }
+        },
+        None => None,
+    }
+}
+
+pub fn format_date(time: SystemTime) -> String {
+    httpdate::fmt_http_date(time)
+}
+
+/// Whether the origin said how long `reply` stays fresh for, rather than
+/// leaving it to heuristics.
+pub fn has_explicit_lifetime(reply: &Reply) -> bool {
+    let cc = CacheControl::from_headers(&reply.headers);
+    cc.s_maxage.is_some() || cc.max_age.is_some() || reply.headers.get("expires").is_some()
+}
+
+/// How long `reply` stays fresh for after it was generated, as per RFC 7234
+/// section 4.2.1. `response_time` is when it was received.
+pub fn freshness_lifetime(reply: &Reply, response_time: SystemTime) -> Duration {
+    let cc = CacheControl::from_headers(&reply.headers);
+
+    // We're a shared cache, so s-maxage takes priority.
+    if let Some(s_maxage) = cc.s_maxage {
+        return s_maxage;
+    }
+
+    if let Some(max_age) = cc.max_age {
+        return max_age;
+    }
+
+    let date = parse_date(&reply.headers, "date").unwrap_or(response_time);
+
+    if reply.headers.get("expires").is_some() {
+        // An invalid Expires, typically "0", means it has already expired.
+        return match parse_date(&reply.headers, "expires") {
+            Some(expires) => expires.duration_since(date).unwrap_or(Duration::from_secs(0)),
+            None => Duration::from_secs(0),
+        };
+    }
+
+    if is_heuristically_cacheable(reply.code) {
+        if let Some(last_modified) = parse_date(&reply.headers, "last-modified") {
+            if let Ok(since) = date.duration_since(last_modified) {
+                let lifetime = since / HEURISTIC_FRACTION;
+                let max = Duration::from_secs(MAX_HEURISTIC_LIFETIME);
+                return if lifetime > max { max } else { lifetime };
+            }
+        }
+    }
+
+    Duration::from_secs(0)
+}
+
+/// How old `reply` is at `now`, as per RFC 7234 section 4.2.3.
+/// `request_time` and `response_time` are when the request that produced it
+/// was sent and when the reply was received.
+pub fn current_age(reply: &Reply, request_time: SystemTime, response_time: SystemTime, now: SystemTime) -> Duration {
+    let zero = Duration::from_secs(0);
+
+    let date = parse_date(&reply.headers, "date").unwrap_or(response_time);
+    let age_value = match reply.headers.get("age") {
+        Some(value) => {
+            str::from_utf8(value).ok()
+                .and_then(|value| control::parse_seconds(value.trim()))
+                .unwrap_or(zero)
+        },
+        None => zero,
+    };
+
+    let apparent_age = response_time.duration_since(date).unwrap_or(zero);
+    let response_delay = response_time.duration_since(request_time).unwrap_or(zero);
+    let corrected_age_value = age_value + response_delay;
+    let corrected_initial_age = if apparent_age > corrected_age_value { apparent_age } else { corrected_age_value };
+    let resident_time = now.duration_since(response_time).unwrap_or(zero);
+
+    corrected_initial_age + resident_time
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+    use http::reply::Reply;
+    use http::test_support;
+
+    use super::{current_age, format_date, freshness_lifetime, has_explicit_lifetime};
+
+    fn at(seconds: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(1500000000 + seconds)
+    }
+
+    fn reply(headers: &str) -> Reply {
+        test_support::reply(format!("HTTP/1.1 200 OK\r\n{}\r\n", headers))
+    }
+
+    #[test]
+    fn test_lifetime_priority() {
+        let date = format!("Date: {}\r\n", format_date(at(0)));
+        let expires = format!("Expires: {}\r\n", format_date(at(300)));
+
+        let r = reply(&format!("{}{}Cache-Control: max-age=60, s-maxage=120\r\n", date, expires));
+        assert_eq!(freshness_lifetime(&r, at(0)), Duration::from_secs(120));
+
+        let r = reply(&format!("{}{}Cache-Control: max-age=60\r\n", date, expires));
+        assert_eq!(freshness_lifetime(&r, at(0)), Duration::from_secs(60));
+
+        let r = reply(&format!("{}{}", date, expires));
+        assert_eq!(freshness_lifetime(&r, at(0)), Duration::from_secs(300));
+        assert!(has_explicit_lifetime(&r));
+
+        let r = reply(&format!("{}Expires: 0\r\n", date));
+        assert_eq!(freshness_lifetime(&r, at(0)), Duration::from_secs(0));
+    }
+
+    #[test]
+    fn test_lifetime_heuristic() {
+        let r = reply(&format!("Date: {}\r\nLast-Modified: {}\r\n", format_date(at(1000)), format_date(at(0))));
+        assert!(!has_explicit_lifetime(&r));
+        assert_eq!(freshness_lifetime(&r, at(1000)), Duration::from_secs(100));
+
+        let r = reply(&format!("Last-Modified: {}\r\n", format_date(at(0))));
+        assert_eq!(freshness_lifetime(&r, at(100 * 24 * 60 * 60)), Duration::from_secs(24 * 60 * 60));
+
+        let r = reply("");
+        assert_eq!(freshness_lifetime(&r, at(0)), Duration::from_secs(0));
+    }
+
+    #[test]
+    fn test_current_age() {
+        // Received a second after sending, already 10 seconds old according
+        // to an upstream cache, then held for 30 seconds.
+        let r = reply(&format!("Date: {}\r\nAge: 10\r\n", format_date(at(0))));
+        assert_eq!(current_age(&r, at(0), at(1), at(31)), Duration::from_secs(41));
+
+        // The origin's clock is well behind ours.
+        let r = reply(&format!("Date: {}\r\n", format_date(at(0))));
+        assert_eq!(current_age(&r, at(99), at(100), at(100)), Duration::from_secs(100));
+    }
+}
@@ -0,0 +1,192 @@
+use std::collections::{BTreeMap, HashMap};
+
+use super::{Entry, Key};
+
+struct Slot {
+    entry: Entry,
+    size: usize,
+    last_used: u64,
+}
+
+/// Cache entries held in memory, up to a total size in bytes. The least
+/// recently used entries are evicted to make room for new ones.
+pub struct MemoryStore {
+    entries: HashMap<Key, Slot>,
+    // Keys by when they were last used, oldest first.
+    recency: BTreeMap<u64, Key>,
+    clock: u64,
+    size: usize,
+    max_size: usize,
+}
+
+impl MemoryStore {
+    pub fn new(max_size: usize) -> MemoryStore {
+        MemoryStore {
+            entries: HashMap::new(),
+            recency: BTreeMap::new(),
+            clock: 0,
+            size: 0,
+            max_size: max_size,
+        }
+    }
+
+    /// Look up `key`, marking it as recently used.
+    pub fn get(&mut self, key: &Key) -> Option<Entry> {
+        let now = self.tick();
+
+        match self.entries.get_mut(key) {
+            Some(slot) => {
+                self.recency.remove(&slot.last_used);
+                self.recency.insert(now, key.clone());
+                slot.last_used = now;
+                Some(slot.entry.clone())
+            },
+            None => None,
+        }
+    }
+
+    /// Look up `key` without it counting as a use.
+    pub fn peek(&self, key: &Key) -> Option<Entry> {
+        self.entries.get(key).map(|slot| slot.entry.clone())
+    }
+
+    /// Store `entry`, replacing any existing entry for `key` and evicting
+    /// others as necessary.
+    ///
+    /// Returns false if the entry is too big to ever fit.
+    pub fn insert(&mut self, key: Key, entry: Entry) -> bool {
+        let size = entry.size();
+        if size > self.max_size {
+            self.remove(&key);
+            return false;
+        }
+
+        self.remove(&key);
+
+        while self.size + size > self.max_size {
+            let oldest = match self.recency.keys().next() {
+                Some(&oldest) => oldest,
+                None => break,
+            };
+
+            if let Some(evicted) = self.recency.remove(&oldest) {
+                debug!("Evicting {} from the memory cache", evicted);
+                self.remove(&evicted);
+            }
+        }
+
+        let now = self.tick();
+        self.recency.insert(now, key.clone());
+        self.entries.insert(key, Slot {
+            entry: entry,
+            size: size,
+            last_used: now,
+        });
+        self.size += size;
+
+        true
+    }
+
+    pub fn remove(&mut self, key: &Key) -> Option<Entry> {
+        match self.entries.remove(key) {
+            Some(slot) => {
+                self.recency.remove(&slot.last_used);
+                self.size -= slot.size;
+                Some(slot.entry)
+            },
+            None => None,
+        }
+    }
+
+    pub fn keys(&self) -> Vec<Key> {
+        self.entries.keys().cloned().collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// The total size of every entry, in bytes.
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    fn tick(&mut self) -> u64 {
+        self.clock += 1;
+        self.clock
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate url;
+
+    use std::time::UNIX_EPOCH;
+
+    use http::headers::Headers;
+    use http::reply::Reply;
+
+    use super::MemoryStore;
+    use super::super::{Entry, Key};
+
+    fn key(path: &str) -> Key {
+        Key::new("GET", &url::Url::parse(&format!("http://example.com{}", path)).unwrap())
+    }
+
+    fn entry(body_size: usize) -> Entry {
+        let reply = Reply {
+            version: 1,
+            code: 200,
+            reason: String::from("OK"),
+            headers: Headers::new(),
+        };
+
+        Entry::new(reply, vec![b'a'; body_size], UNIX_EPOCH, UNIX_EPOCH)
+    }
+
+    #[test]
+    fn test_insert_and_get() {
+        let mut store = MemoryStore::new(1024 * 1024);
+
+        assert!(store.get(&key("/a")).is_none());
+        assert!(store.insert(key("/a"), entry(10)));
+        assert_eq!(store.get(&key("/a")).unwrap().body.len(), 10);
+
+        // Replacing an entry doesn't count it twice.
+        let size = store.size();
+        assert!(store.insert(key("/a"), entry(10)));
+        assert_eq!(store.size(), size);
+        assert_eq!(store.len(), 1);
+
+        assert!(store.remove(&key("/a")).is_some());
+        assert_eq!(store.size(), 0);
+    }
+
+    #[test]
+    fn test_lru_eviction() {
+        let size = entry(1000).size();
+        let mut store = MemoryStore::new(size * 3);
+
+        store.insert(key("/a"), entry(1000));
+        store.insert(key("/b"), entry(1000));
+        store.insert(key("/c"), entry(1000));
+
+        // Using /a makes /b the least recently used.
+        assert!(store.get(&key("/a")).is_some());
+        store.insert(key("/d"), entry(1000));
+
+        assert!(store.get(&key("/b")).is_none());
+        assert!(store.get(&key("/a")).is_some());
+        assert!(store.get(&key("/c")).is_some());
+        assert!(store.get(&key("/d")).is_some());
+        assert!(store.size() <= size * 3);
+    }
+
+    #[test]
+    fn test_too_big() {
+        let mut store = MemoryStore::new(100);
+
+        assert!(!store.insert(key("/a"), entry(1000)));
+        assert_eq!(store.len(), 0);
+    }
+}
@@ -0,0 +1,972 @@
+extern crate url;
+
+pub mod collapse;
+pub mod control;
+pub mod disk;
+pub mod freshness;
+pub mod memory;
+pub mod validation;
+pub mod vary;
+
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::io::{self, Write};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+use config::Config;
+use http::headers::Headers;
+use http::reply::Reply;
+use http::request::Request;
+
+use self::collapse::{Collapse, Collapser, Leader};
+use self::control::CacheControl;
+use self::disk::DiskStore;
+use self::memory::MemoryStore;
+use self::vary::Variant;
+
+/// Identifies a cached reply by the request that produced it.
+///
+/// The method and URL make up the primary key. Replies that vary also have a
+/// secondary key, made up of the request headers they were selected by.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Key {
+    method: String,
+    url: String,
+    variant: Variant,
+}
+
+impl Key {
+    pub fn new(method: &str, url: &url::Url) -> Key {
+        Key::with_variant(method, url, Variant::default())
+    }
+
+    pub fn with_variant(method: &str, url: &url::Url, variant: Variant) -> Key {
+        Key {
+            method: String::from(method),
+            url: String::from(&url[..url::Position::AfterQuery]),
+            variant: variant,
+        }
+    }
+
+    pub fn method(&self) -> &str {
+        &self.method
+    }
+
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    pub fn variant(&self) -> &Variant {
+        &self.variant
+    }
+
+    /// This key without its secondary key.
+    pub fn primary(&self) -> Key {
+        Key {
+            method: self.method.clone(),
+            url: self.url.clone(),
+            variant: Variant::default(),
+        }
+    }
+}
+
+impl fmt::Display for Key {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.variant.is_empty() {
+            write!(f, "{} {}", self.method, self.url)
+        } else {
+            write!(f, "{} {} {}", self.method, self.url, self.variant)
+        }
+    }
+}
+
+impl FromStr for Key {
+    type Err = String;
+
+    /// Parse a key back out of its `Display` form.
+    fn from_str(s: &str) -> Result<Key, String> {
+        let mut parts = s.splitn(3, ' ');
+
+        match (parts.next(), parts.next(), parts.next()) {
+            (Some(method), Some(url), variant) if !method.is_empty() => {
+                let url = match url::Url::parse(url) {
+                    Ok(url) => url,
+                    Err(e) => return Err(format!("Invalid URL in cache key {}: {}", s, e)),
+                };
+
+                let variant = match variant {
+                    Some(variant) => try!(Variant::from_str(variant)),
+                    None => Variant::default(),
+                };
+
+                Ok(Key::with_variant(method, &url, variant))
+            },
+            _ => Err(format!("Invalid cache key {}", s)),
+        }
+    }
+}
+
+/// A stored reply, along with its body.
+#[derive(Debug, Clone)]
+pub struct Entry {
+    /// The reply as received from the origin, minus hop-by-hop headers.
+    pub reply: Reply,
+    pub body: Arc<Vec<u8>>,
+    /// When the request that produced the reply was sent.
+    pub request_time: SystemTime,
+    /// When the reply was received.
+    pub response_time: SystemTime,
+}
+
+impl Entry {
+    pub fn new(mut reply: Reply, body: Vec<u8>, request_time: SystemTime, response_time: SystemTime) -> Entry {
+        // Bodies are stored without any transfer-coding, so they're always
+        // sent on with a known length.
+        reply.headers.remove("transfer-encoding");
+        reply.headers.remove("content-length");
+        reply.headers.insert("Content-Length", &body.len().to_string().into_bytes());
+
+        Entry {
+            reply: reply,
+            body: Arc::new(body),
+            request_time: request_time,
+            response_time: response_time,
+        }
+    }
+
+    pub fn freshness_lifetime(&self) -> Duration {
+        freshness::freshness_lifetime(&self.reply, self.response_time)
+    }
+
+    pub fn current_age(&self, now: SystemTime) -> Duration {
+        freshness::current_age(&self.reply, self.request_time, self.response_time, now)
+    }
+
+    pub fn is_fresh(&self, now: SystemTime) -> bool {
+        self.current_age(now) < self.freshness_lifetime()
+    }
+
+    /// How long past its freshness lifetime this entry is at `now`.
+    pub fn staleness(&self, now: SystemTime) -> Duration {
+        self.current_age(now).checked_sub(self.freshness_lifetime()).unwrap_or(Duration::from_secs(0))
+    }
+
+    /// Whether this entry can be served to a request with `request_headers`
+    /// at `now` because fetching a new one failed, as per RFC 5861 section 4.
+    pub fn is_usable_on_error(&self, request_headers: &Headers, now: SystemTime) -> bool {
+        let request_cc = CacheControl::from_headers(request_headers);
+        let reply_cc = CacheControl::from_headers(&self.reply.headers);
+
+        // The client's own limit takes precedence over the origin's.
+        may_serve_stale(self, &reply_cc, request_cc.stale_if_error.or(reply_cc.stale_if_error), now)
+    }
+
+    /// Roughly how much memory this entry takes up, in bytes.
+    pub fn size(&self) -> usize {
+        let head: Vec<u8> = self.reply.clone().into();
+        head.len() + self.body.len()
+    }
+
+    /// This entry, updated with the headers of a 304 Not Modified reply to a
+    /// request that revalidated it, as per RFC 7234 section 4.3.4.
+    ///
+    /// Returns None if the 304 is about a different representation.
+    pub fn refresh(&self, not_modified: &Reply, request_time: SystemTime, response_time: SystemTime) -> Option<Entry> {
+        if !validation::validates(not_modified, &self.reply) {
+            return None;
+        }
+
+        // These describe the 304 itself rather than the stored body.
+        let mut headers = not_modified.headers.clone();
+        headers.remove("content-length");
+        headers.remove("transfer-encoding");
+
+        let mut reply = self.reply.clone();
+        reply.headers.update(&headers);
+
+        Some(Entry {
+            reply: reply,
+            body: self.body.clone(),
+            request_time: request_time,
+            response_time: response_time,
+        })
+    }
+
+    /// The reply to send to a client at `now`, with its Age filled in.
+    pub fn reply_at(&self, now: SystemTime) -> Reply {
+        let mut reply = self.reply.clone();
+
+        reply.headers.remove("age");
+        reply.headers.insert("Age", &self.current_age(now).as_secs().to_string().into_bytes());
+
+        if !self.is_fresh(now) {
+            reply.headers.insert("Warning", &b"110 - \"Response is Stale\"".to_vec());
+        }
+
+        reply
+    }
+}
+
+/// Collects a copy of a reply body as it's relayed, giving up if it grows
+/// too large to be worth storing.
+pub struct Capture {
+    body: Vec<u8>,
+    size: usize,
+    limit: usize,
+    overflowed: bool,
+    // Passes the body on to collapsed requests as well. It keeps the body
+    // itself, so it isn't kept here too.
+    leader: Option<Leader>,
+}
+
+impl Capture {
+    pub fn new(limit: usize) -> Capture {
+        Capture {
+            body: Vec::new(),
+            size: 0,
+            limit: limit,
+            overflowed: false,
+            leader: None,
+        }
+    }
+
+    /// Share the body with the followers of `leader` as it's captured, along
+    /// with `reply`, the reply to a request with `request_headers`.
+    pub fn share(&mut self, leader: Leader, reply: &Reply, request_headers: &Headers) {
+        leader.publish(reply, request_headers);
+        self.leader = Some(leader);
+    }
+
+    /// The captured body, or None if it was too large.
+    pub fn into_body(self) -> Option<Vec<u8>> {
+        if self.overflowed {
+            return None;
+        }
+
+        match self.leader {
+            Some(leader) => Some(leader.finish()),
+            None => Some(self.body),
+        }
+    }
+}
+
+impl Write for Capture {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        // Never fails, so that relaying the body carries on regardless.
+        if !self.overflowed {
+            if self.size + buf.len() > self.limit {
+                // Followers only get as much as there's room for too.
+                self.overflowed = true;
+                self.body = Vec::new();
+                self.leader = None;
+            } else {
+                self.size += buf.len();
+                match self.leader {
+                    Some(ref leader) => leader.send(buf),
+                    None => self.body.extend(buf),
+                }
+            }
+        }
+
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// A description of a cache entry, for inspecting what the cache holds.
+#[derive(Debug, Clone)]
+pub struct Summary {
+    pub key: Key,
+    pub code: u16,
+    /// How much space the entry takes up, in bytes.
+    pub size: u64,
+    pub response_time: SystemTime,
+    pub age: Duration,
+    pub freshness_lifetime: Duration,
+    /// How many times the entry has been found since it was stored, or since
+    /// the proxy started.
+    pub hits: u64,
+}
+
+impl Summary {
+    fn new(key: Key, entry: &Entry, size: u64, now: SystemTime) -> Summary {
+        Summary {
+            key: key,
+            code: entry.reply.code,
+            size: size,
+            response_time: entry.response_time,
+            age: entry.current_age(now),
+            freshness_lifetime: entry.freshness_lifetime(),
+            hits: 0,
+        }
+    }
+
+    pub fn is_fresh(&self) -> bool {
+        self.freshness_lifetime > self.age
+    }
+}
+
+/// The outcome of looking up a request in the cache.
+#[derive(Debug)]
+pub enum Lookup {
+    /// A stale entry that can be served while it's revalidated in the
+    /// background.
+    StaleWhileRevalidate(Entry),
+    /// This can be served as it is.
+    Fresh(Entry),
+    /// This has to be revalidated with the origin before it can be served.
+    Stale(Entry),
+    Miss,
+}
+
+/// A shared HTTP cache, as per RFC 7234.
+///
+/// Entries are kept in memory, and also on disk if a cache directory is
+/// configured. Entries only found on disk are brought back into memory when
+/// they're used.
+pub struct Cache {
+    memory: Mutex<MemoryStore>,
+    disk: Option<DiskStore>,
+    // The request headers that replies for each primary key vary by, as of
+    // the latest one stored.
+    vary: Mutex<HashMap<Key, Vec<String>>>,
+    collapser: Arc<Collapser>,
+    // How many times each entry has been found.
+    hits: Mutex<HashMap<Key, u64>>,
+    enabled: bool,
+    max_object_size: usize,
+}
+
+impl Cache {
+    pub fn new(config: &Config) -> io::Result<Cache> {
+        let disk = match config.cache_dir {
+            Some(ref dir) => Some(try!(DiskStore::open(dir, config.cache_disk_max_size))),
+            None => None,
+        };
+
+        // Anything that survived a restart has to be found again.
+        let mut vary = HashMap::new();
+        if let Some(ref disk) = disk {
+            for key in disk.keys() {
+                if !key.variant().is_empty() {
+                    vary.insert(key.primary(), key.variant().names());
+                }
+            }
+        }
+
+        Ok(Cache {
+            memory: Mutex::new(MemoryStore::new(config.cache_max_size)),
+            disk: disk,
+            vary: Mutex::new(vary),
+            collapser: Arc::new(Collapser::new()),
+            hits: Mutex::new(HashMap::new()),
+            enabled: config.cache_max_size > 0,
+            max_object_size: config.cache_max_object_size,
+        })
+    }
+
+    /// Find a stored reply to `request`, and whether it can be used at `now`
+    /// without contacting the origin.
+    pub fn lookup(&self, request: &Request, now: SystemTime) -> Lookup {
+        if !self.enabled || (request.method != "GET" && request.method != "HEAD") {
+            return Lookup::Miss;
+        }
+
+        let entry = match self.get(&self.key_for(request)) {
+            Some(entry) => entry,
+            None => return Lookup::Miss,
+        };
+
+        let request_cc = CacheControl::from_headers(&request.headers);
+        let no_cache = request_cc.no_cache ||
+            (request.headers.get("cache-control").is_none() && request.headers.contains_token("pragma", "no-cache"));
+
+        if !no_cache && is_usable(&entry, &request_cc, now) {
+            return Lookup::Fresh(entry);
+        }
+
+        // A stale entry is still of use to revalidate, or to fall back on if
+        // that fails.
+        if request.method != "GET" {
+            return Lookup::Miss;
+        }
+
+        let reply_cc = CacheControl::from_headers(&entry.reply.headers);
+        if !no_cache && may_serve_stale(&entry, &reply_cc, reply_cc.stale_while_revalidate, now) {
+            Lookup::StaleWhileRevalidate(entry)
+        } else {
+            Lookup::Stale(entry)
+        }
+    }
+
+    /// Take part in collapsed forwarding for `request`, so that only one of
+    /// any identical requests missing the cache at once goes to the origin.
+    ///
+    /// Returns None if the reply couldn't be cached anyway. Range requests
+    /// aren't collapsed either, as they'd get the whole thing or be given
+    /// only part of what other requests want.
+    pub fn collapse(&self, request: &Request) -> Option<Collapse> {
+        if !self.enabled || request.method != "GET" || request.headers.get("range").is_some() {
+            return None;
+        }
+
+        Some(Collapser::join(&self.collapser, self.key_for(request)))
+    }
+
+    /// Whether the reply to a `method` request with `request_headers` may be
+    /// stored, as per RFC 7234 section 3.
+    pub fn is_storable(&self, method: &str, request_headers: &Headers, reply: &Reply) -> bool {
+        if !self.enabled || method != "GET" {
+            return false;
+        }
+
+        // Partial content and 304s need an existing entry to make sense of.
+        if reply.code < 200 || reply.code == 206 || reply.code == 304 {
+            return false;
+        }
+
+        let request_cc = CacheControl::from_headers(request_headers);
+        let reply_cc = CacheControl::from_headers(&reply.headers);
+
+        if request_cc.no_store || reply_cc.no_store || reply_cc.private {
+            return false;
+        }
+
+        // Authenticated replies are only shared if the origin says so.
+        if request_headers.get("authorization").is_some() &&
+            !(reply_cc.public || reply_cc.must_revalidate || reply_cc.s_maxage.is_some()) {
+            return false;
+        }
+
+        // No request can ever match `Vary: *`.
+        if vary::vary_names(reply).is_none() {
+            return false;
+        }
+
+        if let Ok(Some(length)) = reply.headers.content_length() {
+            if length > self.max_object_size {
+                return false;
+            }
+        }
+
+        reply_cc.public || freshness::has_explicit_lifetime(reply) || freshness::is_heuristically_cacheable(reply.code)
+    }
+
+    /// Something to collect the body of a storable reply in.
+    pub fn capture(&self) -> Capture {
+        Capture::new(self.max_object_size)
+    }
+
+    /// The key a reply to `request` would be found under, going by what
+    /// replies for its URL were last known to vary by.
+    fn key_for(&self, request: &Request) -> Key {
+        // HEAD requests can be answered from a stored GET.
+        let primary = Key::new("GET", &request.url);
+        let names = self.vary.lock().unwrap().get(&primary).cloned();
+
+        match names {
+            Some(names) => Key::with_variant("GET", &request.url, Variant::select(&names, &request.headers)),
+            None => primary,
+        }
+    }
+
+    /// Store `entry` as the reply to `request`.
+    pub fn store(&self, request: &Request, entry: Entry) {
+        let names = match vary::vary_names(&entry.reply) {
+            Some(names) => names,
+            None => return,
+        };
+
+        let variant = Variant::select(&names, &request.headers);
+        let key = Key::with_variant(&request.method, &request.url, variant);
+
+        {
+            let mut vary = self.vary.lock().unwrap();
+            if names.is_empty() {
+                vary.remove(&key.primary());
+            } else {
+                vary.insert(key.primary(), names);
+            }
+        }
+
+        debug!("Caching {}", key);
+        self.hits.lock().unwrap().remove(&key);
+
+        // It's written to disk in the background, and found in memory in
+        // the meantime.
+        if let Some(ref disk) = self.disk {
+            disk.insert(key.clone(), entry.clone());
+        }
+
+        self.memory.lock().unwrap().insert(key, entry);
+    }
+
+    /// Forget everything stored for `url`, e.g. after it was changed by an
+    /// unsafe request, as per RFC 7234 section 4.4.
+    pub fn invalidate(&self, url: &url::Url) {
+        let primary = Key::new("GET", url);
+
+        if self.purge(|key| key.primary() == primary) > 0 {
+            debug!("Invalidated cached {}", url);
+        }
+    }
+
+    /// Remove every entry whose key `matches`, returning how many there were.
+    pub fn purge<F: Fn(&Key) -> bool>(&self, matches: F) -> usize {
+        let mut removed = HashSet::new();
+
+        {
+            let mut memory = self.memory.lock().unwrap();
+            for key in memory.keys() {
+                if matches(&key) {
+                    memory.remove(&key);
+                    removed.insert(key);
+                }
+            }
+        }
+
+        if let Some(ref disk) = self.disk {
+            removed.extend(disk.remove_matching(&matches));
+        }
+
+        self.vary.lock().unwrap().retain(|primary, _| !matches(primary));
+
+        let mut hits = self.hits.lock().unwrap();
+        for key in &removed {
+            hits.remove(key);
+        }
+
+        removed.len()
+    }
+
+    /// Describe every entry in the cache as of `now`, most recently stored
+    /// first.
+    pub fn summaries(&self, now: SystemTime) -> Vec<Summary> {
+        let mut summaries = Vec::new();
+        let mut seen = HashSet::new();
+
+        {
+            let memory = self.memory.lock().unwrap();
+            for key in memory.keys() {
+                if let Some(entry) = memory.peek(&key) {
+                    summaries.push(Summary::new(key.clone(), &entry, entry.size() as u64, now));
+                    seen.insert(key);
+                }
+            }
+        }
+
+        if let Some(ref disk) = self.disk {
+            for key in disk.keys() {
+                if seen.contains(&key) {
+                    continue;
+                }
+
+                match disk.peek(&key) {
+                    Ok(Some((entry, size))) => {
+                        summaries.push(Summary::new(key.clone(), &entry, size, now));
+                        seen.insert(key);
+                    },
+                    Ok(None) => {},
+                    Err(e) => error!("Error reading {} from the disk cache: {}", key, e),
+                }
+            }
+        }
+
+        // Anything evicted since it was last hit can be forgotten about now.
+        let mut hits = self.hits.lock().unwrap();
+        hits.retain(|key, _| seen.contains(key));
+
+        for summary in &mut summaries {
+            summary.hits = hits.get(&summary.key).cloned().unwrap_or(0);
+        }
+
+        summaries.sort_by(|a, b| b.response_time.cmp(&a.response_time));
+        summaries
+    }
+
+    fn get(&self, key: &Key) -> Option<Entry> {
+        let entry = self.find(key);
+
+        if entry.is_some() {
+            *self.hits.lock().unwrap().entry(key.clone()).or_insert(0) += 1;
+        }
+
+        entry
+    }
+
+    fn find(&self, key: &Key) -> Option<Entry> {
+        if let Some(entry) = self.memory.lock().unwrap().get(key) {
+            return Some(entry);
+        }
+
+        let disk = match self.disk {
+            Some(ref disk) => disk,
+            None => return None,
+        };
+
+        match disk.get(key) {
+            Ok(Some(entry)) => {
+                self.memory.lock().unwrap().insert(key.clone(), entry.clone());
+                Some(entry)
+            },
+            Ok(None) => None,
+            Err(e) => {
+                error!("Error reading {} from the disk cache: {}", key, e);
+                None
+            }
+        }
+    }
+}
+
+/// Whether `entry` can be served in response to a request with `request_cc`,
+/// as per RFC 7234 section 4.2.4 and section 5.2.1.
+fn is_usable(entry: &Entry, request_cc: &CacheControl, now: SystemTime) -> bool {
+    let reply_cc = CacheControl::from_headers(&entry.reply.headers);
+
+    if reply_cc.no_cache {
+        return false;
+    }
+
+    let lifetime = entry.freshness_lifetime();
+    let age = entry.current_age(now);
+
+    if let Some(max_age) = request_cc.max_age {
+        if age > max_age {
+            return false;
+        }
+    }
+
+    if let Some(min_fresh) = request_cc.min_fresh {
+        if age + min_fresh > lifetime {
+            return false;
+        }
+    }
+
+    if age < lifetime {
+        return true;
+    }
+
+    // The client can allow a stale reply to be served, but not if the
+    // origin insists otherwise.
+    let allowed = match request_cc.max_stale {
+        Some(None) => true,
+        Some(Some(max_stale)) => age - lifetime <= max_stale,
+        None => false,
+    };
+
+    allowed && !forbids_stale(&reply_cc)
+}
+
+/// Whether `entry`, with the Cache-Control directives `reply_cc`, can be
+/// served up to `allowance` past its freshness lifetime at `now`.
+fn may_serve_stale(entry: &Entry, reply_cc: &CacheControl, allowance: Option<Duration>, now: SystemTime) -> bool {
+    match allowance {
+        Some(allowance) => !forbids_stale(reply_cc) && entry.staleness(now) <= allowance,
+        None => false,
+    }
+}
+
+/// Whether the origin forbids a reply with the Cache-Control directives
+/// `reply_cc` being served stale under any circumstances.
+fn forbids_stale(reply_cc: &CacheControl) -> bool {
+    reply_cc.no_cache || reply_cc.must_revalidate || reply_cc.proxy_revalidate || reply_cc.s_maxage.is_some()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+    use config::Config;
+    use http::request::Request;
+    use http::test_support::{reply, request};
+
+    use super::freshness::format_date;
+    use std::str::FromStr;
+
+    use super::vary::Variant;
+    use super::collapse::Collapse;
+    use super::{Cache, Capture, Entry, Key, Lookup};
+
+    fn at(seconds: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(1500000000 + seconds)
+    }
+
+    fn fresh(lookup: Lookup) -> Option<Entry> {
+        match lookup {
+            Lookup::Fresh(entry) => Some(entry),
+            _ => None,
+        }
+    }
+
+    fn cache_with(raw_reply: &str) -> (Cache, Request) {
+        let cache = Cache::new(&Config::default()).unwrap();
+        let req = request("GET http://example.com/a?b=c HTTP/1.1\r\n\r\n");
+        let rep = reply(raw_reply);
+
+        assert!(cache.is_storable(&req.method, &req.headers, &rep));
+        cache.store(&req, Entry::new(rep, b"Hello".to_vec(), at(0), at(0)));
+
+        (cache, req)
+    }
+
+    #[test]
+    fn test_key() {
+        let url = ::http::request::parse_authority("example.com:443").unwrap();
+        assert_eq!(Key::new("GET", &url).to_string(), "GET https://example.com/");
+
+        let req = request("GET /path?query HTTP/1.1\r\nHost: example.com\r\n\r\n");
+        assert_eq!(Key::new("GET", &req.url).url(), "http://example.com/path?query");
+
+        let names = vec![String::from("accept-encoding")];
+        let key = Key::with_variant("GET", &req.url, Variant::select(&names, &req.headers));
+        assert_eq!(key.to_string(), "GET http://example.com/path?query accept-encoding");
+        assert_eq!(Key::from_str(&key.to_string()).unwrap(), key);
+        assert_eq!(key.primary(), Key::new("GET", &req.url));
+    }
+
+    #[test]
+    fn test_lookup_fresh() {
+        let date = format_date(at(0));
+        let (cache, req) = cache_with(&format!("HTTP/1.1 200 OK\r\nDate: {}\r\nCache-Control: max-age=60\r\nTransfer-Encoding: chunked\r\n\r\n", date));
+
+        let entry = fresh(cache.lookup(&req, at(30))).unwrap();
+        assert_eq!(&entry.body[..], b"Hello");
+        assert_eq!(entry.reply.headers.content_length().unwrap(), Some(5));
+        assert!(entry.reply.headers.get("transfer-encoding").is_none());
+        assert_eq!(entry.reply_at(at(30)).headers.get("age").unwrap(), b"30");
+
+        assert!(fresh(cache.lookup(&req, at(60))).is_none());
+        assert!(fresh(cache.lookup(&request("GET http://example.com/a HTTP/1.1\r\n\r\n"), at(30))).is_none());
+        assert!(fresh(cache.lookup(&request("HEAD http://example.com/a?b=c HTTP/1.1\r\n\r\n"), at(30))).is_some());
+        assert!(fresh(cache.lookup(&request("POST http://example.com/a?b=c HTTP/1.1\r\n\r\n"), at(30))).is_none());
+    }
+
+    #[test]
+    fn test_lookup_request_directives() {
+        let date = format_date(at(0));
+        let (cache, _) = cache_with(&format!("HTTP/1.1 200 OK\r\nDate: {}\r\nCache-Control: max-age=60\r\n\r\n", date));
+
+        let with = |headers: &str| request(&format!("GET http://example.com/a?b=c HTTP/1.1\r\n{}\r\n", headers));
+
+        assert!(fresh(cache.lookup(&with("Cache-Control: no-cache\r\n"), at(30))).is_none());
+        assert!(fresh(cache.lookup(&with("Pragma: no-cache\r\n"), at(30))).is_none());
+        assert!(fresh(cache.lookup(&with("Cache-Control: max-age=10\r\n"), at(30))).is_none());
+        assert!(fresh(cache.lookup(&with("Cache-Control: min-fresh=40\r\n"), at(30))).is_none());
+        assert!(fresh(cache.lookup(&with("Cache-Control: min-fresh=20\r\n"), at(30))).is_some());
+
+        assert!(fresh(cache.lookup(&with("Cache-Control: max-stale=10\r\n"), at(80))).is_none());
+        let entry = fresh(cache.lookup(&with("Cache-Control: max-stale=30\r\n"), at(80))).unwrap();
+        assert!(entry.reply_at(at(80)).headers.get("warning").is_some());
+        assert!(fresh(cache.lookup(&with("Cache-Control: max-stale\r\n"), at(8000))).is_some());
+    }
+
+    #[test]
+    fn test_lookup_must_revalidate() {
+        let date = format_date(at(0));
+        let (cache, _) = cache_with(&format!("HTTP/1.1 200 OK\r\nDate: {}\r\nCache-Control: max-age=60, must-revalidate\r\n\r\n", date));
+
+        let req = request("GET http://example.com/a?b=c HTTP/1.1\r\nCache-Control: max-stale\r\n\r\n");
+        assert!(fresh(cache.lookup(&req, at(80))).is_none());
+    }
+
+    #[test]
+    fn test_is_storable() {
+        let cache = Cache::new(&Config::default()).unwrap();
+        let get = request("GET http://example.com/ HTTP/1.1\r\n\r\n");
+        let authorized = request("GET http://example.com/ HTTP/1.1\r\nAuthorization: Basic Zm9vOmJhcg==\r\n\r\n");
+        let no_store = request("GET http://example.com/ HTTP/1.1\r\nCache-Control: no-store\r\n\r\n");
+
+        let storable = |req: &Request, raw: &str| cache.is_storable(&req.method, &req.headers, &reply(raw));
+
+        assert!(storable(&get, "HTTP/1.1 200 OK\r\n\r\n"));
+        assert!(storable(&get, "HTTP/1.1 404 Not Found\r\n\r\n"));
+        assert!(storable(&get, "HTTP/1.1 302 Found\r\nCache-Control: max-age=60\r\n\r\n"));
+        assert!(!storable(&get, "HTTP/1.1 302 Found\r\n\r\n"));
+        assert!(!storable(&get, "HTTP/1.1 200 OK\r\nCache-Control: private\r\n\r\n"));
+        assert!(!storable(&get, "HTTP/1.1 200 OK\r\nCache-Control: no-store\r\n\r\n"));
+        assert!(storable(&get, "HTTP/1.1 200 OK\r\nVary: Accept-Encoding\r\n\r\n"));
+        assert!(!storable(&get, "HTTP/1.1 200 OK\r\nVary: Accept-Encoding, *\r\n\r\n"));
+        assert!(!storable(&get, "HTTP/1.1 206 Partial Content\r\n\r\n"));
+        assert!(!storable(&get, "HTTP/1.1 200 OK\r\nContent-Length: 999999999999\r\n\r\n"));
+        assert!(!storable(&no_store, "HTTP/1.1 200 OK\r\n\r\n"));
+        assert!(!storable(&authorized, "HTTP/1.1 200 OK\r\n\r\n"));
+        assert!(storable(&authorized, "HTTP/1.1 200 OK\r\nCache-Control: public\r\n\r\n"));
+
+        let post = request("POST http://example.com/ HTTP/1.1\r\n\r\n");
+        assert!(!storable(&post, "HTTP/1.1 200 OK\r\nCache-Control: max-age=60\r\n\r\n"));
+    }
+
+    #[test]
+    fn test_invalidate() {
+        let (cache, req) = cache_with("HTTP/1.1 200 OK\r\nCache-Control: max-age=60\r\n\r\n");
+
+        cache.invalidate(&req.url);
+        assert!(fresh(cache.lookup(&req, at(0))).is_none());
+    }
+
+    #[test]
+    fn test_capture() {
+        use std::io::Write;
+
+        let mut capture = Capture::new(10);
+        capture.write_all(b"Hello").unwrap();
+        assert_eq!(capture.into_body().unwrap(), b"Hello");
+
+        let mut capture = Capture::new(10);
+        capture.write_all(b"Hello").unwrap();
+        capture.write_all(b" world").unwrap();
+        assert!(capture.into_body().is_none());
+    }
+
+    #[test]
+    fn test_summaries_and_purge() {
+        let date = format_date(at(0));
+        let (cache, req) = cache_with(&format!("HTTP/1.1 200 OK\r\nDate: {}\r\nCache-Control: max-age=60\r\n\r\n", date));
+
+        assert!(fresh(cache.lookup(&req, at(10))).is_some());
+        assert!(fresh(cache.lookup(&req, at(20))).is_some());
+
+        let summaries = cache.summaries(at(90));
+        assert_eq!(summaries.len(), 1);
+        assert_eq!(summaries[0].key, Key::new("GET", &req.url));
+        assert_eq!(summaries[0].hits, 2);
+        assert_eq!(summaries[0].age, Duration::from_secs(90));
+        assert!(!summaries[0].is_fresh());
+
+        assert_eq!(cache.purge(|key| key.url().starts_with("http://other.example.com/")), 0);
+        assert_eq!(cache.purge(|key| key.url().starts_with("http://example.com/")), 1);
+        assert!(cache.summaries(at(90)).is_empty());
+    }
+
+    #[test]
+    fn test_collapse() {
+        use std::io::Write;
+
+        let cache = Cache::new(&Config::default()).unwrap();
+        let req = request("GET http://example.com/ HTTP/1.1\r\n\r\n");
+
+        let leader = match cache.collapse(&req) {
+            Some(Collapse::Leader(leader)) => leader,
+            _ => panic!("expected to lead"),
+        };
+        assert!(match cache.collapse(&req) { Some(Collapse::Follower(_)) => true, _ => false });
+        assert!(cache.collapse(&request("HEAD http://example.com/ HTTP/1.1\r\n\r\n")).is_none());
+
+        // Shared bodies are limited like any other.
+        let mut capture = Capture::new(10);
+        capture.share(leader, &reply("HTTP/1.1 200 OK\r\n\r\n"), &req.headers);
+        capture.write_all(b"Hello").unwrap();
+        assert_eq!(capture.into_body().unwrap(), b"Hello");
+    }
+
+    #[test]
+    fn test_lookup_stale() {
+        let date = format_date(at(0));
+        let (cache, req) = cache_with(&format!("HTTP/1.1 200 OK\r\nDate: {}\r\nCache-Control: max-age=60\r\nETag: \"v1\"\r\n\r\n", date));
+
+        match cache.lookup(&req, at(90)) {
+            Lookup::Stale(entry) => assert_eq!(&entry.body[..], b"Hello"),
+            other => panic!("expected a stale entry, got {:?}", other),
+        }
+
+        // The client insisting on revalidation.
+        let no_cache = request("GET http://example.com/a?b=c HTTP/1.1\r\nCache-Control: no-cache\r\n\r\n");
+        assert!(match cache.lookup(&no_cache, at(0)) { Lookup::Stale(_) => true, _ => false });
+
+        // There's no revalidating a HEAD into a stored GET.
+        let head = request("HEAD http://example.com/a?b=c HTTP/1.1\r\n\r\n");
+        assert!(match cache.lookup(&head, at(90)) { Lookup::Miss => true, _ => false });
+    }
+
+    #[test]
+    fn test_lookup_stale_without_validators() {
+        let date = format_date(at(0));
+        let (cache, req) = cache_with(&format!("HTTP/1.1 200 OK\r\nDate: {}\r\nCache-Control: max-age=60\r\n\r\n", date));
+
+        // It can't be revalidated, but can still be fallen back on.
+        assert!(match cache.lookup(&req, at(90)) { Lookup::Stale(_) => true, _ => false });
+    }
+
+    #[test]
+    fn test_lookup_stale_while_revalidate() {
+        let date = format_date(at(0));
+        let (cache, req) = cache_with(&format!("HTTP/1.1 200 OK\r\nDate: {}\r\nCache-Control: max-age=60, stale-while-revalidate=30\r\n\r\n", date));
+
+        assert!(match cache.lookup(&req, at(30)) { Lookup::Fresh(_) => true, _ => false });
+        assert!(match cache.lookup(&req, at(80)) { Lookup::StaleWhileRevalidate(_) => true, _ => false });
+        assert!(match cache.lookup(&req, at(100)) { Lookup::Stale(_) => true, _ => false });
+
+        let no_cache = request("GET http://example.com/a?b=c HTTP/1.1\r\nCache-Control: no-cache\r\n\r\n");
+        assert!(match cache.lookup(&no_cache, at(80)) { Lookup::Stale(_) => true, _ => false });
+
+        let (cache, req) = cache_with(&format!("HTTP/1.1 200 OK\r\nDate: {}\r\nCache-Control: max-age=60, stale-while-revalidate=30, must-revalidate\r\n\r\n", date));
+        assert!(match cache.lookup(&req, at(80)) { Lookup::Stale(_) => true, _ => false });
+    }
+
+    #[test]
+    fn test_usable_on_error() {
+        let date = format_date(at(0));
+        let stored = Entry::new(reply(&format!("HTTP/1.1 200 OK\r\nDate: {}\r\nCache-Control: max-age=60, stale-if-error=60\r\n\r\n", date)),
+                                b"Hello".to_vec(), at(0), at(0));
+
+        let plain = request("GET http://example.com/ HTTP/1.1\r\n\r\n");
+        let patient = request("GET http://example.com/ HTTP/1.1\r\nCache-Control: stale-if-error=600\r\n\r\n");
+
+        assert!(stored.is_usable_on_error(&plain.headers, at(100)));
+        assert!(!stored.is_usable_on_error(&plain.headers, at(200)));
+        assert!(stored.is_usable_on_error(&patient.headers, at(200)));
+
+        let strict = Entry::new(reply(&format!("HTTP/1.1 200 OK\r\nDate: {}\r\nCache-Control: max-age=60, proxy-revalidate\r\n\r\n", date)),
+                                b"Hello".to_vec(), at(0), at(0));
+        assert!(!strict.is_usable_on_error(&patient.headers, at(100)));
+    }
+
+    #[test]
+    fn test_refresh() {
+        let date = format_date(at(0));
+        let stored = Entry::new(reply(&format!("HTTP/1.1 200 OK\r\nDate: {}\r\nCache-Control: max-age=60\r\nETag: \"v1\"\r\nContent-Type: text/plain\r\n\r\n", date)),
+                                b"Hello".to_vec(), at(0), at(0));
+
+        let later = format_date(at(100));
+        let not_modified = reply(&format!("HTTP/1.1 304 Not Modified\r\nDate: {}\r\nCache-Control: max-age=120\r\nETag: \"v1\"\r\nContent-Length: 0\r\n\r\n", later));
+
+        let refreshed = stored.refresh(&not_modified, at(100), at(100)).unwrap();
+        assert!(refreshed.is_fresh(at(200)));
+        assert_eq!(refreshed.reply.headers.get("cache-control").unwrap(), b"max-age=120");
+        assert_eq!(refreshed.reply.headers.get("content-type").unwrap(), b"text/plain");
+        assert_eq!(refreshed.reply.headers.content_length().unwrap(), Some(5));
+        assert_eq!(&refreshed.body[..], b"Hello");
+
+        let changed = reply("HTTP/1.1 304 Not Modified\r\nETag: \"v2\"\r\n\r\n");
+        assert!(stored.refresh(&changed, at(100), at(100)).is_none());
+    }
+
+    #[test]
+    fn test_variants() {
+        let cache = Cache::new(&Config::default()).unwrap();
+        let date = format_date(at(0));
+        let raw = format!("HTTP/1.1 200 OK\r\nDate: {}\r\nCache-Control: max-age=60\r\nVary: Accept-Encoding\r\n\r\n", date);
+
+        let gzip = request("GET http://example.com/ HTTP/1.1\r\nAccept-Encoding: gzip\r\n\r\n");
+        let identity = request("GET http://example.com/ HTTP/1.1\r\n\r\n");
+
+        cache.store(&gzip, Entry::new(reply(&raw), b"zipped".to_vec(), at(0), at(0)));
+        assert!(fresh(cache.lookup(&identity, at(1))).is_none());
+
+        cache.store(&identity, Entry::new(reply(&raw), b"plain".to_vec(), at(0), at(0)));
+        assert_eq!(&fresh(cache.lookup(&gzip, at(1))).unwrap().body[..], b"zipped");
+        assert_eq!(&fresh(cache.lookup(&identity, at(1))).unwrap().body[..], b"plain");
+
+        let deflate = request("GET http://example.com/ HTTP/1.1\r\nAccept-Encoding: deflate\r\n\r\n");
+        assert!(fresh(cache.lookup(&deflate, at(1))).is_none());
+
+        cache.invalidate(&gzip.url);
+        assert!(fresh(cache.lookup(&gzip, at(1))).is_none());
+        assert!(fresh(cache.lookup(&identity, at(1))).is_none());
+    }
+}
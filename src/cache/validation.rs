@@ -0,0 +1,237 @@
+use std::str;
+
+use http::headers::Headers;
+use http::reply::Reply;
+
+use super::freshness::parse_date;
+
+// Headers sent along with a 304 Not Modified, as per RFC 7232 section 4.1,
+// plus the ones a cache adds.
+const NOT_MODIFIED_HEADERS: &'static [&'static str] = &[
+    "Cache-Control",
+    "Content-Location",
+    "Date",
+    "ETag",
+    "Expires",
+    "Last-Modified",
+    "Vary",
+    "Age",
+    "Warning",
+];
+
+/// Whether `reply` has anything to revalidate it with.
+pub fn has_validators(reply: &Reply) -> bool {
+    reply.headers.get("etag").is_some() || reply.headers.get("last-modified").is_some()
+}
+
+/// Make a request conditional on the stored `reply` having changed, so the
+/// origin can answer with 304 Not Modified if it hasn't.
+///
+/// Any conditions the client set itself are replaced, and are evaluated
+/// against whatever ends up being served instead.
+pub fn make_conditional(headers: &mut Headers, reply: &Reply) {
+    headers.remove("if-none-match");
+    headers.remove("if-modified-since");
+
+    if let Some(etag) = reply.headers.get("etag") {
+        headers.insert("If-None-Match", etag);
+    }
+
+    if let Some(last_modified) = reply.headers.get("last-modified") {
+        headers.insert("If-Modified-Since", last_modified);
+    }
+}
+
+/// Whether a client sending `request_headers` already has `reply`, as per
+/// RFC 7232 section 3.2 and section 3.3.
+pub fn is_not_modified(request_headers: &Headers, reply: &Reply) -> bool {
+    let if_none_match = request_headers.get_all("if-none-match");
+
+    // If-None-Match takes precedence when both are given.
+    if !if_none_match.is_empty() {
+        let etag = reply.headers.get("etag").and_then(|etag| str::from_utf8(etag).ok());
+
+        return if_none_match.iter().any(|value| {
+            match str::from_utf8(value) {
+                Ok(value) => {
+                    split_etags(value).iter().any(|tag| {
+                        match etag {
+                            Some(etag) => tag == "*" || weak_match(tag, etag),
+                            None => tag == "*",
+                        }
+                    })
+                },
+                Err(_) => false,
+            }
+        });
+    }
+
+    match parse_date(request_headers, "if-modified-since") {
+        Some(since) => {
+            match parse_date(&reply.headers, "last-modified").or_else(|| parse_date(&reply.headers, "date")) {
+                Some(modified) => modified <= since,
+                None => false,
+            }
+        },
+        None => false,
+    }
+}
+
+/// Turn a full reply into a 304 Not Modified for a client that already has
+/// it.
+pub fn not_modified(reply: &Reply) -> Reply {
+    let mut headers = Headers::new();
+
+    for name in NOT_MODIFIED_HEADERS {
+        for value in reply.headers.get_all(name) {
+            headers.insert(name, value);
+        }
+    }
+
+    Reply {
+        version: reply.version,
+        code: 304,
+        reason: String::from("Not Modified"),
+        headers: headers,
+    }
+}
+
+/// Whether a 304 Not Modified `validated` is about the stored `reply`, as
+/// opposed to some other representation, as per RFC 7234 section 4.3.4.
+pub fn validates(validated: &Reply, reply: &Reply) -> bool {
+    let etag = |reply: &Reply| reply.headers.get("etag").and_then(|etag| str::from_utf8(etag).ok()).map(String::from);
+
+    match (etag(validated), etag(reply)) {
+        (Some(a), Some(b)) => weak_match(&a, &b),
+        (Some(_), None) => false,
+        _ => true,
+    }
+}
+
+/// Compare two entity-tags using the weak comparison function of RFC 7232
+/// section 2.3.2, i.e. ignoring whether either is weak.
+pub fn weak_match(a: &str, b: &str) -> bool {
+    let opaque = |tag: &str| {
+        let tag = tag.trim();
+        if tag.starts_with("W/") {
+            String::from(&tag[2..])
+        } else {
+            String::from(tag)
+        }
+    };
+
+    opaque(a) == opaque(b)
+}
+
+/// Split a list of entity-tags. Commas can appear inside the quotes.
+fn split_etags(value: &str) -> Vec<String> {
+    let mut tags = Vec::new();
+    let mut current = String::new();
+    let mut quoted = false;
+
+    for c in value.chars() {
+        match c {
+            '"' => {
+                quoted = !quoted;
+                current.push(c);
+            },
+            ',' if !quoted => {
+                tags.push(current.trim().to_string());
+                current = String::new();
+            },
+            _ => current.push(c),
+        }
+    }
+
+    tags.push(current.trim().to_string());
+    tags.into_iter().filter(|tag| !tag.is_empty()).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use http::headers::Headers;
+    use http::reply::Reply;
+
+    use super::{is_not_modified, make_conditional, not_modified, validates, weak_match};
+
+    fn reply(headers: &[(&str, &str)]) -> Reply {
+        let mut h = Headers::new();
+        for &(name, value) in headers {
+            h.insert(name, &value.as_bytes().to_vec());
+        }
+
+        Reply {
+            version: 1,
+            code: 200,
+            reason: String::from("OK"),
+            headers: h,
+        }
+    }
+
+    fn headers(headers: &[(&str, &str)]) -> Headers {
+        reply(headers).headers
+    }
+
+    #[test]
+    fn test_weak_match() {
+        assert!(weak_match("\"abc\"", "\"abc\""));
+        assert!(weak_match("W/\"abc\"", "\"abc\""));
+        assert!(!weak_match("\"abc\"", "\"abd\""));
+    }
+
+    #[test]
+    fn test_if_none_match() {
+        let stored = reply(&[("ETag", "W/\"v1\""), ("Last-Modified", "Sat, 01 Jan 2000 00:00:00 GMT")]);
+
+        assert!(is_not_modified(&headers(&[("If-None-Match", "\"v1\"")]), &stored));
+        assert!(is_not_modified(&headers(&[("If-None-Match", "\"v0\", \"a,b\", W/\"v1\"")]), &stored));
+        assert!(is_not_modified(&headers(&[("If-None-Match", "*")]), &stored));
+        assert!(!is_not_modified(&headers(&[("If-None-Match", "\"v2\"")]), &stored));
+
+        // If-None-Match wins over If-Modified-Since.
+        assert!(!is_not_modified(&headers(&[("If-None-Match", "\"v2\""), ("If-Modified-Since", "Sat, 01 Jan 2000 00:00:00 GMT")]), &stored));
+    }
+
+    #[test]
+    fn test_if_modified_since() {
+        let stored = reply(&[("Last-Modified", "Sat, 01 Jan 2000 00:00:00 GMT")]);
+
+        assert!(is_not_modified(&headers(&[("If-Modified-Since", "Sat, 01 Jan 2000 00:00:00 GMT")]), &stored));
+        assert!(is_not_modified(&headers(&[("If-Modified-Since", "Sun, 02 Jan 2000 00:00:00 GMT")]), &stored));
+        assert!(!is_not_modified(&headers(&[("If-Modified-Since", "Fri, 31 Dec 1999 00:00:00 GMT")]), &stored));
+        assert!(!is_not_modified(&headers(&[("If-Modified-Since", "yesterday")]), &stored));
+        assert!(!is_not_modified(&headers(&[]), &stored));
+    }
+
+    #[test]
+    fn test_make_conditional() {
+        let stored = reply(&[("ETag", "\"v1\""), ("Last-Modified", "Sat, 01 Jan 2000 00:00:00 GMT")]);
+        let mut request = headers(&[("If-None-Match", "\"mine\"")]);
+
+        make_conditional(&mut request, &stored);
+        assert_eq!(request.get_all("if-none-match"), vec![&b"\"v1\"".to_vec()]);
+        assert_eq!(request.get("if-modified-since").unwrap(), b"Sat, 01 Jan 2000 00:00:00 GMT");
+    }
+
+    #[test]
+    fn test_not_modified() {
+        let stored = reply(&[("ETag", "\"v1\""), ("Content-Length", "5"), ("Content-Type", "text/plain"), ("Cache-Control", "max-age=60")]);
+        let reply = not_modified(&stored);
+
+        assert_eq!(reply.code, 304);
+        assert_eq!(reply.headers.get("etag").unwrap(), b"\"v1\"");
+        assert_eq!(reply.headers.get("cache-control").unwrap(), b"max-age=60");
+        assert!(reply.headers.get("content-length").is_none());
+        assert!(reply.headers.get("content-type").is_none());
+    }
+
+    #[test]
+    fn test_validates() {
+        let stored = reply(&[("ETag", "\"v1\"")]);
+
+        assert!(validates(&reply(&[("ETag", "\"v1\"")]), &stored));
+        assert!(validates(&reply(&[]), &stored));
+        assert!(!validates(&reply(&[("ETag", "\"v2\"")]), &stored));
+        assert!(!validates(&reply(&[("ETag", "\"v1\"")]), &reply(&[])));
+    }
+}
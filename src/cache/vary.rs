@@ -0,0 +1,232 @@
+use std::fmt;
+use std::str::{self, FromStr};
+
+use http::headers::Headers;
+use http::reply::Reply;
+
+/// The request headers that a varying reply was selected by, as per RFC 7234
+/// section 4.1. A request only gets the same reply if its headers match.
+///
+/// Each header is recorded by lowercase name along with its value, or None if
+/// the request didn't have it at all.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Hash)]
+pub struct Variant {
+    headers: Vec<(String, Option<String>)>,
+}
+
+impl Variant {
+    /// Pick the headers named by `names` out of `request_headers`.
+    pub fn select(names: &[String], request_headers: &Headers) -> Variant {
+        let headers = names.iter().map(|name| {
+            let values = request_headers.get_all(name);
+
+            let value = if values.is_empty() {
+                None
+            } else {
+                let values: Vec<String> = values.iter()
+                    .map(|value| normalize(&String::from_utf8_lossy(value)))
+                    .collect();
+                Some(values.join(", "))
+            };
+
+            (name.clone(), value)
+        }).collect();
+
+        Variant {
+            headers: headers,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.headers.is_empty()
+    }
+
+    /// The names of the headers this variant was selected by.
+    pub fn names(&self) -> Vec<String> {
+        self.headers.iter().map(|&(ref name, _)| name.clone()).collect()
+    }
+}
+
+/// Written as `name="value"; other`, where `other` wasn't in the request.
+impl fmt::Display for Variant {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (i, &(ref name, ref value)) in self.headers.iter().enumerate() {
+            if i > 0 {
+                try!(write!(f, "; "));
+            }
+
+            match *value {
+                Some(ref value) => try!(write!(f, "{}=\"{}\"", name, value.replace('\\', "\\\\").replace('"', "\\\""))),
+                None => try!(write!(f, "{}", name)),
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl FromStr for Variant {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Variant, String> {
+        let invalid = || Err(format!("Invalid variant {}", s));
+
+        let mut headers = Vec::new();
+        let mut chars = s.chars().peekable();
+
+        loop {
+            while chars.peek() == Some(&' ') {
+                chars.next();
+            }
+
+            let mut name = String::new();
+            while let Some(&c) = chars.peek() {
+                if c == '=' || c == ';' {
+                    break;
+                }
+                name.push(c);
+                chars.next();
+            }
+
+            if name.is_empty() {
+                if chars.peek().is_none() && headers.is_empty() {
+                    break;
+                }
+                return invalid();
+            }
+
+            let value = if chars.peek() == Some(&'=') {
+                chars.next();
+                if chars.next() != Some('"') {
+                    return invalid();
+                }
+
+                let mut value = String::new();
+                loop {
+                    match chars.next() {
+                        Some('\\') => {
+                            match chars.next() {
+                                Some(c) => value.push(c),
+                                None => return invalid(),
+                            }
+                        },
+                        Some('"') => break,
+                        Some(c) => value.push(c),
+                        None => return invalid(),
+                    }
+                }
+                Some(value)
+            } else {
+                None
+            };
+
+            headers.push((name, value));
+
+            match chars.next() {
+                Some(';') => continue,
+                None => break,
+                Some(_) => return invalid(),
+            }
+        }
+
+        Ok(Variant {
+            headers: headers,
+        })
+    }
+}
+
+/// The request headers named by the Vary header of `reply`, lowercased and
+/// sorted, or None for `Vary: *`, which no request can ever match.
+pub fn vary_names(reply: &Reply) -> Option<Vec<String>> {
+    let mut names = Vec::new();
+
+    for value in reply.headers.get_all("vary") {
+        let value = String::from_utf8_lossy(value);
+
+        for name in value.split(',') {
+            let name = name.trim().to_lowercase();
+
+            if name == "*" {
+                return None;
+            }
+
+            if !name.is_empty() && !names.contains(&name) {
+                names.push(name);
+            }
+        }
+    }
+
+    names.sort();
+    Some(names)
+}
+
+/// Collapse insignificant whitespace, so that trivially different headers
+/// still select the same variant.
+fn normalize(value: &str) -> String {
+    let parts: Vec<&str> = value.split(',').map(|part| part.trim()).collect();
+    parts.join(", ")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use http::headers::Headers;
+    use http::reply::Reply;
+
+    use super::{vary_names, Variant};
+
+    fn headers(headers: &[(&str, &str)]) -> Headers {
+        let mut h = Headers::new();
+        for &(name, value) in headers {
+            h.insert(name, &value.as_bytes().to_vec());
+        }
+        h
+    }
+
+    fn reply(vary: &[&str]) -> Reply {
+        let h: Vec<(&str, &str)> = vary.iter().map(|value| ("Vary", *value)).collect();
+
+        Reply {
+            version: 1,
+            code: 200,
+            reason: String::from("OK"),
+            headers: headers(&h),
+        }
+    }
+
+    #[test]
+    fn test_vary_names() {
+        assert_eq!(vary_names(&reply(&[])), Some(vec![]));
+        assert_eq!(vary_names(&reply(&["Accept-Language, accept-encoding", "Accept-Encoding"])),
+                   Some(vec![String::from("accept-encoding"), String::from("accept-language")]));
+        assert_eq!(vary_names(&reply(&["Accept-Encoding, *"])), None);
+    }
+
+    #[test]
+    fn test_select() {
+        let names = vec![String::from("accept-encoding"), String::from("accept-language")];
+
+        let gzip = Variant::select(&names, &headers(&[("Accept-Encoding", "gzip,  deflate")]));
+        let gzip_again = Variant::select(&names, &headers(&[("accept-encoding", "gzip, deflate"), ("Cookie", "a=b")]));
+        let plain = Variant::select(&names, &headers(&[]));
+        let empty = Variant::select(&names, &headers(&[("Accept-Encoding", "")]));
+
+        assert_eq!(gzip, gzip_again);
+        assert!(gzip != plain);
+        assert!(plain != empty);
+        assert_eq!(gzip.names(), names);
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let names = vec![String::from("accept-encoding"), String::from("accept-language"), String::from("x-odd")];
+        let variant = Variant::select(&names, &headers(&[("Accept-Encoding", "gzip"), ("X-Odd", "a \"quoted\"; thing\\")]));
+
+        assert_eq!(variant.to_string(), "accept-encoding=\"gzip\"; accept-language; x-odd=\"a \\\"quoted\\\"; thing\\\\\"");
+        assert_eq!(Variant::from_str(&variant.to_string()).unwrap(), variant);
+        assert_eq!(Variant::from_str("").unwrap(), Variant::default());
+        assert!(Variant::from_str("a=\"unterminated").is_err());
+        assert!(Variant::from_str("a=b").is_err());
+    }
+}
@@ -0,0 +1,162 @@
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr};
+use std::str::FromStr;
+
+/// A block of IP addresses, e.g. `10.0.0.0/8` or `2001:db8::/32`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Cidr {
+    network: IpAddr,
+    prefix_len: u8,
+}
+
+impl Cidr {
+    pub fn new(network: IpAddr, prefix_len: u8) -> Result<Cidr, String> {
+        let max = match network {
+            IpAddr::V4(_) => 32,
+            IpAddr::V6(_) => 128,
+        };
+
+        if prefix_len > max {
+            return Err(format!("Prefix length {} is too long for {}", prefix_len, network));
+        }
+
+        Ok(Cidr {
+            network: network,
+            prefix_len: prefix_len,
+        })
+    }
+
+    /// Whether `address` falls within this block. IPv4 addresses mapped into
+    /// IPv6, as seen on dual-stack sockets, are treated as IPv4.
+    pub fn contains(&self, address: &IpAddr) -> bool {
+        match (self.network, unmap(address)) {
+            (IpAddr::V4(network), IpAddr::V4(address)) => {
+                prefix_matches(&network.octets(), &address.octets(), self.prefix_len)
+            },
+            (IpAddr::V6(network), IpAddr::V6(address)) => {
+                prefix_matches(&network.octets(), &address.octets(), self.prefix_len)
+            },
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = String;
+
+    /// Parse `address/prefix_len`, or a lone address meaning just that one.
+    fn from_str(s: &str) -> Result<Cidr, String> {
+        let (address, prefix_len) = match s.find('/') {
+            Some(i) => (&s[..i], Some(&s[i + 1..])),
+            None => (s, None),
+        };
+
+        let network = match IpAddr::from_str(address) {
+            Ok(network) => network,
+            Err(_) => return Err(format!("Invalid address in {}", s)),
+        };
+
+        let prefix_len = match prefix_len {
+            Some(prefix_len) => {
+                match prefix_len.parse() {
+                    Ok(prefix_len) => prefix_len,
+                    Err(_) => return Err(format!("Invalid prefix length in {}", s)),
+                }
+            },
+            None => {
+                match network {
+                    IpAddr::V4(_) => 32,
+                    IpAddr::V6(_) => 128,
+                }
+            }
+        };
+
+        Cidr::new(network, prefix_len)
+    }
+}
+
+impl fmt::Display for Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}/{}", self.network, self.prefix_len)
+    }
+}
+
+/// Turn an IPv4-mapped IPv6 address, as clients connecting over IPv4 to a
+/// dual-stack socket show up with, back into the IPv4 address it stands for.
+/// Anything else is returned as it is.
+pub fn unmap(address: &IpAddr) -> IpAddr {
+    if let IpAddr::V6(v6) = *address {
+        let segments = v6.segments();
+        if segments[..5] == [0, 0, 0, 0, 0] && segments[5] == 0xffff {
+            let octets = v6.octets();
+            return IpAddr::V4(Ipv4Addr::new(octets[12], octets[13], octets[14], octets[15]));
+        }
+    }
+
+    *address
+}
+
+fn prefix_matches(network: &[u8], address: &[u8], prefix_len: u8) -> bool {
+    let whole = (prefix_len / 8) as usize;
+    let remainder = prefix_len % 8;
+
+    if network[..whole] != address[..whole] {
+        return false;
+    }
+
+    if remainder == 0 {
+        return true;
+    }
+
+    let mask = 0xffu8 << (8 - remainder);
+    network[whole] & mask == address[whole] & mask
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::IpAddr;
+    use std::str::FromStr;
+
+    use super::{unmap, Cidr};
+
+    fn ip(s: &str) -> IpAddr {
+        IpAddr::from_str(s).unwrap()
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(Cidr::from_str("10.0.0.0/8").unwrap().to_string(), "10.0.0.0/8");
+        assert_eq!(Cidr::from_str("10.1.2.3").unwrap().to_string(), "10.1.2.3/32");
+        assert_eq!(Cidr::from_str("2001:db8::/32").unwrap().to_string(), "2001:db8::/32");
+        assert_eq!(Cidr::from_str("::1").unwrap().to_string(), "::1/128");
+
+        assert!(Cidr::from_str("10.0.0.0/33").is_err());
+        assert!(Cidr::from_str("10.0.0.0/").is_err());
+        assert!(Cidr::from_str("10.0.0/8").is_err());
+        assert!(Cidr::from_str("localhost").is_err());
+    }
+
+    #[test]
+    fn test_contains() {
+        let private = Cidr::from_str("172.16.0.0/12").unwrap();
+        assert!(private.contains(&ip("172.16.0.1")));
+        assert!(private.contains(&ip("172.31.255.255")));
+        assert!(!private.contains(&ip("172.32.0.0")));
+        assert!(!private.contains(&ip("::1")));
+        assert!(private.contains(&ip("::ffff:172.20.1.1")));
+
+        let doc = Cidr::from_str("2001:db8::/32").unwrap();
+        assert!(doc.contains(&ip("2001:db8:1::1")));
+        assert!(!doc.contains(&ip("2001:db9::1")));
+
+        let everything = Cidr::from_str("0.0.0.0/0").unwrap();
+        assert!(everything.contains(&ip("8.8.8.8")));
+    }
+
+    #[test]
+    fn test_unmap() {
+        assert_eq!(unmap(&ip("::ffff:10.1.2.3")), ip("10.1.2.3"));
+        assert_eq!(unmap(&ip("2001:db8::1")), ip("2001:db8::1"));
+        assert_eq!(unmap(&ip("10.1.2.3")), ip("10.1.2.3"));
+    }
+}
@@ -0,0 +1,163 @@
+extern crate clap;
+
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use octopus::config::ListenAddress;
+
+use self::clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
+
+/// What the binary has been asked to do.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Command {
+    /// Run the proxy.
+    Run,
+    /// Load and validate the configuration, then exit.
+    Check,
+}
+
+/// Everything given on the command line.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Options {
+    pub command: Command,
+    pub config: Option<PathBuf>,
+    /// Addresses to listen on in place of the configured listeners.
+    pub listen: Vec<ListenAddress>,
+    /// How many levels more verbose than configured to log, or less if
+    /// negative.
+    pub verbosity: isize,
+}
+
+fn app<'a, 'b>() -> App<'a, 'b> {
+    let args = vec![
+        Arg::with_name("config")
+            .short("c")
+            .long("config")
+            .value_name("FILE")
+            .help("Reads the configuration from FILE")
+            .takes_value(true)
+            .global(true),
+        Arg::with_name("listen")
+            .short("l")
+            .long("listen")
+            .value_name("ADDR:PORT|unix:PATH")
+            .help("Listens on ADDR:PORT or a Unix domain socket at PATH as a forward proxy, instead of on the configured listeners; may be repeated")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .validator(|value| ListenAddress::from_str(&value).map(|_| ()))
+            .global(true),
+        Arg::with_name("check-config")
+            .long("check-config")
+            .help("Checks the configuration and exits, like the check command")
+            .global(true),
+        Arg::with_name("verbose")
+            .short("v")
+            .long("verbose")
+            .help("Logs more; may be repeated")
+            .multiple(true)
+            .global(true),
+        Arg::with_name("quiet")
+            .short("q")
+            .long("quiet")
+            .help("Logs less; may be repeated")
+            .multiple(true)
+            .global(true),
+    ];
+
+    App::new("octopus")
+        .version(env!("CARGO_PKG_VERSION"))
+        .about("A caching HTTP proxy")
+        .setting(AppSettings::VersionlessSubcommands)
+        .args(&args)
+        .subcommand(SubCommand::with_name("run").about("Runs the proxy (the default)"))
+        .subcommand(SubCommand::with_name("check").about("Checks the configuration and exits"))
+}
+
+/// Parse `args`, including the program name. Exits, after printing help or
+/// an error, if they're asked for or the arguments are invalid.
+pub fn parse<I, T>(args: I) -> Options
+    where I: IntoIterator<Item = T>, T: Into<::std::ffi::OsString> + Clone
+{
+    match app().get_matches_from_safe(args) {
+        Ok(matches) => options(&matches),
+        Err(e) => e.exit(),
+    }
+}
+
+fn options(matches: &ArgMatches) -> Options {
+    // The subcommand's matches have the global arguments wherever they were
+    // given, so they're all read from there.
+    let (command, sub) = match matches.subcommand() {
+        ("check", Some(sub)) => (Command::Check, sub),
+        (_, Some(sub)) => (Command::Run, sub),
+        _ => (Command::Run, matches),
+    };
+
+    let command = if sub.is_present("check-config") { Command::Check } else { command };
+
+    let listen = match sub.values_of("listen") {
+        Some(values) => values.map(|value| ListenAddress::from_str(value).unwrap()).collect(),
+        None => Vec::new(),
+    };
+
+    Options {
+        command: command,
+        config: sub.value_of("config").map(PathBuf::from),
+        listen: listen,
+        verbosity: sub.occurrences_of("verbose") as isize - sub.occurrences_of("quiet") as isize,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use octopus::config::ListenAddress;
+
+    use super::{app, options, Command};
+
+    fn parse(args: &[&str]) -> Result<super::Options, String> {
+        let mut full = vec!["octopus"];
+        full.extend(args);
+        app().get_matches_from_safe(full).map(|matches| options(&matches)).map_err(|e| e.message)
+    }
+
+    #[test]
+    fn test_defaults() {
+        let options = parse(&[]).unwrap();
+        assert_eq!(options.command, Command::Run);
+        assert_eq!(options.config, None);
+        assert!(options.listen.is_empty());
+        assert_eq!(options.verbosity, 0);
+    }
+
+    #[test]
+    fn test_options() {
+        let options = parse(&["-c", "/etc/octopus.toml", "--listen", "0.0.0.0:3128", "-l", "unix:/run/octopus.sock", "-vv", "-q"]).unwrap();
+        assert_eq!(options.command, Command::Run);
+        assert_eq!(options.config, Some(PathBuf::from("/etc/octopus.toml")));
+        assert_eq!(options.listen.len(), 2);
+        assert_eq!(options.listen[1], ListenAddress::Unix { path: PathBuf::from("/run/octopus.sock"), mode: None });
+        assert_eq!(options.verbosity, 1);
+
+        assert!(parse(&["--listen", "localhost"]).is_err());
+    }
+
+    #[test]
+    fn test_subcommands() {
+        let options = parse(&["check", "--config", "octopus.toml"]).unwrap();
+        assert_eq!(options.command, Command::Check);
+        assert_eq!(options.config, Some(PathBuf::from("octopus.toml")));
+
+        // Options can come before the subcommand too.
+        let options = parse(&["-c", "octopus.toml", "-v", "check"]).unwrap();
+        assert_eq!(options.command, Command::Check);
+        assert_eq!(options.config, Some(PathBuf::from("octopus.toml")));
+        assert_eq!(options.verbosity, 1);
+
+        assert_eq!(parse(&["run", "-v"]).unwrap().verbosity, 1);
+        assert_eq!(parse(&["--check-config"]).unwrap().command, Command::Check);
+        assert!(parse(&["frobnicate"]).is_err());
+    }
+}
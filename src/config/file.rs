@@ -0,0 +1,731 @@
+extern crate toml;
+extern crate url;
+
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::File;
+use std::io::Read;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+
+use cidr::Cidr;
+use logging::Level;
+
+use self::toml::value::{Table, Value};
+
+use super::{Config, ListenAddress, Listener, Peer, Role};
+
+/// What's wrong with a configuration file.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConfigError {
+    /// The offending key, e.g. `listener[0].address`, if it's down to one.
+    pub key: Option<String>,
+    /// Where in the file the problem is, counting from 1.
+    pub line: Option<usize>,
+    pub message: String,
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(line) = self.line {
+            try!(write!(f, "line {}: ", line));
+        }
+
+        if let Some(ref key) = self.key {
+            try!(write!(f, "{}: ", key));
+        }
+
+        write!(f, "{}", self.message)
+    }
+}
+
+/// Read the configuration from the TOML file at `path`.
+pub fn load(path: &Path) -> Result<Config, ConfigError> {
+    let mut source = String::new();
+    let read = File::open(path).and_then(|mut file| file.read_to_string(&mut source));
+
+    if let Err(e) = read {
+        return Err(ConfigError {
+            key: None,
+            line: None,
+            message: e.to_string(),
+        });
+    }
+
+    parse(&source, path.parent().unwrap_or(Path::new(".")))
+}
+
+/// Parse a configuration from TOML. Relative paths in it are taken to be
+/// relative to `base`.
+pub fn parse(source: &str, base: &Path) -> Result<Config, ConfigError> {
+    let root = match source.parse::<Value>() {
+        Ok(Value::Table(root)) => root,
+        Ok(_) => unreachable!(),
+        Err(e) => {
+            return Err(ConfigError {
+                key: None,
+                line: e.line_col().map(|(line, _)| line + 1),
+                message: e.to_string(),
+            });
+        }
+    };
+
+    let loader = Loader {
+        lines: key_lines(source),
+        base: base,
+    };
+
+    loader.config(&root)
+}
+
+/// Works out which line every key and table header in `source` is on, keyed
+/// by their full names, e.g. `listener[1].address`.
+fn key_lines(source: &str) -> HashMap<String, usize> {
+    let mut lines = HashMap::new();
+    let mut counts: HashMap<String, usize> = HashMap::new();
+    let mut section = String::new();
+
+    for (i, line) in source.lines().enumerate() {
+        let line = line.trim();
+
+        if line.starts_with("[[") {
+            let name = line[2..].split("]]").next().unwrap_or("").trim().to_string();
+            let count = counts.entry(name.clone()).or_insert(0);
+            section = format!("{}[{}]", name, count);
+            *count += 1;
+        } else if line.starts_with('[') {
+            section = line[1..].split(']').next().unwrap_or("").trim().to_string();
+        } else if let Some(eq) = line.find('=') {
+            let key = line[..eq].trim().trim_matches('"');
+            let key = if section.is_empty() { key.to_string() } else { format!("{}.{}", section, key) };
+            lines.entry(key).or_insert(i + 1);
+            continue;
+        } else {
+            continue;
+        }
+
+        lines.entry(section.clone()).or_insert(i + 1);
+    }
+
+    lines
+}
+
+fn join(prefix: &str, key: &str) -> String {
+    if prefix.is_empty() {
+        key.to_string()
+    } else {
+        format!("{}.{}", prefix, key)
+    }
+}
+
+struct Loader<'a> {
+    lines: HashMap<String, usize>,
+    base: &'a Path,
+}
+
+impl<'a> Loader<'a> {
+    fn config(&self, root: &Table) -> Result<Config, ConfigError> {
+        let mut config = Config::default();
+
+        try!(self.known_keys(root, "", &["via_pseudonym", "listener", "timeouts", "limits", "cache", "acl", "admin", "upstream", "logging", "error_pages"]));
+
+        if let Some(pseudonym) = try!(self.string(root, "", "via_pseudonym")) {
+            if pseudonym.is_empty() || pseudonym.contains(|c: char| c.is_whitespace() || c == ',') {
+                return Err(self.error("via_pseudonym", "must be a single token"));
+            }
+            config.via_pseudonym = pseudonym.to_string();
+        }
+
+        if let Some(listeners) = try!(self.tables(root, "", "listener")) {
+            config.listeners.clear();
+
+            for (i, listener) in listeners.iter().enumerate() {
+                let prefix = format!("listener[{}]", i);
+                let listener = try!(self.listener(listener, &prefix));
+
+                if config.listeners.iter().any(|other| other.address == listener.address) {
+                    return Err(self.error(&prefix, &format!("{} is listened on more than once", listener.address)));
+                }
+
+                config.listeners.push(listener);
+            }
+
+            if config.listeners.is_empty() {
+                return Err(self.error("listener", "there has to be at least one listener"));
+            }
+        }
+
+        if let Some(timeouts) = try!(self.table(root, "", "timeouts")) {
+            let prefix = "timeouts";
+            try!(self.known_keys(timeouts, prefix, &["header_read", "keep_alive", "client_read", "tunnel_idle", "upstream_connect", "upstream_read", "pool_idle", "drain"]));
+
+            if let Some(timeout) = try!(self.seconds(timeouts, prefix, "header_read", false)) {
+                config.header_read_timeout = timeout;
+            }
+            if let Some(timeout) = try!(self.seconds(timeouts, prefix, "keep_alive", false)) {
+                config.keep_alive_timeout = timeout;
+            }
+            if let Some(timeout) = try!(self.seconds(timeouts, prefix, "client_read", false)) {
+                config.client_read_timeout = timeout;
+            }
+            if let Some(timeout) = try!(self.seconds(timeouts, prefix, "tunnel_idle", false)) {
+                config.tunnel_idle_timeout = timeout;
+            }
+            if let Some(timeout) = try!(self.seconds(timeouts, prefix, "upstream_connect", false)) {
+                config.upstream_connect_timeout = timeout;
+            }
+            if let Some(timeout) = try!(self.seconds(timeouts, prefix, "upstream_read", false)) {
+                config.upstream_read_timeout = timeout;
+            }
+            if let Some(timeout) = try!(self.seconds(timeouts, prefix, "pool_idle", true)) {
+                config.pool_idle_timeout = timeout;
+            }
+            if let Some(timeout) = try!(self.seconds(timeouts, prefix, "drain", true)) {
+                config.drain_timeout = timeout;
+            }
+        }
+
+        if let Some(limits) = try!(self.table(root, "", "limits")) {
+            let prefix = "limits";
+            try!(self.known_keys(limits, prefix, &["max_request_headers", "max_request_header_bytes"]));
+
+            if let Some(max) = try!(self.size(limits, prefix, "max_request_headers", false)) {
+                config.max_request_headers = max as usize;
+            }
+            if let Some(max) = try!(self.size(limits, prefix, "max_request_header_bytes", false)) {
+                config.max_request_header_bytes = max as usize;
+            }
+        }
+
+        if let Some(cache) = try!(self.table(root, "", "cache")) {
+            let prefix = "cache";
+            try!(self.known_keys(cache, prefix, &["max_size", "max_object_size", "dir", "disk_max_size"]));
+
+            if let Some(size) = try!(self.size(cache, prefix, "max_size", true)) {
+                config.cache_max_size = size as usize;
+            }
+            if let Some(size) = try!(self.size(cache, prefix, "max_object_size", true)) {
+                config.cache_max_object_size = size as usize;
+            }
+            if let Some(dir) = try!(self.string(cache, prefix, "dir")) {
+                config.cache_dir = Some(self.path(dir));
+            }
+            if let Some(size) = try!(self.size(cache, prefix, "disk_max_size", true)) {
+                config.cache_disk_max_size = size;
+            }
+        }
+
+        if let Some(acl) = try!(self.table(root, "", "acl")) {
+            let prefix = "acl";
+            try!(self.known_keys(acl, prefix, &["clients", "unix_clients", "connect_ports", "trusted_proxies"]));
+
+            if let Some(clients) = try!(self.cidrs(acl, prefix, "clients")) {
+                config.allowed_clients = clients;
+            }
+            if let Some(unix) = try!(self.boolean(acl, prefix, "unix_clients")) {
+                config.allowed_unix_clients = unix;
+            }
+            if let Some(ports) = try!(self.ports(acl, prefix, "connect_ports")) {
+                config.connect_ports = ports;
+            }
+            if let Some(proxies) = try!(self.cidrs(acl, prefix, "trusted_proxies")) {
+                config.trusted_proxies = proxies;
+            }
+        }
+
+        if let Some(admin) = try!(self.table(root, "", "admin")) {
+            let prefix = "admin";
+            try!(self.known_keys(admin, prefix, &["host", "clients", "unix_clients"]));
+
+            if let Some(host) = try!(self.string(admin, prefix, "host")) {
+                if host.is_empty() {
+                    return Err(self.error("admin.host", "can't be empty"));
+                }
+                config.admin_host = host.to_lowercase();
+            }
+            if let Some(clients) = try!(self.cidrs(admin, prefix, "clients")) {
+                config.admin_clients = clients;
+            }
+            if let Some(unix) = try!(self.boolean(admin, prefix, "unix_clients")) {
+                config.admin_unix_clients = unix;
+            }
+        }
+
+        // Nobody could use an admin listener on a Unix domain socket.
+        for (i, listener) in config.listeners.iter().enumerate() {
+            if let ListenAddress::Unix { .. } = listener.address {
+                if listener.role == Role::Admin && !config.admin_unix_clients {
+                    return Err(self.error(&format!("listener[{}]", i), "admin listeners on Unix domain sockets need admin.unix_clients to be set"));
+                }
+            }
+        }
+
+        if let Some(upstream) = try!(self.table(root, "", "upstream")) {
+            let prefix = "upstream";
+            try!(self.known_keys(upstream, prefix, &["pool_max_idle_per_host", "forwarded_headers", "peer"]));
+
+            if let Some(max) = try!(self.size(upstream, prefix, "pool_max_idle_per_host", true)) {
+                config.pool_max_idle_per_host = max as usize;
+            }
+            if let Some(forwarded) = try!(self.boolean(upstream, prefix, "forwarded_headers")) {
+                config.forwarded_headers = forwarded;
+            }
+
+            if let Some(peers) = try!(self.tables(upstream, prefix, "peer")) {
+                for (i, peer) in peers.iter().enumerate() {
+                    let prefix = format!("upstream.peer[{}]", i);
+                    config.peers.push(try!(self.peer(peer, &prefix)));
+                }
+            }
+        }
+
+        if let Some(logging) = try!(self.table(root, "", "logging")) {
+            let prefix = "logging";
+            try!(self.known_keys(logging, prefix, &["level"]));
+
+            if let Some(level) = try!(self.string(logging, prefix, "level")) {
+                config.log_level = match Level::from_str(level) {
+                    Ok(level) => level,
+                    Err(e) => return Err(self.error("logging.level", &e)),
+                };
+            }
+        }
+
+        if let Some(pages) = try!(self.table(root, "", "error_pages")) {
+            let prefix = "error_pages";
+            try!(self.known_keys(pages, prefix, &["html", "text"]));
+
+            if let Some(html) = try!(self.string(pages, prefix, "html")) {
+                config.error_templates.html = try!(self.read_file(html, "error_pages.html"));
+            }
+            if let Some(text) = try!(self.string(pages, prefix, "text")) {
+                config.error_templates.text = try!(self.read_file(text, "error_pages.text"));
+            }
+        }
+
+        Ok(config)
+    }
+
+    fn listener(&self, listener: &Table, prefix: &str) -> Result<Listener, ConfigError> {
+        try!(self.known_keys(listener, prefix, &["address", "path", "mode", "role", "origin"]));
+
+        let address = match (try!(self.string(listener, prefix, "address")), try!(self.string(listener, prefix, "path"))) {
+            (Some(address), None) => {
+                if listener.contains_key("mode") {
+                    return Err(self.error(&join(prefix, "mode"), "only Unix domain sockets have a mode"));
+                }
+
+                match SocketAddr::from_str(address) {
+                    Ok(address) => ListenAddress::Tcp(address),
+                    Err(_) => return Err(self.error(&join(prefix, "address"), &format!("{} isn't an address and port", address))),
+                }
+            },
+            (None, Some(path)) => {
+                if path.is_empty() {
+                    return Err(self.error(&join(prefix, "path"), "can't be empty"));
+                }
+
+                let mode = match try!(self.string(listener, prefix, "mode")) {
+                    Some(mode) => match u32::from_str_radix(mode, 8) {
+                        Ok(mode) if mode <= 0o7777 => Some(mode),
+                        _ => return Err(self.error(&join(prefix, "mode"), &format!("{} isn't an octal file mode, e.g. \"0660\"", mode))),
+                    },
+                    None => None,
+                };
+
+                ListenAddress::Unix {
+                    path: self.path(path),
+                    mode: mode,
+                }
+            },
+            (Some(_), Some(_)) => return Err(self.error(prefix, "has both an address and a path")),
+            (None, None) => return Err(self.error(prefix, "address or path is missing")),
+        };
+
+        let origin = try!(self.string(listener, prefix, "origin"));
+
+        let role = match try!(self.string(listener, prefix, "role")).unwrap_or("forward") {
+            "forward" => Role::Forward,
+            "reverse" => {
+                let origin = match origin {
+                    Some(origin) => origin,
+                    None => return Err(self.error(prefix, "reverse proxies need an origin")),
+                };
+
+                match url::Url::parse(origin) {
+                    Ok(ref url) if url.scheme() == "http" && url.host_str().is_some() => Role::Reverse(url.clone()),
+                    _ => return Err(self.error(&join(prefix, "origin"), &format!("{} isn't an http:// URL", origin))),
+                }
+            },
+            "admin" => Role::Admin,
+            role => return Err(self.error(&join(prefix, "role"), &format!("{} isn't a role, expected forward, reverse or admin", role))),
+        };
+
+        if origin.is_some() && !match role { Role::Reverse(_) => true, _ => false } {
+            return Err(self.error(&join(prefix, "origin"), "only reverse proxies have an origin"));
+        }
+
+        Ok(Listener {
+            address: address,
+            role: role,
+        })
+    }
+
+    fn peer(&self, peer: &Table, prefix: &str) -> Result<Peer, ConfigError> {
+        try!(self.known_keys(peer, prefix, &["address", "domains"]));
+
+        let address = match try!(self.string(peer, prefix, "address")) {
+            Some(address) => address,
+            None => return Err(self.error(prefix, "address is missing")),
+        };
+
+        let valid = match address.rfind(':') {
+            Some(i) => i > 0 && address[i + 1..].parse::<u16>().map(|port| port > 0).unwrap_or(false),
+            None => false,
+        };
+        if !valid {
+            return Err(self.error(&join(prefix, "address"), &format!("{} isn't a host and port", address)));
+        }
+
+        let domains = match try!(self.strings(peer, prefix, "domains")) {
+            Some(domains) => domains.iter().map(|domain| domain.trim_matches('.').to_lowercase()).collect(),
+            None => Vec::new(),
+        };
+
+        Ok(Peer {
+            address: address.to_string(),
+            domains: domains,
+        })
+    }
+
+    fn error(&self, key: &str, message: &str) -> ConfigError {
+        // Fall back to the line of the enclosing table if the key itself
+        // isn't there, e.g. because it's missing.
+        let mut name = key;
+        let line = loop {
+            if let Some(&line) = self.lines.get(name) {
+                break Some(line);
+            }
+
+            match name.rfind('.') {
+                Some(i) => name = &name[..i],
+                None => break None,
+            }
+        };
+
+        ConfigError {
+            key: Some(key.to_string()),
+            line: line,
+            message: message.to_string(),
+        }
+    }
+
+    fn known_keys(&self, table: &Table, prefix: &str, known: &[&str]) -> Result<(), ConfigError> {
+        for key in table.keys() {
+            if !known.contains(&&**key) {
+                return Err(self.error(&join(prefix, key), "unknown setting"));
+            }
+        }
+
+        Ok(())
+    }
+
+    fn wrong_type(&self, prefix: &str, key: &str, expected: &str, value: &Value) -> ConfigError {
+        self.error(&join(prefix, key), &format!("expected {}, found {}", expected, value.type_str()))
+    }
+
+    fn table<'t>(&self, table: &'t Table, prefix: &str, key: &str) -> Result<Option<&'t Table>, ConfigError> {
+        match table.get(key) {
+            Some(&Value::Table(ref inner)) => Ok(Some(inner)),
+            Some(value) => Err(self.wrong_type(prefix, key, "a table", value)),
+            None => Ok(None),
+        }
+    }
+
+    fn tables<'t>(&self, table: &'t Table, prefix: &str, key: &str) -> Result<Option<Vec<&'t Table>>, ConfigError> {
+        match table.get(key) {
+            Some(&Value::Array(ref array)) => {
+                let mut tables = Vec::new();
+                for value in array {
+                    match *value {
+                        Value::Table(ref inner) => tables.push(inner),
+                        _ => return Err(self.wrong_type(prefix, key, "an array of tables", value)),
+                    }
+                }
+                Ok(Some(tables))
+            },
+            Some(value) => Err(self.wrong_type(prefix, key, "an array of tables", value)),
+            None => Ok(None),
+        }
+    }
+
+    fn string<'t>(&self, table: &'t Table, prefix: &str, key: &str) -> Result<Option<&'t str>, ConfigError> {
+        match table.get(key) {
+            Some(&Value::String(ref s)) => Ok(Some(s)),
+            Some(value) => Err(self.wrong_type(prefix, key, "a string", value)),
+            None => Ok(None),
+        }
+    }
+
+    fn strings<'t>(&self, table: &'t Table, prefix: &str, key: &str) -> Result<Option<Vec<&'t str>>, ConfigError> {
+        match table.get(key) {
+            Some(&Value::Array(ref array)) => {
+                let mut strings = Vec::new();
+                for value in array {
+                    match *value {
+                        Value::String(ref s) => strings.push(&**s),
+                        _ => return Err(self.wrong_type(prefix, key, "an array of strings", value)),
+                    }
+                }
+                Ok(Some(strings))
+            },
+            Some(value) => Err(self.wrong_type(prefix, key, "an array of strings", value)),
+            None => Ok(None),
+        }
+    }
+
+    fn boolean(&self, table: &Table, prefix: &str, key: &str) -> Result<Option<bool>, ConfigError> {
+        match table.get(key) {
+            Some(&Value::Boolean(b)) => Ok(Some(b)),
+            Some(value) => Err(self.wrong_type(prefix, key, "true or false", value)),
+            None => Ok(None),
+        }
+    }
+
+    /// A count or a number of bytes, which can't be negative, or zero unless
+    /// `allow_zero` is set.
+    fn size(&self, table: &Table, prefix: &str, key: &str, allow_zero: bool) -> Result<Option<u64>, ConfigError> {
+        match table.get(key) {
+            Some(&Value::Integer(n)) if n > 0 || (n == 0 && allow_zero) => Ok(Some(n as u64)),
+            Some(&Value::Integer(n)) => {
+                let expected = if allow_zero { "zero or more" } else { "more than zero" };
+                Err(self.error(&join(prefix, key), &format!("{} is out of range, expected {}", n, expected)))
+            },
+            Some(value) => Err(self.wrong_type(prefix, key, "an integer", value)),
+            None => Ok(None),
+        }
+    }
+
+    fn seconds(&self, table: &Table, prefix: &str, key: &str, allow_zero: bool) -> Result<Option<Duration>, ConfigError> {
+        let seconds = try!(self.size(table, prefix, key, allow_zero));
+        Ok(seconds.map(Duration::from_secs))
+    }
+
+    fn cidrs(&self, table: &Table, prefix: &str, key: &str) -> Result<Option<Vec<Cidr>>, ConfigError> {
+        let strings = match try!(self.strings(table, prefix, key)) {
+            Some(strings) => strings,
+            None => return Ok(None),
+        };
+
+        let mut cidrs = Vec::new();
+        for s in strings {
+            match Cidr::from_str(s) {
+                Ok(cidr) => cidrs.push(cidr),
+                Err(e) => return Err(self.error(&join(prefix, key), &e)),
+            }
+        }
+
+        Ok(Some(cidrs))
+    }
+
+    fn ports(&self, table: &Table, prefix: &str, key: &str) -> Result<Option<Vec<u16>>, ConfigError> {
+        match table.get(key) {
+            Some(&Value::Array(ref array)) => {
+                let mut ports = Vec::new();
+                for value in array {
+                    match *value {
+                        Value::Integer(port) if port > 0 && port <= 65535 => ports.push(port as u16),
+                        Value::Integer(port) => {
+                            return Err(self.error(&join(prefix, key), &format!("{} isn't a valid port", port)));
+                        },
+                        _ => return Err(self.wrong_type(prefix, key, "an array of ports", value)),
+                    }
+                }
+                Ok(Some(ports))
+            },
+            Some(value) => Err(self.wrong_type(prefix, key, "an array of ports", value)),
+            None => Ok(None),
+        }
+    }
+
+    fn path(&self, path: &str) -> PathBuf {
+        self.base.join(path)
+    }
+
+    fn read_file(&self, path: &str, key: &str) -> Result<String, ConfigError> {
+        let path = self.path(path);
+        let mut contents = String::new();
+
+        match File::open(&path).and_then(|mut file| file.read_to_string(&mut contents)) {
+            Ok(_) => Ok(contents),
+            Err(e) => Err(self.error(key, &format!("could not read {}: {}", path.display(), e))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+    use std::path::{Path, PathBuf};
+    use std::str::FromStr;
+    use std::time::Duration;
+
+    use config::{ListenAddress, Role};
+    use logging::Level;
+
+    use super::{parse, ConfigError};
+
+    fn error(source: &str) -> ConfigError {
+        parse(source, Path::new("/etc/octopus")).unwrap_err()
+    }
+
+    #[test]
+    fn test_parse() {
+        let config = parse(r#"
+via_pseudonym = "squid1"
+
+[[listener]]
+address = "0.0.0.0:3128"
+
+[[listener]]
+address = "[::1]:3128"
+
+[timeouts]
+header_read = 5
+client_read = 20
+tunnel_idle = 600
+pool_idle = 0
+drain = 10
+
+[cache]
+max_size = 0
+dir = "cache"
+
+[acl]
+clients = ["10.0.0.0/8"]
+connect_ports = [443, 8443]
+
+[[upstream.peer]]
+address = "parent.example.com:3128"
+domains = ["Example.com."]
+
+[logging]
+level = "debug"
+"#, Path::new("/etc/octopus")).unwrap();
+
+        assert_eq!(config.via_pseudonym, "squid1");
+        assert_eq!(config.listeners.len(), 2);
+        assert_eq!(config.listeners[1].address, ListenAddress::Tcp(SocketAddr::from_str("[::1]:3128").unwrap()));
+        assert_eq!(config.header_read_timeout, Duration::from_secs(5));
+        assert_eq!(config.client_read_timeout, Duration::from_secs(20));
+        assert_eq!(config.tunnel_idle_timeout, Duration::from_secs(600));
+        assert_eq!(config.pool_idle_timeout, Duration::from_secs(0));
+        assert_eq!(config.drain_timeout, Duration::from_secs(10));
+        assert_eq!(config.keep_alive_timeout, Duration::from_secs(60));
+        assert_eq!(config.cache_max_size, 0);
+        assert_eq!(config.cache_dir.unwrap(), Path::new("/etc/octopus/cache"));
+        assert_eq!(config.allowed_clients.len(), 1);
+        assert!(config.allowed_unix_clients);
+        assert!(!config.admin_unix_clients);
+        assert_eq!(config.connect_ports, vec![443, 8443]);
+        assert_eq!(config.peers[0].address, "parent.example.com:3128");
+        assert_eq!(config.peers[0].domains, vec![String::from("example.com")]);
+        assert_eq!(config.log_level, Level::Debug);
+
+        // Everything is optional.
+        assert!(parse("", Path::new(".")).is_ok());
+    }
+
+    #[test]
+    fn test_listeners() {
+        let config = parse(r#"
+[[listener]]
+address = "[::]:3128"
+
+[[listener]]
+path = "octopus.sock"
+mode = "0660"
+role = "admin"
+
+[[listener]]
+address = "0.0.0.0:80"
+role = "reverse"
+origin = "http://10.0.0.5:8080/"
+
+[admin]
+unix_clients = true
+"#, Path::new("/run")).unwrap();
+
+        assert_eq!(config.listeners[0].role, Role::Forward);
+        assert_eq!(config.listeners[1].address, ListenAddress::Unix { path: PathBuf::from("/run/octopus.sock"), mode: Some(0o660) });
+        assert_eq!(config.listeners[1].role, Role::Admin);
+        match config.listeners[2].role {
+            Role::Reverse(ref origin) => assert_eq!(origin.as_str(), "http://10.0.0.5:8080/"),
+            ref role => panic!("expected a reverse proxy, got {:?}", role),
+        }
+
+        let e = error("[[listener]]\npath = \"a.sock\"\n\n[[listener]]\npath = \"a.sock\"\n");
+        assert_eq!(e.to_string(), "line 4: listener[1]: unix:/etc/octopus/a.sock is listened on more than once");
+
+        let e = error("[[listener]]\naddress = \"[::]:80\"\nmode = \"0600\"\n");
+        assert_eq!(e.key.as_ref().unwrap(), "listener[0].mode");
+
+        let e = error("[[listener]]\npath = \"a.sock\"\nmode = \"rw\"\n");
+        assert_eq!(e.line, Some(3));
+
+        let e = error("[[listener]]\naddress = \"[::]:80\"\nrole = \"reverse\"\n");
+        assert_eq!(e.message, "reverse proxies need an origin");
+
+        let e = error("[[listener]]\naddress = \"[::]:80\"\nrole = \"reverse\"\norigin = \"ftp://example.com/\"\n");
+        assert_eq!(e.key.as_ref().unwrap(), "listener[0].origin");
+
+        let e = error("[[listener]]\naddress = \"[::]:80\"\norigin = \"http://example.com/\"\n");
+        assert_eq!(e.message, "only reverse proxies have an origin");
+
+        let e = error("[[listener]]\naddress = \"[::]:80\"\nrole = \"sideways\"\n");
+        assert_eq!(e.line, Some(3));
+
+        let e = error("[[listener]]\npath = \"a.sock\"\nrole = \"admin\"\n");
+        assert_eq!(e.key.as_ref().unwrap(), "listener[0]");
+        assert_eq!(e.line, Some(1));
+    }
+
+    #[test]
+    fn test_errors() {
+        let e = error("[[listener]]\naddress = \"127.0.0.1:3128\"\n\n[[listener]]\naddress = \"localhost\"\n");
+        assert_eq!(e.key.as_ref().unwrap(), "listener[1].address");
+        assert_eq!(e.line, Some(5));
+        assert_eq!(e.to_string(), "line 5: listener[1].address: localhost isn't an address and port");
+
+        let e = error("[timeouts]\n\nheader_read = -1\n");
+        assert_eq!(e.key.as_ref().unwrap(), "timeouts.header_read");
+        assert_eq!(e.line, Some(3));
+
+        let e = error("[acl]\nclients = [\"10.0.0.0/33\"]\n");
+        assert_eq!(e.key.as_ref().unwrap(), "acl.clients");
+        assert_eq!(e.line, Some(2));
+
+        let e = error("[cache]\nmax_size = \"lots\"\n");
+        assert_eq!(e.message, "expected an integer, found string");
+
+        let e = error("[cache]\nmax_sise = 1\n");
+        assert_eq!(e.to_string(), "line 2: cache.max_sise: unknown setting");
+
+        let e = error("[[upstream.peer]]\ndomains = []\n");
+        assert_eq!(e.key.as_ref().unwrap(), "upstream.peer[0]");
+        assert_eq!(e.line, Some(1));
+
+        let e = error("[logging]\nlevel = \"loud\"\n");
+        assert_eq!(e.line, Some(2));
+
+        let e = error("[error_pages]\nhtml = \"missing.html\"\n");
+        assert_eq!(e.key.as_ref().unwrap(), "error_pages.html");
+
+        // Syntax errors have a line, but no key.
+        let e = error("[cache]\nmax_size = \n");
+        assert!(e.key.is_none());
+        assert_eq!(e.line, Some(2));
+    }
+}
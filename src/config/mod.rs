@@ -0,0 +1,290 @@
+extern crate url;
+
+pub mod file;
+
+use std::fmt;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+
+use cidr::Cidr;
+use http::pages::Templates;
+use logging::Level;
+
+pub use self::file::ConfigError;
+
+/// Runtime configuration for the proxy.
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// Where to accept connections from clients.
+    pub listeners: Vec<Listener>,
+
+    /// Clients allowed to use the proxy at all.
+    pub allowed_clients: Vec<Cidr>,
+    /// Whether clients connecting over Unix domain sockets may use the proxy.
+    /// They have no address for `allowed_clients` to match.
+    pub allowed_unix_clients: bool,
+    /// Destination ports that CONNECT requests are allowed to tunnel to.
+    pub connect_ports: Vec<u16>,
+
+    /// The most headers a request may have.
+    pub max_request_headers: usize,
+    /// The most bytes a request head may take up, including the request
+    /// line.
+    pub max_request_header_bytes: usize,
+
+    /// How long a client has to send a whole request head, starting from
+    /// when it connects or sends the first byte of a later request.
+    pub header_read_timeout: Duration,
+    /// How long a client connection may sit idle between requests.
+    pub keep_alive_timeout: Duration,
+    /// How long a client may go without sending anything while it's sending
+    /// a request body.
+    pub client_read_timeout: Duration,
+    /// How long a CONNECT tunnel may go without anything passing through it
+    /// in either direction.
+    pub tunnel_idle_timeout: Duration,
+    /// How long connecting to an upstream server may take, including looking
+    /// it up.
+    pub upstream_connect_timeout: Duration,
+    /// How long an upstream server may go without sending anything while
+    /// it's replying.
+    pub upstream_read_timeout: Duration,
+    /// How long requests still being handled at shutdown are given to
+    /// finish.
+    pub drain_timeout: Duration,
+
+    /// Other proxies to forward requests through rather than going to the
+    /// origin directly. The first one that serves a request's host is used.
+    pub peers: Vec<Peer>,
+    /// How many idle connections to keep open to each origin.
+    pub pool_max_idle_per_host: usize,
+    /// How long an idle upstream connection is kept before being closed.
+    pub pool_idle_timeout: Duration,
+
+    /// Name added to the Via header of everything forwarded. Requests that
+    /// already carry it are rejected as a forwarding loop, so this must be
+    /// unique between proxies that forward to each other.
+    pub via_pseudonym: String,
+
+    /// Whether to add X-Forwarded-For, X-Forwarded-Proto, X-Forwarded-Host
+    /// and Forwarded headers identifying the client.
+    pub forwarded_headers: bool,
+    /// Clients whose own forwarding headers are believed and extended.
+    /// Anyone else's are replaced.
+    pub trusted_proxies: Vec<Cidr>,
+
+    /// The most memory the cache may use for replies, in bytes. Zero
+    /// disables caching.
+    pub cache_max_size: usize,
+    /// Replies with bodies bigger than this, in bytes, aren't cached.
+    pub cache_max_object_size: usize,
+    /// Where to keep cached replies so they survive a restart, if anywhere.
+    pub cache_dir: Option<PathBuf>,
+    /// The most disk space the cache may use, in bytes.
+    pub cache_disk_max_size: u64,
+
+    /// Requests for this host are answered by the proxy's own admin API
+    /// rather than forwarded.
+    pub admin_host: String,
+    /// Clients allowed to use the admin API and the PURGE method.
+    pub admin_clients: Vec<Cidr>,
+    /// Whether clients connecting over Unix domain sockets may use the admin
+    /// API and the PURGE method, whatever their listener's role.
+    pub admin_unix_clients: bool,
+
+    /// What the pages the proxy generates itself to report errors look like.
+    pub error_templates: Templates,
+
+    pub log_level: Level,
+}
+
+/// Somewhere to accept connections, and what they're for.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Listener {
+    pub address: ListenAddress,
+    pub role: Role,
+}
+
+/// A socket to listen on.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ListenAddress {
+    /// An IPv4 or IPv6 address and port. `[::]` takes IPv4 connections too,
+    /// unless the system is set up to keep them apart.
+    Tcp(SocketAddr),
+    /// A Unix domain socket, with the permissions to give it if not the
+    /// default ones. Anything already at `path` that's a socket is replaced.
+    ///
+    /// Its clients have no address, so they're outside every client ACL.
+    /// `Config::allowed_unix_clients` and `Config::admin_unix_clients` say
+    /// what they may do instead, and they're forwarded as `unknown`.
+    Unix {
+        path: PathBuf,
+        mode: Option<u32>,
+    },
+}
+
+impl FromStr for ListenAddress {
+    type Err = String;
+
+    /// Parse `address:port`, or `unix:path` for a Unix domain socket.
+    fn from_str(s: &str) -> Result<ListenAddress, String> {
+        if s.starts_with("unix:") {
+            let path = &s["unix:".len()..];
+            if path.is_empty() {
+                return Err(format!("{} is missing a path", s));
+            }
+
+            return Ok(ListenAddress::Unix {
+                path: PathBuf::from(path),
+                mode: None,
+            });
+        }
+
+        match SocketAddr::from_str(s) {
+            Ok(address) => Ok(ListenAddress::Tcp(address)),
+            Err(_) => Err(format!("{} isn't an address and port, or a unix: path", s)),
+        }
+    }
+}
+
+impl fmt::Display for ListenAddress {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ListenAddress::Tcp(ref address) => write!(f, "{}", address),
+            ListenAddress::Unix { ref path, .. } => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+/// How the clients of a listener use the proxy.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Role {
+    /// As a forward proxy, for whatever URLs they ask for.
+    Forward,
+    /// As a reverse proxy in front of the origin server at this URL, which
+    /// every request is sent to.
+    Reverse(url::Url),
+    /// Only for the admin API.
+    Admin,
+}
+
+/// Another proxy that requests can be forwarded through.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Peer {
+    /// Where to reach it, as `host:port`.
+    pub address: String,
+    /// The hosts it serves, including their subdomains. Empty means all of
+    /// them.
+    pub domains: Vec<String>,
+}
+
+impl Peer {
+    pub fn serves(&self, host: &str) -> bool {
+        if self.domains.is_empty() {
+            return true;
+        }
+
+        let host = host.to_lowercase();
+        self.domains.iter().any(|domain| {
+            host == *domain || (host.ends_with(&**domain) && host[..host.len() - domain.len()].ends_with('.'))
+        })
+    }
+}
+
+impl Config {
+    /// Read the configuration from the TOML file at `path`. Anything it
+    /// doesn't mention keeps its default.
+    pub fn load(path: &Path) -> Result<Config, ConfigError> {
+        file::load(path)
+    }
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            listeners: vec![
+                Listener {
+                    address: ListenAddress::Tcp(SocketAddr::from_str("127.0.0.1:8000").unwrap()),
+                    role: Role::Forward,
+                },
+            ],
+            allowed_clients: vec![
+                Cidr::from_str("0.0.0.0/0").unwrap(),
+                Cidr::from_str("::/0").unwrap(),
+            ],
+            allowed_unix_clients: true,
+            connect_ports: vec![443],
+            max_request_headers: 100,
+            max_request_header_bytes: 64 * 1024,
+            header_read_timeout: Duration::from_secs(30),
+            keep_alive_timeout: Duration::from_secs(60),
+            client_read_timeout: Duration::from_secs(30),
+            tunnel_idle_timeout: Duration::from_secs(300),
+            upstream_connect_timeout: Duration::from_secs(10),
+            upstream_read_timeout: Duration::from_secs(60),
+            drain_timeout: Duration::from_secs(30),
+            peers: Vec::new(),
+            pool_max_idle_per_host: 8,
+            pool_idle_timeout: Duration::from_secs(30),
+            via_pseudonym: String::from("octopus"),
+            forwarded_headers: true,
+            trusted_proxies: Vec::new(),
+            cache_max_size: 64 * 1024 * 1024,
+            cache_max_object_size: 8 * 1024 * 1024,
+            cache_dir: None,
+            cache_disk_max_size: 1024 * 1024 * 1024,
+            admin_host: String::from("octopus.admin"),
+            admin_clients: vec![
+                Cidr::from_str("127.0.0.0/8").unwrap(),
+                Cidr::from_str("::1").unwrap(),
+            ],
+            admin_unix_clients: false,
+            error_templates: Templates::default(),
+            log_level: Level::Info,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+    use std::str::FromStr;
+
+    use super::{ListenAddress, Peer};
+
+    #[test]
+    fn test_peer_serves() {
+        let peer = Peer {
+            address: String::from("parent:3128"),
+            domains: vec![String::from("example.com")],
+        };
+
+        assert!(peer.serves("example.com"));
+        assert!(peer.serves("www.Example.com"));
+        assert!(!peer.serves("badexample.com"));
+        assert!(!peer.serves("example.org"));
+
+        let everything = Peer {
+            address: String::from("parent:3128"),
+            domains: Vec::new(),
+        };
+        assert!(everything.serves("example.org"));
+    }
+
+    #[test]
+    fn test_listen_address() {
+        match ListenAddress::from_str("[::]:3128").unwrap() {
+            ListenAddress::Tcp(address) => assert!(address.is_ipv6()),
+            address => panic!("expected a TCP address, got {}", address),
+        }
+
+        let unix = ListenAddress::from_str("unix:/run/octopus.sock").unwrap();
+        assert_eq!(unix, ListenAddress::Unix { path: PathBuf::from("/run/octopus.sock"), mode: None });
+        assert_eq!(unix.to_string(), "unix:/run/octopus.sock");
+
+        assert!(ListenAddress::from_str("unix:").is_err());
+        assert!(ListenAddress::from_str("localhost:3128").is_err());
+    }
+}
@@ -0,0 +1,130 @@
+use std::error;
+use std::fmt;
+use std::io;
+use std::result;
+
+/// Everything that can go wrong while handling a request.
+///
+/// Each kind of error corresponds to the status a client should be given for
+/// it; see `status()`.
+#[derive(Debug)]
+pub enum Error {
+    /// The client sent something that isn't valid HTTP.
+    Parse(String),
+    /// The message parsed, but its headers are invalid or contradict each
+    /// other, e.g. two different Content-Lengths.
+    Header(String),
+    /// The client's request head is bigger than we're willing to read.
+    HeadersTooLarge(String),
+    /// No connection could be made to the upstream server.
+    Connect(String),
+    /// The upstream server sent something that isn't a usable reply.
+    Upstream(String),
+    /// The upstream server took too long.
+    Timeout(String),
+    /// The client took too long to send its request.
+    ClientTimeout(String),
+    /// Reading or writing failed.
+    Io(io::Error),
+    /// The request isn't allowed, e.g. by an ACL.
+    Policy(String),
+}
+
+pub type Result<T> = result::Result<T, Error>;
+
+impl Error {
+    /// The status code and reason phrase to answer a client with.
+    pub fn status(&self) -> (u16, &'static str) {
+        match *self {
+            Error::Parse(_) | Error::Header(_) => (400, "Bad Request"),
+            Error::HeadersTooLarge(_) => (431, "Request Header Fields Too Large"),
+            Error::Connect(_) | Error::Upstream(_) | Error::Io(_) => (502, "Bad Gateway"),
+            Error::Timeout(_) => (504, "Gateway Timeout"),
+            Error::ClientTimeout(_) => (408, "Request Timeout"),
+            Error::Policy(_) => (403, "Forbidden"),
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::Parse(ref reason) => write!(f, "parse error: {}", reason),
+            Error::Header(ref reason) => write!(f, "invalid headers: {}", reason),
+            Error::HeadersTooLarge(ref reason) => write!(f, "headers too large: {}", reason),
+            Error::Connect(ref reason) => write!(f, "could not connect upstream: {}", reason),
+            Error::Upstream(ref reason) => write!(f, "invalid upstream reply: {}", reason),
+            Error::Timeout(ref reason) => write!(f, "timed out: {}", reason),
+            Error::ClientTimeout(ref reason) => write!(f, "client timed out: {}", reason),
+            Error::Io(ref e) => write!(f, "I/O error: {}", e),
+            Error::Policy(ref reason) => write!(f, "not allowed: {}", reason),
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match *self {
+            Error::Io(ref e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Error {
+        match e.kind() {
+            io::ErrorKind::TimedOut => Error::Timeout(e.to_string()),
+            _ => Error::Io(e),
+        }
+    }
+}
+
+impl From<Error> for io::Error {
+    fn from(e: Error) -> io::Error {
+        match e {
+            Error::Io(e) => e,
+            Error::Timeout(_) | Error::ClientTimeout(_) => io::Error::new(io::ErrorKind::TimedOut, e.to_string()),
+            _ => io::Error::new(io::ErrorKind::InvalidData, e.to_string()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::error;
+    use std::io;
+
+    use super::Error;
+
+    #[test]
+    fn test_status() {
+        assert_eq!(Error::Parse(String::from("x")).status().0, 400);
+        assert_eq!(Error::Header(String::from("x")).status().0, 400);
+        assert_eq!(Error::HeadersTooLarge(String::from("x")).status().0, 431);
+        assert_eq!(Error::Connect(String::from("x")).status().0, 502);
+        assert_eq!(Error::Upstream(String::from("x")).status().0, 502);
+        assert_eq!(Error::Timeout(String::from("x")).status().0, 504);
+        assert_eq!(Error::ClientTimeout(String::from("x")).status().0, 408);
+        assert_eq!(Error::Policy(String::from("x")).status().0, 403);
+    }
+
+    #[test]
+    fn test_io_conversion() {
+        let timed_out: Error = io::Error::new(io::ErrorKind::TimedOut, "slow").into();
+        assert_eq!(timed_out.status().0, 504);
+
+        let reset: Error = io::Error::new(io::ErrorKind::ConnectionReset, "reset").into();
+        match reset {
+            Error::Io(ref e) => assert_eq!(e.kind(), io::ErrorKind::ConnectionReset),
+            _ => panic!("expected an I/O error"),
+        }
+
+        let reset: Error = io::Error::new(io::ErrorKind::ConnectionReset, "reset").into();
+        assert!(error::Error::source(&reset).is_some());
+        assert!(error::Error::source(&Error::Parse(String::from("x"))).is_none());
+
+        let back: io::Error = Error::Header(String::from("x")).into();
+        assert_eq!(back.kind(), io::ErrorKind::InvalidData);
+    }
+}
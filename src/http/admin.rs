@@ -0,0 +1,236 @@
+extern crate regex;
+extern crate url;
+
+use std::io::Write;
+use std::time::SystemTime;
+
+use cache::Cache;
+use error::{Error, Result};
+
+use super::request::Request;
+
+/// Which cache entries an admin request is about, going by their URLs.
+pub enum Filter {
+    All,
+    Url(String),
+    Prefix(String),
+    Regex(regex::Regex),
+}
+
+impl Filter {
+    /// Read the filter from the `url`, `prefix` or `regex` parameter in the
+    /// query string of `url`. No parameter means everything.
+    pub fn from_query(url: &url::Url) -> Result<Filter> {
+        let mut filter = Filter::All;
+
+        for (name, value) in url.query_pairs() {
+            let parsed = match &*name {
+                "url" => {
+                    match url::Url::parse(&value) {
+                        Ok(url) => Filter::Url(String::from(&url[..url::Position::AfterQuery])),
+                        Err(e) => return Err(Error::Parse(format!("Invalid URL {}: {}", value, e))),
+                    }
+                },
+                "prefix" => Filter::Prefix(value.into_owned()),
+                "regex" => {
+                    match regex::Regex::new(&value) {
+                        Ok(regex) => Filter::Regex(regex),
+                        Err(e) => return Err(Error::Parse(format!("Invalid regex {}: {}", value, e))),
+                    }
+                },
+                _ => return Err(Error::Parse(format!("Unknown parameter {}", name))),
+            };
+
+            if let Filter::All = filter {
+                filter = parsed;
+            } else {
+                return Err(Error::Parse(String::from("Only one of url, prefix or regex can be given")));
+            }
+        }
+
+        Ok(filter)
+    }
+
+    pub fn matches(&self, url: &str) -> bool {
+        match *self {
+            Filter::All => true,
+            Filter::Url(ref exact) => url == exact,
+            Filter::Prefix(ref prefix) => url.starts_with(prefix),
+            Filter::Regex(ref regex) => regex.is_match(url),
+        }
+    }
+}
+
+/// Answer a request to the admin API.
+///
+/// `GET /cache` lists cache entries, and `POST` or `DELETE /cache` purges
+/// them. Either takes a `url`, `prefix` or `regex` parameter to pick
+/// entries by URL.
+///
+/// Returns whether the client connection can be used for another request.
+pub fn handle<W: Write>(stream: &mut W, request: &Request, cache: &Cache) -> Result<bool> {
+    if request.url.path() != "/cache" {
+        return respond(stream, request, "404 Not Found", "Not found\n");
+    }
+
+    let filter = match Filter::from_query(&request.url) {
+        Ok(filter) => filter,
+        Err(e) => return respond(stream, request, "400 Bad Request", &format!("{}\n", e)),
+    };
+
+    match &*request.method {
+        "GET" | "HEAD" => {
+            let mut listing = String::new();
+
+            for summary in cache.summaries(SystemTime::now()) {
+                if !filter.matches(summary.key.url()) {
+                    continue;
+                }
+
+                listing.push_str(&format!("{} {} age={} lifetime={} size={} hits={} {}\n",
+                                          if summary.is_fresh() { "fresh" } else { "stale" },
+                                          summary.code,
+                                          summary.age.as_secs(),
+                                          summary.freshness_lifetime.as_secs(),
+                                          summary.size,
+                                          summary.hits,
+                                          summary.key));
+            }
+
+            respond(stream, request, "200 OK", &listing)
+        },
+        "POST" | "DELETE" => {
+            if let Filter::All = filter {
+                return respond(stream, request, "400 Bad Request", "Say what to purge with url, prefix or regex\n");
+            }
+
+            let purged = cache.purge(|key| filter.matches(key.url()));
+            info!("Purged {} cache entries for {}", purged, request.url);
+
+            respond(stream, request, "200 OK", &format!("Purged {} entries\n", purged))
+        },
+        _ => respond(stream, request, "405 Method Not Allowed", "Use GET, POST or DELETE\n"),
+    }
+}
+
+/// Answer a PURGE request by removing everything cached for its URL.
+///
+/// Returns whether the client connection can be used for another request.
+pub fn purge<W: Write>(stream: &mut W, request: &Request, cache: &Cache) -> Result<bool> {
+    let url = &request.url[..url::Position::AfterQuery];
+    let purged = cache.purge(|key| key.url() == url);
+
+    if purged > 0 {
+        info!("Purged {} cache entries for {}", purged, url);
+        respond(stream, request, "200 OK", &format!("Purged {} entries\n", purged))
+    } else {
+        respond(stream, request, "404 Not Found", "Not cached\n")
+    }
+}
+
+fn respond<W: Write>(stream: &mut W, request: &Request, status: &str, body: &str) -> Result<bool> {
+    let keep_alive = request.keep_alive();
+
+    let mut head = format!("HTTP/1.1 {}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nCache-Control: no-store\r\n", status, body.len());
+    if !keep_alive {
+        head.push_str("Connection: close\r\n");
+    } else if request.version == 0 {
+        head.push_str("Connection: keep-alive\r\n");
+    }
+    head.push_str("\r\n");
+
+    try!(stream.write_all(head.as_bytes()));
+    if request.method != "HEAD" {
+        try!(stream.write_all(body.as_bytes()));
+    }
+
+    Ok(keep_alive)
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate url;
+
+    use std::str;
+    use std::time::SystemTime;
+
+    use cache::{Cache, Entry};
+    use config::Config;
+    use http::test_support::{reply, request};
+
+    use super::{handle, purge, Filter};
+
+    fn cache_with(urls: &[&str]) -> Cache {
+        let cache = Cache::new(&Config::default()).unwrap();
+
+        for url in urls {
+            let req = request(&format!("GET {} HTTP/1.1\r\n\r\n", url));
+            let rep = reply("HTTP/1.1 200 OK\r\nCache-Control: max-age=60\r\n\r\n");
+            cache.store(&req, Entry::new(rep, b"Hello".to_vec(), SystemTime::now(), SystemTime::now()));
+        }
+
+        cache
+    }
+
+    fn admin(cache: &Cache, raw: &str) -> String {
+        let mut out = Vec::new();
+        handle(&mut out, &request(raw), cache).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn test_filter() {
+        let filter = |query: &str| Filter::from_query(&url::Url::parse(&format!("http://octopus.admin/cache?{}", query)).unwrap());
+
+        assert!(filter("").unwrap().matches("http://example.com/"));
+        assert!(filter("url=http://example.com").unwrap().matches("http://example.com/"));
+        assert!(!filter("url=http://example.com/").unwrap().matches("http://example.com/a"));
+        assert!(filter("prefix=http://example.com/a").unwrap().matches("http://example.com/abc"));
+        assert!(filter("regex=%5C.png$").unwrap().matches("http://example.com/a.png"));
+        assert!(!filter("regex=%5C.png$").unwrap().matches("http://example.com/a.jpg"));
+
+        assert!(filter("regex=(").is_err());
+        assert!(filter("url=nope").is_err());
+        assert!(filter("url=http://a/&prefix=http://b/").is_err());
+        assert!(filter("colour=blue").is_err());
+    }
+
+    #[test]
+    fn test_list() {
+        let cache = cache_with(&["http://example.com/a", "http://example.com/b"]);
+
+        let out = admin(&cache, "GET /cache HTTP/1.1\r\nHost: octopus.admin\r\n\r\n");
+        assert!(out.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(out.contains("fresh 200 age=0 lifetime=60 size="));
+        assert!(out.contains(" hits=0 GET http://example.com/a\n"));
+        assert!(out.contains("GET http://example.com/b\n"));
+
+        let out = admin(&cache, "GET /cache?prefix=http://example.com/b HTTP/1.1\r\nHost: octopus.admin\r\n\r\n");
+        assert!(!out.contains("http://example.com/a"));
+        assert!(out.contains("http://example.com/b"));
+
+        let out = admin(&cache, "GET /other HTTP/1.1\r\nHost: octopus.admin\r\n\r\n");
+        assert!(out.starts_with("HTTP/1.1 404 Not Found\r\n"));
+    }
+
+    #[test]
+    fn test_purge() {
+        let cache = cache_with(&["http://example.com/a.png", "http://example.com/b.png", "http://example.com/c.css"]);
+
+        let out = admin(&cache, "POST /cache HTTP/1.1\r\nHost: octopus.admin\r\nContent-Length: 0\r\n\r\n");
+        assert!(out.starts_with("HTTP/1.1 400 Bad Request\r\n"));
+
+        let out = admin(&cache, "POST /cache?regex=%5C.png$ HTTP/1.1\r\nHost: octopus.admin\r\nContent-Length: 0\r\n\r\n");
+        assert!(out.ends_with("Purged 2 entries\n"));
+        assert_eq!(cache.summaries(SystemTime::now()).len(), 1);
+
+        let mut out = Vec::new();
+        purge(&mut out, &request("PURGE http://example.com/c.css HTTP/1.1\r\n\r\n"), &cache).unwrap();
+        assert!(str::from_utf8(&out).unwrap().starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(cache.summaries(SystemTime::now()).is_empty());
+
+        let mut out = Vec::new();
+        purge(&mut out, &request("PURGE http://example.com/c.css HTTP/1.1\r\n\r\n"), &cache).unwrap();
+        assert!(str::from_utf8(&out).unwrap().starts_with("HTTP/1.1 404 Not Found\r\n"));
+    }
+}
@@ -0,0 +1,294 @@
+use std::io::{self, BufRead, Read, Write};
+use std::str;
+
+use error::{Error, Result};
+
+use super::chunked::{ChunkedReader, ChunkedWriter};
+use super::headers::Headers;
+use super::reply::Reply;
+use super::request::Request;
+
+/// How the end of a message body is determined, as per RFC 7230 section 3.3.3.
+#[derive(Debug, PartialEq)]
+pub enum Framing {
+    /// There is no body at all, regardless of what the headers say.
+    Empty,
+    /// The body is exactly this many bytes long.
+    Length(usize),
+    /// The body uses the chunked transfer-coding.
+    Chunked,
+    /// The body runs until the connection is closed.
+    Close,
+}
+
+impl Framing {
+    /// Determine the framing of a request body.
+    ///
+    /// Unlike replies, a request can't be delimited by closing the connection,
+    /// so a transfer-coding other than chunked is an error.
+    pub fn for_request(request: &Request) -> Result<Framing> {
+        if let Some(_) = request.headers.get("transfer-encoding") {
+            if is_chunked(&request.headers) {
+                return Ok(Framing::Chunked);
+            }
+
+            return Err(Error::Header(String::from("Unsupported Transfer-Encoding in request")));
+        }
+
+        match try!(request.headers.content_length()) {
+            Some(0) | None => Ok(Framing::Empty),
+            Some(n) => Ok(Framing::Length(n)),
+        }
+    }
+
+    /// Determine the framing of a reply to a request made with `method`.
+    ///
+    /// 101 Switching Protocols is an error: Upgrade is stripped from requests
+    /// as hop-by-hop, and whatever follows wouldn't be HTTP anyway.
+    pub fn for_reply(reply: &Reply, method: &str) -> Result<Framing> {
+        if reply.code == 101 {
+            return Err(Error::Upstream(String::from("Switching Protocols without being asked to upgrade")));
+        }
+
+        if method == "HEAD" || reply.code == 204 || reply.code == 304 || (reply.code >= 100 && reply.code < 200) {
+            return Ok(Framing::Empty);
+        }
+
+        if let Some(_) = reply.headers.get("transfer-encoding") {
+            if is_chunked(&reply.headers) {
+                return Ok(Framing::Chunked);
+            }
+
+            return Ok(Framing::Close);
+        }
+
+        match try!(reply.headers.content_length()) {
+            Some(n) => Ok(Framing::Length(n)),
+            None => Ok(Framing::Close),
+        }
+    }
+}
+
+/// Whether chunked is the final transfer-coding applied to the message.
+fn is_chunked(headers: &Headers) -> bool {
+    match headers.get("transfer-encoding") {
+        Some(value) => {
+            match str::from_utf8(value) {
+                Ok(value) => {
+                    match value.rsplit(',').next() {
+                        Some(last) => last.trim().eq_ignore_ascii_case("chunked"),
+                        None => false,
+                    }
+                },
+                Err(_) => false,
+            }
+        },
+        None => false,
+    }
+}
+
+/// Copy a single message body from `source` to `sink`, stopping at the end of
+/// the body as described by `framing`.
+///
+/// Chunked bodies are decoded and encoded again rather than copied verbatim,
+/// so that malformed chunking is caught here. Chunk extensions are dropped,
+/// but trailers are passed along.
+///
+/// Returns the number of payload bytes copied.
+pub fn relay<R: BufRead, W: Write>(source: &mut R, sink: &mut W, framing: &Framing) -> io::Result<u64> {
+    relay_and_copy(source, sink, framing, false, &mut io::sink())
+}
+
+/// Like `relay`, but strips the chunked transfer-coding rather than passing
+/// it on, for recipients that don't understand it.
+pub fn relay_decoded<R: BufRead, W: Write>(source: &mut R, sink: &mut W, framing: &Framing) -> io::Result<u64> {
+    relay_and_copy(source, sink, framing, true, &mut io::sink())
+}
+
+/// Like `relay`, or `relay_decoded` if `dechunk` is set, but also write the
+/// payload to `copy` as it goes past, without any transfer-coding.
+pub fn relay_and_copy<R: BufRead, W: Write, C: Write>(source: &mut R, sink: &mut W, framing: &Framing, dechunk: bool, copy: &mut C) -> io::Result<u64> {
+    match *framing {
+        Framing::Empty => Ok(0),
+        Framing::Length(n) => {
+            let copied = try!(io::copy(&mut source.take(n as u64), &mut Tee::new(sink, copy)));
+            if copied < n as u64 {
+                return Err(io::Error::new(io::ErrorKind::UnexpectedEof,
+                                          format!("body ended after {} of {} bytes", copied, n)));
+            }
+            Ok(copied)
+        },
+        Framing::Chunked if dechunk => {
+            let mut decoder = ChunkedReader::new(source);
+            io::copy(&mut decoder, &mut Tee::new(sink, copy))
+        },
+        Framing::Chunked => {
+            let mut decoder = ChunkedReader::new(source);
+            let mut encoder = Tee::new(ChunkedWriter::new(sink), copy);
+
+            let copied = try!(io::copy(&mut decoder, &mut encoder));
+            try!(encoder.into_inner().finish(decoder.trailers().cloned()));
+            Ok(copied)
+        },
+        Framing::Close => io::copy(source, &mut Tee::new(sink, copy)),
+    }
+}
+
+/// Writes everything to both `inner` and `copy`.
+struct Tee<W, C> {
+    inner: W,
+    copy: C,
+}
+
+impl<W: Write, C: Write> Tee<W, C> {
+    fn new(inner: W, copy: C) -> Tee<W, C> {
+        Tee {
+            inner: inner,
+            copy: copy,
+        }
+    }
+
+    fn into_inner(self) -> W {
+        self.inner
+    }
+}
+
+impl<W: Write, C: Write> Write for Tee<W, C> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = try!(self.inner.write(buf));
+        try!(self.copy.write_all(&buf[..n]));
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        try!(self.inner.flush());
+        self.copy.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Cursor, Read};
+
+    use error::Result;
+
+    use super::{relay, relay_and_copy, relay_decoded, Framing};
+    use super::super::test_support::{reply, request};
+
+    fn framing_for(raw: &[u8], method: &str) -> Framing {
+        Framing::for_reply(&reply(raw), method).unwrap()
+    }
+
+    #[test]
+    fn test_framing_for_reply() {
+        assert_eq!(framing_for(b"HTTP/1.1 200 OK\r\nContent-Length: 10\r\n\r\n", "GET"), Framing::Length(10));
+        assert_eq!(framing_for(b"HTTP/1.1 200 OK\r\nContent-Length: 10\r\n\r\n", "HEAD"), Framing::Empty);
+        assert_eq!(framing_for(b"HTTP/1.1 304 Not Modified\r\nContent-Length: 10\r\n\r\n", "GET"), Framing::Empty);
+        assert_eq!(framing_for(b"HTTP/1.1 200 OK\r\nTransfer-Encoding: gzip, chunked\r\nContent-Length: 10\r\n\r\n", "GET"), Framing::Chunked);
+        assert_eq!(framing_for(b"HTTP/1.1 200 OK\r\nTransfer-Encoding: gzip\r\n\r\n", "GET"), Framing::Close);
+        assert_eq!(framing_for(b"HTTP/1.0 200 OK\r\n\r\n", "GET"), Framing::Close);
+
+        let invalid = reply(b"HTTP/1.1 200 OK\r\nContent-Length: lots\r\n\r\n");
+        assert!(Framing::for_reply(&invalid, "GET").is_err());
+        assert_eq!(Framing::for_reply(&invalid, "HEAD").unwrap(), Framing::Empty);
+
+        let switching = reply(b"HTTP/1.1 101 Switching Protocols\r\nConnection: upgrade\r\nUpgrade: websocket\r\n\r\n");
+        assert!(Framing::for_reply(&switching, "GET").is_err());
+        assert_eq!(framing_for(b"HTTP/1.1 100 Continue\r\n\r\n", "GET"), Framing::Empty);
+    }
+
+    fn request_framing_for(raw: &[u8]) -> Result<Framing> {
+        Framing::for_request(&request(raw))
+    }
+
+    #[test]
+    fn test_framing_for_request() {
+        assert_eq!(request_framing_for(b"GET / HTTP/1.1\r\nHost: foo\r\n\r\n").unwrap(), Framing::Empty);
+        assert_eq!(request_framing_for(b"POST / HTTP/1.1\r\nHost: foo\r\nContent-Length: 3\r\n\r\n").unwrap(), Framing::Length(3));
+        assert_eq!(request_framing_for(b"POST / HTTP/1.1\r\nHost: foo\r\nTransfer-Encoding: chunked\r\nContent-Length: 3\r\n\r\n").unwrap(), Framing::Chunked);
+        assert!(request_framing_for(b"POST / HTTP/1.1\r\nHost: foo\r\nTransfer-Encoding: gzip\r\n\r\n").is_err());
+        assert!(request_framing_for(b"POST / HTTP/1.1\r\nHost: foo\r\nContent-Length: -3\r\n\r\n").is_err());
+    }
+
+    #[test]
+    fn test_relay_length() {
+        let mut source = Cursor::new(b"Hello world".to_vec());
+        let mut sink = Vec::new();
+
+        assert_eq!(relay(&mut source, &mut sink, &Framing::Length(5)).unwrap(), 5);
+        assert_eq!(sink, b"Hello");
+
+        let mut source = Cursor::new(b"Hi".to_vec());
+        assert!(relay(&mut source, &mut sink, &Framing::Length(5)).is_err());
+    }
+
+    #[test]
+    fn test_relay_length_streams() {
+        // Much bigger than any buffer used along the way, and split between
+        // what was already read and what is still on the wire.
+        let size = 1024 * 1024;
+        let partial = vec![b'a'; 100];
+        let mut rest = vec![b'b'; size - partial.len()];
+        rest.extend(b"GET / HTTP/1.1\r\n");
+
+        let mut wire = Cursor::new(rest);
+        let mut sink = Vec::new();
+
+        {
+            let mut source = Cursor::new(partial).chain(&mut wire);
+            assert_eq!(relay(&mut source, &mut sink, &Framing::Length(size)).unwrap(), size as u64);
+        }
+
+        assert_eq!(sink.len(), size);
+        assert_eq!(wire.position() as usize, size - 100);
+    }
+
+    #[test]
+    fn test_relay_chunked() {
+        let mut source = Cursor::new(b"5;name=value\r\nHello\r\n6\r\n world\r\n0\r\nExpires: never\r\n\r\n".to_vec());
+        let mut sink = Vec::new();
+
+        assert_eq!(relay(&mut source, &mut sink, &Framing::Chunked).unwrap(), 11);
+        assert_eq!(&sink[..], &b"5\r\nHello\r\n6\r\n world\r\n0\r\nExpires: never\r\n\r\n"[..]);
+    }
+
+    #[test]
+    fn test_relay_decoded() {
+        let mut source = Cursor::new(b"5\r\nHello\r\n6\r\n world\r\n0\r\n\r\nGET".to_vec());
+        let mut sink = Vec::new();
+
+        assert_eq!(relay_decoded(&mut source, &mut sink, &Framing::Chunked).unwrap(), 11);
+        assert_eq!(sink, b"Hello world");
+        assert_eq!(source.position(), 26);
+    }
+
+    #[test]
+    fn test_relay_chunked_truncated() {
+        let mut source = Cursor::new(b"5\r\nHel".to_vec());
+        let mut sink = Vec::new();
+
+        assert!(relay(&mut source, &mut sink, &Framing::Chunked).is_err());
+
+        let mut source = Cursor::new(b"zz\r\nHello\r\n".to_vec());
+        assert!(relay(&mut source, &mut sink, &Framing::Chunked).is_err());
+    }
+
+    #[test]
+    fn test_relay_and_copy() {
+        let mut source = Cursor::new(b"5\r\nHello\r\n6\r\n world\r\n0\r\n\r\n".to_vec());
+        let mut sink = Vec::new();
+        let mut copy = Vec::new();
+
+        assert_eq!(relay_and_copy(&mut source, &mut sink, &Framing::Chunked, false, &mut copy).unwrap(), 11);
+        assert_eq!(&sink[..], &b"5\r\nHello\r\n6\r\n world\r\n0\r\n\r\n"[..]);
+        assert_eq!(copy, b"Hello world");
+
+        let mut source = Cursor::new(b"Hello world".to_vec());
+        let mut sink = Vec::new();
+        let mut copy = Vec::new();
+
+        assert_eq!(relay_and_copy(&mut source, &mut sink, &Framing::Close, false, &mut copy).unwrap(), 11);
+        assert_eq!(sink, b"Hello world");
+        assert_eq!(copy, b"Hello world");
+    }
+}
@@ -0,0 +1,153 @@
+use std::cmp;
+use std::io::{self, BufRead, Read, Write};
+
+use super::read_into_buffer;
+
+const BUFFER_SIZE: usize = 65536;
+
+/// Wraps a stream, keeping hold of anything read from it that hasn't been
+/// consumed yet.
+///
+/// This lets a connection be handed from one message to the next without
+/// losing bytes that were read past the end of the previous one, e.g. a
+/// pipelined request that arrived in the same packet.
+pub struct Buffered<S> {
+    inner: S,
+    buffer: Vec<u8>,
+    pos: usize,
+}
+
+impl<S> Buffered<S> {
+    pub fn new(inner: S) -> Buffered<S> {
+        Buffered {
+            inner: inner,
+            buffer: Vec::new(),
+            pos: 0,
+        }
+    }
+
+    /// Put `data` back in front of anything still buffered, so that it's the
+    /// next thing read.
+    pub fn unread(&mut self, data: &[u8]) {
+        if data.is_empty() {
+            return;
+        }
+
+        let mut buffer = Vec::with_capacity(data.len() + self.buffer.len() - self.pos);
+        buffer.extend(data);
+        buffer.extend(&self.buffer[self.pos..]);
+
+        self.buffer = buffer;
+        self.pos = 0;
+    }
+
+    /// Data that has been read from the stream but not yet consumed.
+    pub fn buffered(&self) -> &[u8] {
+        &self.buffer[self.pos..]
+    }
+
+    /// Remove and return everything currently buffered.
+    pub fn take_buffered(&mut self) -> Vec<u8> {
+        let buffered = self.buffer.split_off(self.pos);
+        self.buffer.truncate(0);
+        self.pos = 0;
+        buffered
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.inner
+    }
+
+    pub fn get_mut(&mut self) -> &mut S {
+        &mut self.inner
+    }
+
+    /// Unwrap the stream. Anything still buffered is lost.
+    pub fn into_inner(self) -> S {
+        self.inner
+    }
+}
+
+impl<S: Read> Read for Buffered<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        // Don't bother copying through our own buffer for large reads.
+        if self.pos == self.buffer.len() && buf.len() >= BUFFER_SIZE {
+            return self.inner.read(buf);
+        }
+
+        let n = {
+            let available = try!(self.fill_buf());
+            let n = cmp::min(available.len(), buf.len());
+            buf[..n].copy_from_slice(&available[..n]);
+            n
+        };
+
+        self.consume(n);
+        Ok(n)
+    }
+}
+
+impl<S: Read> BufRead for Buffered<S> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        if self.pos == self.buffer.len() {
+            self.buffer.truncate(0);
+            self.pos = 0;
+            try!(read_into_buffer(&mut self.inner, &mut self.buffer));
+        }
+
+        Ok(&self.buffer[self.pos..])
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.pos = cmp::min(self.pos + amt, self.buffer.len());
+    }
+}
+
+impl<S: Write> Write for Buffered<S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.inner.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{BufRead, Cursor, Read};
+
+    use super::Buffered;
+
+    #[test]
+    fn test_unread() {
+        let mut stream = Buffered::new(Cursor::new(b"world".to_vec()));
+        let mut buf = [0; 3];
+
+        stream.unread(b"Hello ");
+        assert_eq!(stream.buffered(), b"Hello ");
+
+        assert_eq!(stream.read(&mut buf).unwrap(), 3);
+        assert_eq!(&buf, b"Hel");
+
+        stream.unread(b"J");
+        let mut rest = String::new();
+        stream.read_to_string(&mut rest).unwrap();
+        assert_eq!(rest, "Jlo world");
+    }
+
+    #[test]
+    fn test_buf_read() {
+        let mut stream = Buffered::new(Cursor::new(b"line one\nline two\n".to_vec()));
+        let mut line = String::new();
+
+        stream.read_line(&mut line).unwrap();
+        assert_eq!(line, "line one\n");
+
+        // The rest was read from the stream, but is still available.
+        assert_eq!(stream.buffered(), b"line two\n");
+        assert_eq!(stream.take_buffered(), b"line two\n");
+        assert_eq!(stream.buffered(), b"");
+        assert_eq!(stream.read(&mut [0; 10]).unwrap(), 0);
+    }
+}
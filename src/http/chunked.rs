@@ -0,0 +1,419 @@
+extern crate httparse;
+
+use std::cmp;
+use std::io::{self, BufRead, Read, Write};
+use std::str;
+
+use super::headers::Headers;
+
+// Upper bound on a chunk-size line including its extensions, so a client can't
+// make us buffer an endless line.
+const MAX_CHUNK_LINE: u64 = 4096;
+
+// Upper bound on the combined size of the trailer section.
+const MAX_TRAILER_BYTES: usize = 65536;
+const MAX_TRAILERS: usize = 64;
+
+#[derive(Debug, PartialEq)]
+enum State {
+    // Expecting a chunk-size line.
+    Size,
+    // Part way through the data of a chunk, with this many bytes remaining.
+    Data(u64),
+    // Expecting the CRLF that terminates a chunk's data.
+    DataEnd,
+    // Expecting the trailer section after the last chunk.
+    Trailers,
+    Done,
+}
+
+/// A chunk extension, i.e. `name` or `name=value` after a chunk size.
+pub type Extension = (String, Option<String>);
+
+/// Decodes a body with the chunked transfer-coding from `inner`, yielding only
+/// the payload.
+///
+/// Reading stops at the end of the body, so nothing past it is consumed from
+/// `inner` beyond what its own buffering reads ahead.
+pub struct ChunkedReader<R> {
+    inner: R,
+    state: State,
+    extensions: Vec<Extension>,
+    trailers: Option<Headers>,
+}
+
+impl<R: BufRead> ChunkedReader<R> {
+    pub fn new(inner: R) -> ChunkedReader<R> {
+        ChunkedReader {
+            inner: inner,
+            state: State::Size,
+            extensions: Vec::new(),
+            trailers: None,
+        }
+    }
+
+    /// Extensions given for the chunk most recently started.
+    pub fn extensions(&self) -> &[Extension] {
+        &self.extensions
+    }
+
+    /// The trailer section, which is only available once the whole body has
+    /// been read.
+    pub fn trailers(&self) -> Option<&Headers> {
+        self.trailers.as_ref()
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.state == State::Done
+    }
+
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+
+    fn read_line(&mut self, line: &mut Vec<u8>) -> io::Result<()> {
+        line.truncate(0);
+
+        match (&mut self.inner).take(MAX_CHUNK_LINE).read_until(b'\n', line) {
+            Ok(0) => Err(io::Error::new(io::ErrorKind::UnexpectedEof, "chunked body ended early")),
+            Ok(_) if line.last() != Some(&b'\n') => {
+                if line.len() as u64 == MAX_CHUNK_LINE {
+                    Err(io::Error::new(io::ErrorKind::InvalidData, "chunk line too long"))
+                } else {
+                    Err(io::Error::new(io::ErrorKind::UnexpectedEof, "chunked body ended early"))
+                }
+            },
+            Ok(_) => Ok(()),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn read_trailers(&mut self) -> io::Result<Headers> {
+        let mut raw = Vec::new();
+        let mut line = Vec::new();
+
+        loop {
+            try!(self.read_line(&mut line));
+            raw.extend(&line);
+
+            if line == b"\r\n" || line == b"\n" {
+                break;
+            }
+
+            if raw.len() > MAX_TRAILER_BYTES {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "trailers too large"));
+            }
+        }
+
+        let mut headers = [httparse::EMPTY_HEADER; MAX_TRAILERS];
+        match httparse::parse_headers(&raw, &mut headers) {
+            Ok(httparse::Status::Complete((_, parsed))) => Headers::from_raw(parsed).map_err(io::Error::from),
+            Ok(httparse::Status::Partial) => {
+                Err(io::Error::new(io::ErrorKind::InvalidData, "incomplete trailers"))
+            },
+            Err(e) => {
+                Err(io::Error::new(io::ErrorKind::InvalidData, format!("invalid trailers: {:?}", e)))
+            }
+        }
+    }
+}
+
+impl<R: BufRead> Read for ChunkedReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut line = Vec::new();
+
+        loop {
+            match self.state {
+                State::Size => {
+                    try!(self.read_line(&mut line));
+                    let (size, extensions) = try!(parse_chunk_line(&line));
+
+                    self.extensions = extensions;
+                    self.state = if size == 0 {
+                        State::Trailers
+                    } else {
+                        State::Data(size)
+                    };
+                },
+                State::Data(remaining) => {
+                    if buf.is_empty() {
+                        return Ok(0);
+                    }
+
+                    let max = cmp::min(buf.len() as u64, remaining) as usize;
+                    let n = try!(self.inner.read(&mut buf[..max]));
+
+                    if n == 0 {
+                        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "chunked body ended early"));
+                    }
+
+                    let remaining = remaining - n as u64;
+                    self.state = if remaining == 0 {
+                        State::DataEnd
+                    } else {
+                        State::Data(remaining)
+                    };
+
+                    return Ok(n);
+                },
+                State::DataEnd => {
+                    try!(self.read_line(&mut line));
+
+                    if line != b"\r\n" && line != b"\n" {
+                        return Err(io::Error::new(io::ErrorKind::InvalidData, "chunk data too long"));
+                    }
+
+                    self.state = State::Size;
+                },
+                State::Trailers => {
+                    self.trailers = Some(try!(self.read_trailers()));
+                    self.state = State::Done;
+                },
+                State::Done => {
+                    return Ok(0);
+                }
+            }
+        }
+    }
+}
+
+/// Encodes everything written to it with the chunked transfer-coding.
+///
+/// `finish` must be called to terminate the body.
+pub struct ChunkedWriter<W> {
+    inner: W,
+}
+
+impl<W: Write> ChunkedWriter<W> {
+    pub fn new(inner: W) -> ChunkedWriter<W> {
+        ChunkedWriter {
+            inner: inner,
+        }
+    }
+
+    /// Write the last chunk and the given trailers, ending the body.
+    pub fn finish(mut self, trailers: Option<Headers>) -> io::Result<W> {
+        try!(self.inner.write_all(b"0\r\n"));
+
+        match trailers {
+            Some(trailers) => {
+                let trailers: Vec<u8> = trailers.into();
+                try!(self.inner.write_all(&trailers));
+            },
+            None => {
+                try!(self.inner.write_all(b"\r\n"));
+            }
+        }
+
+        try!(self.inner.flush());
+        Ok(self.inner)
+    }
+}
+
+impl<W: Write> Write for ChunkedWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        // An empty chunk would terminate the body, so never write one here.
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        let size = format!("{:x}\r\n", buf.len());
+        try!(self.inner.write_all(size.as_bytes()));
+        try!(self.inner.write_all(buf));
+        try!(self.inner.write_all(b"\r\n"));
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+fn is_tchar(c: u8) -> bool {
+    match c {
+        b'!' | b'#' | b'$' | b'%' | b'&' | b'\'' | b'*' | b'+' | b'-' | b'.' |
+        b'^' | b'_' | b'`' | b'|' | b'~' => true,
+        _ => (c as char).is_ascii_alphanumeric(),
+    }
+}
+
+/// Parse a chunk-size line, e.g. `1a;name="value"\r\n`, into the chunk size
+/// and its extensions.
+fn parse_chunk_line(line: &[u8]) -> io::Result<(u64, Vec<Extension>)> {
+    let invalid = || io::Error::new(io::ErrorKind::InvalidData, "invalid chunk size line");
+
+    let line = match str::from_utf8(line) {
+        Ok(line) => line.trim_end_matches(|c| c == '\r' || c == '\n'),
+        Err(_) => return Err(invalid()),
+    };
+
+    let (size, mut rest) = match line.find(';') {
+        Some(i) => (&line[..i], &line[i..]),
+        None => (line, ""),
+    };
+
+    let size = size.trim_end_matches(|c| c == ' ' || c == '\t');
+    if size.is_empty() || size.len() > 16 || !size.bytes().all(|c| (c as char).is_digit(16)) {
+        return Err(invalid());
+    }
+
+    let size = match u64::from_str_radix(size, 16) {
+        Ok(size) => size,
+        Err(_) => return Err(invalid()),
+    };
+
+    let mut extensions = Vec::new();
+
+    loop {
+        rest = rest.trim_start_matches(|c| c == ' ' || c == '\t');
+        if rest.is_empty() {
+            break;
+        }
+
+        if !rest.starts_with(';') {
+            return Err(invalid());
+        }
+        rest = rest[1..].trim_start_matches(|c| c == ' ' || c == '\t');
+
+        let name_len = rest.bytes().take_while(|c| is_tchar(*c)).count();
+        if name_len == 0 {
+            return Err(invalid());
+        }
+        let name = String::from(&rest[..name_len]);
+        rest = rest[name_len..].trim_start_matches(|c| c == ' ' || c == '\t');
+
+        if !rest.starts_with('=') {
+            extensions.push((name, None));
+            continue;
+        }
+        rest = rest[1..].trim_start_matches(|c| c == ' ' || c == '\t');
+
+        if rest.starts_with('"') {
+            let mut value = String::new();
+            let mut escaped = false;
+            let mut end = None;
+
+            for (i, c) in rest.char_indices().skip(1) {
+                if escaped {
+                    value.push(c);
+                    escaped = false;
+                } else if c == '\\' {
+                    escaped = true;
+                } else if c == '"' {
+                    end = Some(i);
+                    break;
+                } else {
+                    value.push(c);
+                }
+            }
+
+            match end {
+                Some(i) => rest = &rest[i + 1..],
+                None => return Err(invalid()),
+            }
+
+            extensions.push((name, Some(value)));
+        } else {
+            let value_len = rest.bytes().take_while(|c| is_tchar(*c)).count();
+            if value_len == 0 {
+                return Err(invalid());
+            }
+
+            extensions.push((name, Some(String::from(&rest[..value_len]))));
+            rest = &rest[value_len..];
+        }
+    }
+
+    Ok((size, extensions))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Cursor, Read, Write};
+
+    use super::{parse_chunk_line, ChunkedReader, ChunkedWriter};
+    use super::super::headers::Headers;
+
+    #[test]
+    fn test_parse_chunk_line() {
+        assert_eq!(parse_chunk_line(b"1a\r\n").unwrap(), (26, vec![]));
+        assert_eq!(parse_chunk_line(b"0\r\n").unwrap(), (0, vec![]));
+
+        let (size, extensions) = parse_chunk_line(b"5 ; foo ; bar=baz;quux=\"a \\\"b\\\"; c\"\r\n").unwrap();
+        assert_eq!(size, 5);
+        assert_eq!(extensions, vec![
+            (String::from("foo"), None),
+            (String::from("bar"), Some(String::from("baz"))),
+            (String::from("quux"), Some(String::from("a \"b\"; c"))),
+        ]);
+
+        assert!(parse_chunk_line(b"\r\n").is_err());
+        assert!(parse_chunk_line(b"zz\r\n").is_err());
+        assert!(parse_chunk_line(b"-1\r\n").is_err());
+        assert!(parse_chunk_line(b"11111111111111111\r\n").is_err());
+        assert!(parse_chunk_line(b"5;\r\n").is_err());
+        assert!(parse_chunk_line(b"5;foo=\"bar\r\n").is_err());
+    }
+
+    #[test]
+    fn test_read() {
+        let raw = b"5;name=value\r\nHello\r\n6\r\n world\r\n0\r\nExpires: never\r\n\r\nGET / HTTP/1.1\r\n".to_vec();
+        let mut reader = ChunkedReader::new(Cursor::new(raw));
+        let mut body = Vec::new();
+
+        reader.read_to_end(&mut body).unwrap();
+        assert_eq!(body, b"Hello world");
+        assert!(reader.is_done());
+        assert_eq!(reader.trailers().unwrap().get("Expires").unwrap(), b"never");
+
+        // Nothing past the end of the body is consumed.
+        let mut rest = Vec::new();
+        reader.into_inner().read_to_end(&mut rest).unwrap();
+        assert_eq!(rest, b"GET / HTTP/1.1\r\n");
+    }
+
+    #[test]
+    fn test_read_errors() {
+        let mut body = Vec::new();
+
+        let mut reader = ChunkedReader::new(Cursor::new(b"5\r\nHel".to_vec()));
+        assert!(reader.read_to_end(&mut body).is_err());
+
+        let mut reader = ChunkedReader::new(Cursor::new(b"2\r\nHello\r\n0\r\n\r\n".to_vec()));
+        assert!(reader.read_to_end(&mut body).is_err());
+
+        let mut reader = ChunkedReader::new(Cursor::new(b"0\r\n".to_vec()));
+        assert!(reader.read_to_end(&mut body).is_err());
+    }
+
+    #[test]
+    fn test_write() {
+        let mut writer = ChunkedWriter::new(Vec::new());
+        writer.write_all(b"Hello").unwrap();
+        writer.write_all(b"").unwrap();
+        writer.write_all(b" world!!!!!!").unwrap();
+
+        let out = writer.finish(None).unwrap();
+        assert_eq!(&out[..], &b"5\r\nHello\r\nc\r\n world!!!!!!\r\n0\r\n\r\n"[..]);
+
+        let mut trailers = Headers::new();
+        trailers.insert("Expires", &b"never".to_vec());
+
+        let writer = ChunkedWriter::new(Vec::new());
+        let out = writer.finish(Some(trailers)).unwrap();
+        assert_eq!(&out[..], &b"0\r\nExpires: never\r\n\r\n"[..]);
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let mut writer = ChunkedWriter::new(Vec::new());
+        writer.write_all(b"Hello").unwrap();
+        writer.write_all(b" world").unwrap();
+        let out = writer.finish(None).unwrap();
+
+        let mut reader = ChunkedReader::new(Cursor::new(out));
+        let mut body = Vec::new();
+        reader.read_to_end(&mut body).unwrap();
+        assert_eq!(body, b"Hello world");
+    }
+}
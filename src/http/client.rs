@@ -1,61 +1,1065 @@
+extern crate httparse;
 extern crate mioco;
 extern crate url;
 
-use std::io::{self, Write, Read};
-use std::net::ToSocketAddrs;
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::io::{self, BufRead, Write, Read};
+use std::net::{Shutdown, SocketAddr, ToSocketAddrs};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
 
+use cache::{validation, vary, Cache, Entry};
+use cache::collapse::{Collapse, Follower};
+use config::{Config, Peer};
+use error::{Error, Result};
+
+use super::body::{self, Framing};
+use super::chunked::ChunkedWriter;
+use super::range::{self, Ranged};
+use super::buffered::Buffered;
+use super::reply::{self, Reply};
 use super::request::Request;
-pub struct Client;
+use super::pages::{self, Templates};
+use super::timeout::{self, Timed};
+use super::read_into_buffer;
+
+// Upstream replies tend to carry a lot more headers than requests do.
+const MAX_REPLY_HEADERS: usize = 64;
+
+/// Something that can be checked for staleness before it is reused.
+pub trait Liveness {
+    fn is_alive(&self) -> bool;
+}
+
+impl Liveness for mioco::tcp::TcpStream {
+    fn is_alive(&self) -> bool {
+        // This only catches connections that have been reset. One that the
+        // origin closed cleanly is only noticed once it's used, which is why
+        // `Client::forward` retries requests that are safe to retry.
+        self.take_socket_error().is_ok()
+    }
+}
+
+/// Identifies upstream connections that are interchangeable with each other.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct PoolKey {
+    scheme: String,
+    host: String,
+    port: u16,
+}
+
+impl PoolKey {
+    pub fn for_url(url: &url::Url) -> PoolKey {
+        PoolKey {
+            scheme: String::from(url.scheme()),
+            host: String::from(url.host_str().unwrap_or("")),
+            port: url.port_or_known_default().unwrap_or(0),
+        }
+    }
+}
+
+/// Idle upstream connections, kept for reuse by later requests to the same
+/// origin.
+pub struct Pool<C> {
+    idle: HashMap<PoolKey, Vec<(C, Instant)>>,
+    max_idle_per_host: usize,
+    idle_timeout: Duration,
+}
+
+impl<C: Liveness> Pool<C> {
+    pub fn new(max_idle_per_host: usize, idle_timeout: Duration) -> Pool<C> {
+        Pool {
+            idle: HashMap::new(),
+            max_idle_per_host: max_idle_per_host,
+            idle_timeout: idle_timeout,
+        }
+    }
+
+    /// Take the most recently used connection for `key`, discarding any that
+    /// have been idle for too long or are no longer alive.
+    pub fn take(&mut self, key: &PoolKey) -> Option<C> {
+        let idle_timeout = self.idle_timeout;
+        let mut found = None;
+
+        if let Some(conns) = self.idle.get_mut(key) {
+            while let Some((conn, since)) = conns.pop() {
+                if since.elapsed() < idle_timeout && conn.is_alive() {
+                    found = Some(conn);
+                    break;
+                }
+            }
+        }
+
+        if self.idle.get(key).map_or(false, |conns| conns.is_empty()) {
+            self.idle.remove(key);
+        }
+
+        found
+    }
+
+    /// Return a connection to the pool, evicting the longest idle connection
+    /// for the same key if there are already too many.
+    pub fn put(&mut self, key: PoolKey, conn: C) {
+        self.expire();
+
+        if self.max_idle_per_host == 0 {
+            return;
+        }
+
+        let conns = self.idle.entry(key).or_insert_with(Vec::new);
+        if conns.len() >= self.max_idle_per_host {
+            conns.remove(0);
+        }
+
+        conns.push((conn, Instant::now()));
+    }
+
+    /// Close every connection that has been idle for too long.
+    pub fn expire(&mut self) {
+        let idle_timeout = self.idle_timeout;
+
+        for conns in self.idle.values_mut() {
+            conns.retain(|&(_, since)| since.elapsed() < idle_timeout);
+        }
+
+        self.idle.retain(|_, conns| !conns.is_empty());
+    }
+
+    /// The number of idle connections held for `key`.
+    pub fn idle_count(&self, key: &PoolKey) -> usize {
+        self.idle.get(key).map_or(0, |conns| conns.len())
+    }
+}
+
+pub struct Client {
+    pool: Mutex<Pool<mioco::tcp::TcpStream>>,
+    via_pseudonym: String,
+    templates: Templates,
+    connect_timeout: Duration,
+    read_timeout: Duration,
+    peers: Vec<Peer>,
+}
 
 impl Client {
-    pub fn forward<S: Write>(&self, downstream: &mut S, request: Request, body: Vec<u8>) {
-        match self.connect(&request.url) {
-            Ok(mut upstream) => {
-                let serialized: Vec<u8> = request.into();
-                upstream.write_all(&serialized).unwrap();
-                upstream.write_all(&body).unwrap();
-
-                let mut buffer = [0; 65536];
-
-                // FIXME: actually parse the response here.
-                loop {
-                    match upstream.read(&mut buffer) {
-                        Ok(0) => {
-                            break
-                        },
-                        Ok(n) => {
-                            downstream.write_all(&buffer[..n]).unwrap();
+    pub fn new(config: &Config) -> Client {
+        Client {
+            pool: Mutex::new(Pool::new(config.pool_max_idle_per_host, config.pool_idle_timeout)),
+            via_pseudonym: config.via_pseudonym.clone(),
+            templates: config.error_templates.clone(),
+            connect_timeout: config.upstream_connect_timeout,
+            read_timeout: config.upstream_read_timeout,
+            peers: config.peers.clone(),
+        }
+    }
+
+    /// Send `request` upstream and relay the reply back to `downstream`.
+    ///
+    /// The request body is streamed upstream as it arrives from `downstream`
+    /// itself, so only a bounded amount of it is ever held in memory.
+    ///
+    /// Idle connections to the same origin are reused where possible, and the
+    /// connection is returned to the pool afterwards if the origin allows it.
+    /// Requests for hosts a peer serves go through the peer instead.
+    ///
+    /// Storable replies are put in `cache` on the way past. If `stale` is
+    /// given, it's revalidated with the origin if it has validators, and
+    /// served from the cache if it turns out not to have changed. It's also
+    /// served in place of an error from the origin where `stale-if-error`
+    /// allows.
+    ///
+    /// Identical requests forwarded at the same time share a single fetch
+    /// from the origin, as long as the reply is one they could all have been
+    /// given from the cache.
+    ///
+    /// Returns whether `downstream` can be used for another request.
+    pub fn forward<S: BufRead + Write>(&self, downstream: &mut S, mut request: Request, framing: Framing, cache: &Cache, stale: Option<Entry>) -> Result<bool> {
+        let original = request.clone();
+        let method = request.method.clone();
+        let url = request.url.clone();
+        let client_version = request.version;
+        let client_keep_alive = request.keep_alive();
+
+        let mut leader = None;
+        if framing == Framing::Empty {
+            match cache.collapse(&original) {
+                Some(Collapse::Leader(l)) => {
+                    leader = Some(l);
+                },
+                Some(Collapse::Follower(mut follower)) => {
+                    // A leader that's stuck shouldn't hold this request up
+                    // for longer than the origin would be allowed to.
+                    follower.set_timeout(Some(self.read_timeout));
+                    if let Some(keep_alive) = try!(self.follow(downstream, &original, follower, cache)) {
+                        return Ok(keep_alive);
+                    }
+                },
+                None => {},
+            }
+        }
+
+        request.headers.strip_hop_by_hop();
+
+        let revalidating = stale.as_ref().map_or(false, |entry| validation::has_validators(&entry.reply));
+        if revalidating {
+            if let Some(ref entry) = stale {
+                validation::make_conditional(&mut request.headers, &entry.reply);
+            }
+        }
+
+        request.headers.append_via(client_version, &self.via_pseudonym);
+        // Whatever the client spoke, we speak HTTP/1.1 upstream.
+        request.version = 1;
+
+        let (next_hop, via_peer) = self.route(&url);
+        let serialized = request.serialize(via_peer);
+
+        // A pooled connection may have been closed by the origin while it sat
+        // idle, in which case requests that are safe to send twice are retried
+        // on a fresh connection.
+        let retryable = framing == Framing::Empty && is_idempotent(&method);
+        let mut allow_reuse = true;
+        let request_time = SystemTime::now();
+
+        let (mut upstream, mut reply, body_sent) = loop {
+            let (upstream, reused) = match self.checkout(&next_hop, allow_reuse) {
+                Ok(v) => v,
+                Err(e) => {
+                    warn!("Error connecting upstream: {}", e);
+                    if let Some(result) = self.serve_stale_on_error(downstream, &original, &stale) {
+                        return result;
+                    }
+                    try!(pages::send_error(downstream, &self.templates, Some(&original), &e));
+                    return Ok(false);
+                }
+            };
+
+            let mut upstream = Buffered::new(upstream);
+
+            if let Err(e) = upstream.write_all(&serialized) {
+                if reused && retryable {
+                    debug!("Pooled connection to {} is dead, retrying: {}", url, e);
+                    allow_reuse = false;
+                    continue;
+                }
+
+                warn!("Error sending request upstream: {}", e);
+                if let Some(result) = self.serve_stale_on_error(downstream, &original, &stale) {
+                    return result;
+                }
+                let e = Error::from(e);
+                try!(pages::send_error(downstream, &self.templates, Some(&original), &e));
+                return Err(e);
+            }
+
+            if let Err(e) = body::relay(downstream, &mut upstream, &framing) {
+                warn!("Error sending request body upstream: {}", e);
+                if e.kind() == io::ErrorKind::InvalidData {
+                    // The client's body is malformed, e.g. bad chunking.
+                    let e = Error::Parse(e.to_string());
+                    try!(pages::send_error(downstream, &self.templates, Some(&original), &e));
+                    return Err(e);
+                }
+                if e.kind() == io::ErrorKind::TimedOut {
+                    // Only reads from the client are timed here.
+                    let e = Error::ClientTimeout(e.to_string());
+                    try!(pages::send_error(downstream, &self.templates, Some(&original), &e));
+                    return Err(e);
+                }
+
+                // The origin may have stopped reading because it's already
+                // replied, e.g. with 413 Payload Too Large. Otherwise letting
+                // it know there's no more to come should get it to answer or
+                // hang up, rather than leave us waiting.
+                let _ = upstream.get_ref().get_ref().shutdown(Shutdown::Write);
+                if let Ok(reply) = read_reply(&mut upstream) {
+                    break (upstream, reply, false);
+                }
+
+                if let Some(result) = self.serve_stale_on_error(downstream, &original, &stale) {
+                    return result;
+                }
+                let e = Error::from(e);
+                try!(pages::send_error(downstream, &self.templates, Some(&original), &e));
+                return Err(e);
+            }
+
+            match read_reply(&mut upstream) {
+                Ok(reply) => {
+                    break (upstream, reply, true);
+                },
+                Err(e) => {
+                    let timed_out = match e {
+                        Error::Timeout(_) => true,
+                        _ => false,
+                    };
+
+                    if reused && retryable && !timed_out {
+                        debug!("Pooled connection to {} is dead, retrying: {}", url, e);
+                        allow_reuse = false;
+                        continue;
+                    }
+
+                    warn!("Error reading reply from upstream: {}", e);
+                    if let Some(result) = self.serve_stale_on_error(downstream, &original, &stale) {
+                        return result;
+                    }
+                    try!(pages::send_error(downstream, &self.templates, Some(&original), &e));
+                    return Err(e);
+                }
+            }
+        };
+
+        loop {
+            let reply_framing = match Framing::for_reply(&reply, &method) {
+                Ok(framing) => framing,
+                Err(e) => {
+                    // There's no telling where the body ends, so the reply
+                    // can't be passed on.
+                    warn!("Invalid reply from upstream: {}", e);
+                    if let Some(result) = self.serve_stale_on_error(downstream, &original, &stale) {
+                        return result;
+                    }
+                    let e = Error::Upstream(e.to_string());
+                    try!(pages::send_error(downstream, &self.templates, Some(&original), &e));
+                    return Err(e);
+                }
+            };
+            // If the request body didn't all make it, neither connection is
+            // where the next message would start.
+            let reusable = body_sent && reply_framing != Framing::Close && reply.keep_alive();
+
+            debug!("Upstream replied: {:?} {:?}", reply, reply_framing);
+
+            reply.headers.strip_hop_by_hop();
+
+            if reply.code == 304 && revalidating {
+                if let Some(entry) = stale {
+                    if reusable && upstream.buffered().is_empty() {
+                        self.checkin(&next_hop, upstream.into_inner());
+                    }
+
+                    return match entry.refresh(&reply, request_time, SystemTime::now()) {
+                        Some(refreshed) => {
+                            debug!("Revalidated cached {}", url);
+                            cache.store(&original, refreshed.clone());
+
+                            if let Some(leader) = leader {
+                                leader.publish(&refreshed.reply, &original.headers);
+                                leader.send(&refreshed.body);
+                                leader.finish();
+                            }
+
+                            self.serve_cached(downstream, &original, &refreshed)
                         },
-                        Err(e) => {
-                            println!("Error {}", e);
-                            break
+                        None => {
+                            // The origin's idea of what we have is different
+                            // to ours, so start again from scratch. Anyone
+                            // waiting on us has to as well.
+                            info!("Revalidating {} gave a different representation, refetching", url);
+                            drop(leader);
+                            self.forward(downstream, original, framing, cache, None)
+                        }
+                    };
+                }
+            }
+
+            if reply.code >= 500 {
+                if let Some(result) = self.serve_stale_on_error(downstream, &original, &stale) {
+                    return result;
+                }
+            }
+
+            // Keep the reply as the origin sent it for the cache, before it's
+            // tailored to this particular client. Any collapsed requests get
+            // it as is too, or go to the origin themselves if it can't be
+            // stored.
+            let mut stored = None;
+            if !reply.is_interim() {
+                let leader = leader.take();
+
+                if cache.is_storable(&method, &original.headers, &reply) {
+                    let mut capture = cache.capture();
+                    if let Some(leader) = leader {
+                        capture.share(leader, &reply, &original.headers);
+                    }
+
+                    stored = Some((reply.clone(), SystemTime::now(), capture));
+                }
+            }
+
+            reply.headers.append_via(reply.version, &self.via_pseudonym);
+
+            // We speak HTTP/1.1 to the client regardless of what the origin
+            // speaks to us.
+            reply.version = 1;
+
+            if reply.is_interim() {
+                // e.g. 100 Continue. HTTP/1.0 clients don't expect these at
+                // all, otherwise pass it on, then wait for the real reply.
+                if client_version >= 1 {
+                    let head: Vec<u8> = reply.into();
+                    try!(downstream.write_all(&head));
+                }
+
+                match read_reply(&mut upstream) {
+                    Ok(next) => {
+                        reply = next;
+                        continue;
+                    },
+                    Err(e) => {
+                        warn!("Error reading reply from upstream: {}", e);
+                        if let Some(result) = self.serve_stale_on_error(downstream, &original, &stale) {
+                            return result;
                         }
+                        try!(pages::send_error(downstream, &self.templates, Some(&original), &e));
+                        return Err(e);
                     }
                 }
+            }
+
+            if !is_safe(&method) && reply.code < 400 {
+                cache.invalidate(&url);
+            }
+
+            // HTTP/1.0 clients don't understand chunked, so decode it and mark
+            // the end of the body by closing the connection instead.
+            let dechunk = reply_framing == Framing::Chunked && client_version == 0;
+            if dechunk {
+                reply.headers.remove("transfer-encoding");
+            }
+
+            // Transfer-Encoding overrides Content-Length, which a client
+            // might trust instead, as per RFC 7230 section 3.3.3.
+            if reply_framing == Framing::Chunked {
+                reply.headers.remove("content-length");
+            }
+
+            let keep_alive = body_sent && client_keep_alive && !dechunk && reply_framing != Framing::Close;
+
+            if !keep_alive {
+                reply.headers.insert("Connection", &b"close".to_vec());
+            } else if client_version == 0 {
+                reply.headers.insert("Connection", &b"keep-alive".to_vec());
+            }
+
+            let head: Vec<u8> = reply.into();
+            try!(downstream.write_all(&head));
+
+            match stored {
+                Some((_, _, ref mut capture)) => {
+                    try!(body::relay_and_copy(&mut upstream, downstream, &reply_framing, dechunk, capture));
+                },
+                None => {
+                    try!(body::relay_and_copy(&mut upstream, downstream, &reply_framing, dechunk, &mut io::sink()));
+                }
+            }
+
+            if let Some((stored, response_time, capture)) = stored {
+                if let Some(body) = capture.into_body() {
+                    cache.store(&original, Entry::new(stored, body, request_time, response_time));
+                }
+            }
+
+            // Anything the origin sent beyond the end of the body means we've
+            // lost track of the framing, so the connection can't be reused.
+            if reusable && upstream.buffered().is_empty() {
+                self.checkin(&next_hop, upstream.into_inner());
+            }
+
+            return Ok(keep_alive);
+        }
+    }
+
+    /// Answer `request` with `entry` from the cache, or just with 304 Not
+    /// Modified if the client's conditions say it already has it. Any byte
+    /// ranges asked for are cut out of the cached body.
+    ///
+    /// Returns whether `downstream` can be used for another request.
+    pub fn serve_cached<S: Write>(&self, downstream: &mut S, request: &Request, entry: &Entry) -> Result<bool> {
+        let keep_alive = request.keep_alive();
+        let not_modified = validation::is_not_modified(&request.headers, &entry.reply);
+
+        let mut reply = entry.reply_at(SystemTime::now());
+        let mut body = Cow::Borrowed(&entry.body[..]);
+
+        if not_modified {
+            reply = validation::not_modified(&reply);
+            body = Cow::Borrowed(&[]);
+        } else if request.method == "HEAD" {
+            body = Cow::Borrowed(&[]);
+        } else {
+            match range::apply(&request.headers, &reply, &entry.body) {
+                Ranged::Full => {},
+                Ranged::Partial(partial, content) => {
+                    reply = partial;
+                    body = Cow::Owned(content);
+                },
+                Ranged::Unsatisfiable(unsatisfiable) => {
+                    reply = unsatisfiable;
+                    body = Cow::Borrowed(&[]);
+                },
+            }
+        }
+
+        reply.headers.append_via(reply.version, &self.via_pseudonym);
+        reply.version = 1;
+
+        if !keep_alive {
+            reply.headers.insert("Connection", &b"close".to_vec());
+        } else if request.version == 0 {
+            reply.headers.insert("Connection", &b"keep-alive".to_vec());
+        }
+
+        let head: Vec<u8> = reply.into();
+        try!(downstream.write_all(&head));
+        try!(downstream.write_all(&body));
+
+        Ok(keep_alive)
+    }
+
+    /// Revalidate `stale` in the background, after it's already been served
+    /// to `request`, as per RFC 5861 section 3.
+    pub fn revalidate(&self, mut request: Request, stale: Entry, cache: &Cache) -> Result<()> {
+        // Whatever the client itself wanted has been dealt with, and the cache
+        // needs the whole thing.
+        for name in &["if-match", "if-none-match", "if-modified-since", "if-unmodified-since", "if-range", "range"] {
+            request.headers.remove(name);
+        }
+
+        debug!("Revalidating {} in the background", request.url);
+        try!(self.forward(&mut Discard, request, Framing::Empty, cache, Some(stale)));
+        Ok(())
+    }
+
+    /// Answer `request` with `stale` instead of an error, if its
+    /// `stale-if-error` allows it.
+    ///
+    /// Returns None if not, leaving the error to be reported as usual.
+    fn serve_stale_on_error<S: Write>(&self, downstream: &mut S, request: &Request, stale: &Option<Entry>) -> Option<Result<bool>> {
+        match *stale {
+            Some(ref entry) if entry.is_usable_on_error(&request.headers, SystemTime::now()) => {
+                info!("Serving stale {} in place of an error", request.url);
+                Some(self.serve_cached(downstream, request, entry))
             },
-            Err(e) => {
-                println!("Error connecting upstream: {}", e);
-                downstream.write_all(b"HTTP/1.1 501 Internal Server Error\r\nContent-Length: 6\r\n\r\nSorry\n").unwrap();
+            _ => None,
+        }
+    }
+
+    /// Answer `request` with the reply fetched by the leader `follower` is
+    /// waiting on, streaming the body as it arrives.
+    ///
+    /// Returns None, without having written anything, if the reply isn't one
+    /// that could be given to this request, which then has to go to the
+    /// origin itself. Otherwise returns whether `downstream` can be used for
+    /// another request.
+    fn follow<S: Write>(&self, downstream: &mut S, request: &Request, mut follower: Follower, cache: &Cache) -> Result<Option<bool>> {
+        let (mut reply, variant) = match follower.reply() {
+            Some(shared) => shared,
+            None => return Ok(None),
+        };
+
+        let names = vary::vary_names(&reply).unwrap_or(Vec::new());
+        if variant != vary::Variant::select(&names, &request.headers) || !cache.is_storable(&request.method, &request.headers, &reply) {
+            return Ok(None);
+        }
+
+        debug!("Sharing the reply for {}", request.url);
+
+        if validation::is_not_modified(&request.headers, &reply) {
+            let mut reply = validation::not_modified(&reply);
+            reply.headers.append_via(reply.version, &self.via_pseudonym);
+            reply.version = 1;
+
+            let head: Vec<u8> = reply.into();
+            try!(downstream.write_all(&head));
+            return Ok(Some(request.keep_alive()));
+        }
+
+        // The body is shared decoded, so is framed afresh for this client.
+        if reply.headers.remove("transfer-encoding") {
+            reply.headers.remove("content-length");
+        }
+
+        let chunked = reply.headers.get("content-length").is_none() && request.version >= 1;
+        let keep_alive = request.keep_alive() && (reply.headers.get("content-length").is_some() || chunked);
+
+        if chunked {
+            reply.headers.insert("Transfer-Encoding", &b"chunked".to_vec());
+        }
+
+        reply.headers.append_via(reply.version, &self.via_pseudonym);
+        reply.version = 1;
+
+        if !keep_alive {
+            reply.headers.insert("Connection", &b"close".to_vec());
+        } else if request.version == 0 {
+            reply.headers.insert("Connection", &b"keep-alive".to_vec());
+        }
+
+        let head: Vec<u8> = reply.into();
+        try!(downstream.write_all(&head));
+
+        if chunked {
+            let mut writer = ChunkedWriter::new(downstream);
+            try!(io::copy(&mut follower, &mut writer));
+            try!(writer.finish(None));
+        } else {
+            try!(io::copy(&mut follower, downstream));
+        }
+
+        Ok(Some(keep_alive))
+    }
+
+    /// Where to send a request for `url`: the URL of the peer that serves its
+    /// host, or else `url` itself. Also returns whether it's a peer.
+    fn route(&self, url: &url::Url) -> (url::Url, bool) {
+        let host = url.host_str().unwrap_or("");
+
+        if let Some(peer) = self.peers.iter().find(|peer| peer.serves(host)) {
+            if let Ok(peer_url) = url::Url::parse(&format!("http://{}/", peer.address)) {
+                return (peer_url, true);
             }
         }
+
+        (url.clone(), false)
     }
 
-    pub fn connect(&self, url: &url::Url) -> io::Result<mioco::tcp::TcpStream> {
-        // FIXME: actual async DNS would be nice?
-        for addrs in url.to_socket_addrs() {
-            // Extract std::net::SocketAddr for this set
-            for addr in addrs {
-                match mioco::tcp::TcpStream::connect(&addr) {
-                    Ok(conn) => {
-                        return Ok(conn);
-                    }
-                    Err(_) => {
-                        continue;
-                    }
+    /// Get a connection to the origin of `url`, preferring an idle pooled
+    /// one if `allow_reuse` is set. Reading from it times out if the origin
+    /// stalls.
+    ///
+    /// Returns the connection and whether it came from the pool.
+    fn checkout(&self, url: &url::Url, allow_reuse: bool) -> Result<(Timed<mioco::tcp::TcpStream>, bool)> {
+        if allow_reuse {
+            let pooled = self.pool.lock().unwrap().take(&PoolKey::for_url(url));
+            if let Some(conn) = pooled {
+                return Ok((Timed::new(conn, self.read_timeout), true));
+            }
+        }
+
+        let conn = try!(self.connect(url));
+        Ok((Timed::new(conn, self.read_timeout), false))
+    }
+
+    fn checkin(&self, url: &url::Url, conn: Timed<mioco::tcp::TcpStream>) {
+        self.pool.lock().unwrap().put(PoolKey::for_url(url), conn.into_inner());
+    }
+
+    /// Connect to the origin of `url`, giving up if it takes longer than the
+    /// connect timeout.
+    pub fn connect(&self, url: &url::Url) -> Result<mioco::tcp::TcpStream> {
+        let target = url.clone();
+
+        match timeout::with_timeout(self.connect_timeout, move || connect(&target)) {
+            Some(result) => result,
+            None => Err(Error::Timeout(format!("connecting to {} took longer than {}s", url, self.connect_timeout.as_secs()))),
+        }
+    }
+}
+
+fn connect(url: &url::Url) -> Result<mioco::tcp::TcpStream> {
+    if let Ok(addrs) = resolve(url) {
+        // Extract std::net::SocketAddr for this set
+        for addr in addrs {
+            match mioco::tcp::TcpStream::connect(&addr) {
+                Ok(conn) => {
+                    return Ok(conn);
+                }
+                Err(_) => {
+                    continue;
                 }
             }
         }
+    }
+
+    Err(Error::Connect(format!("No suitable host could be found for {}", url)))
+}
+
+/// Look up the addresses of the origin of `url`.
+///
+/// The standard library's lookup blocks, which would hold up every coroutine
+/// on the scheduler's thread, timers included, so it's done on a thread of
+/// its own while this coroutine waits.
+fn resolve(url: &url::Url) -> io::Result<Vec<SocketAddr>> {
+    // FIXME: actual async DNS would be nice?
+    let (sender, receiver) = mioco::sync::mpsc::channel();
+    let url = url.clone();
+
+    thread::spawn(move || {
+        let _ = sender.send(url.to_socket_addrs().map(|addrs| addrs.collect()));
+    });
+
+    match receiver.recv() {
+        Ok(addrs) => addrs,
+        Err(_) => Err(io::Error::new(io::ErrorKind::Other, "looking up the address failed")),
+    }
+}
+
+/// Stands in for the client of a request nobody is waiting on.
+struct Discard;
+
+impl Read for Discard {
+    fn read(&mut self, _: &mut [u8]) -> io::Result<usize> {
+        Ok(0)
+    }
+}
+
+impl BufRead for Discard {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        Ok(&[])
+    }
+
+    fn consume(&mut self, _: usize) {}
+}
+
+impl Write for Discard {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Whether a request with this method can be sent again without ill effect,
+/// as per RFC 7231 section 4.2.2.
+fn is_idempotent(method: &str) -> bool {
+    match method {
+        "GET" | "HEAD" | "OPTIONS" | "TRACE" | "PUT" | "DELETE" => true,
+        _ => false,
+    }
+}
+
+/// Whether a request with this method only retrieves things, as per RFC 7231
+/// section 4.2.1.
+fn is_safe(method: &str) -> bool {
+    match method {
+        "GET" | "HEAD" | "OPTIONS" | "TRACE" => true,
+        _ => false,
+    }
+}
+
+/// Read a reply head from `upstream`.
+///
+/// Anything read past the end of the head is left buffered in `upstream`.
+fn read_reply<R: Read>(upstream: &mut Buffered<R>) -> Result<Reply> {
+    let mut buffer = Vec::new();
+
+    loop {
+        match read_into_buffer(upstream, &mut buffer) {
+            Ok(0) => {
+                return Err(Error::Upstream(String::from("upstream closed before replying")));
+            },
+            Ok(_) => {},
+            Err(e) => {
+                return Err(Error::from(e));
+            }
+        }
+
+        let mut headers = [httparse::EMPTY_HEADER; MAX_REPLY_HEADERS];
+        match reply::parse(&buffer, &mut headers, buffer.len()) {
+            Ok(Some((reply, leftover))) => {
+                upstream.unread(&leftover);
+                return Ok(reply);
+            },
+            Ok(None) => {},
+            Err(e) => {
+                return Err(Error::Upstream(format!("Error parsing reply: {}", e)));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate url;
+
+    extern crate mioco;
+
+    use std::io::{self, Cursor, Read, Write};
+    use std::net::{SocketAddr, TcpListener};
+    use std::thread;
+    use std::time::{Duration, SystemTime};
+
+    use cache::{Cache, Entry};
+    use config::{Config, Peer};
+
+    use super::{read_reply, Client, Liveness, Pool, PoolKey};
+    use super::super::body::Framing;
+    use super::super::buffered::Buffered;
+    use super::super::test_support::request;
+
+    struct Conn {
+        id: usize,
+        alive: bool,
+    }
+
+    /// A client connection that has sent `input`, keeping whatever it's sent
+    /// back. If it `stalls`, it then goes quiet until reading from it times
+    /// out, rather than hanging up.
+    struct Downstream {
+        input: Cursor<Vec<u8>>,
+        stalls: bool,
+        output: Vec<u8>,
+    }
+
+    impl Read for Downstream {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            match try!(self.input.read(buf)) {
+                0 if self.stalls => Err(io::Error::new(io::ErrorKind::TimedOut, "nothing read in time")),
+                n => Ok(n),
+            }
+        }
+    }
+
+    impl Write for Downstream {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.output.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// Start an origin server that answers a single request with `reply`.
+    fn origin(reply: &'static [u8]) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+
+        thread::spawn(move || {
+            let (mut conn, _) = listener.accept().unwrap();
+
+            let mut head = Vec::new();
+            let mut byte = [0; 1];
+            while !head.ends_with(b"\r\n\r\n") && conn.read(&mut byte).unwrap() == 1 {
+                head.push(byte[0]);
+            }
+
+            conn.write_all(reply).unwrap();
+
+            // Hang up only once the proxy has, so nothing it sent is left
+            // unread.
+            let _ = io::copy(&mut conn, &mut io::sink());
+        });
+
+        address
+    }
+
+    /// Forward `raw`, a request head, with whatever the client sends after it
+    /// being `body`. Returns what the client is sent back.
+    fn forward(raw: String, body: &'static [u8], stalls: bool) -> String {
+        mioco::start(move || {
+            let client = Client::new(&Config::default());
+            let cache = Cache::new(&Config::default()).unwrap();
+            let mut downstream = Buffered::new(Downstream {
+                input: Cursor::new(body.to_vec()),
+                stalls: stalls,
+                output: Vec::new(),
+            });
+
+            let request = request(raw);
+            let framing = Framing::for_request(&request).unwrap();
+            let _ = client.forward(&mut downstream, request, framing, &cache, None);
+            String::from_utf8(downstream.into_inner().output).unwrap()
+        }).unwrap()
+    }
+
+    impl Liveness for Conn {
+        fn is_alive(&self) -> bool {
+            self.alive
+        }
+    }
+
+    fn key(url: &str) -> PoolKey {
+        PoolKey::for_url(&url::Url::parse(url).unwrap())
+    }
+
+    #[test]
+    fn test_pool_key() {
+        assert_eq!(key("http://google.com/foo"), key("http://google.com:80/bar?baz"));
+        assert!(key("http://google.com/") != key("https://google.com/"));
+        assert!(key("http://google.com/") != key("http://google.com:8080/"));
+        assert!(key("http://google.com/") != key("http://www.google.com/"));
+    }
+
+    #[test]
+    fn test_pool_reuse() {
+        let mut pool = Pool::new(2, Duration::from_secs(60));
+        let google = key("http://google.com/");
+
+        assert!(pool.take(&google).is_none());
+
+        pool.put(google.clone(), Conn { id: 1, alive: true });
+        pool.put(google.clone(), Conn { id: 2, alive: true });
+        pool.put(google.clone(), Conn { id: 3, alive: true });
+        assert_eq!(pool.idle_count(&google), 2);
+
+        // Most recently used first, and the oldest was evicted.
+        assert_eq!(pool.take(&google).unwrap().id, 3);
+        assert_eq!(pool.take(&google).unwrap().id, 2);
+        assert!(pool.take(&google).is_none());
+        assert!(pool.take(&key("http://yahoo.com/")).is_none());
+    }
+
+    #[test]
+    fn test_pool_liveness() {
+        let mut pool = Pool::new(4, Duration::from_secs(60));
+        let google = key("http://google.com/");
+
+        pool.put(google.clone(), Conn { id: 1, alive: true });
+        pool.put(google.clone(), Conn { id: 2, alive: false });
+
+        assert_eq!(pool.take(&google).unwrap().id, 1);
+        assert_eq!(pool.idle_count(&google), 0);
+    }
+
+    #[test]
+    fn test_pool_idle_timeout() {
+        let mut pool = Pool::new(4, Duration::from_millis(10));
+        let google = key("http://google.com/");
+        let yahoo = key("http://yahoo.com/");
+
+        pool.put(google.clone(), Conn { id: 1, alive: true });
+        thread::sleep(Duration::from_millis(20));
+
+        // Putting anything back sweeps out expired connections for every host.
+        pool.put(yahoo.clone(), Conn { id: 2, alive: true });
+        assert_eq!(pool.idle_count(&google), 0);
+        assert!(pool.take(&google).is_none());
+        assert_eq!(pool.take(&yahoo).unwrap().id, 2);
+    }
+
+    #[test]
+    fn test_pool_disabled() {
+        let mut pool = Pool::new(0, Duration::from_secs(60));
+        let google = key("http://google.com/");
+
+        pool.put(google.clone(), Conn { id: 1, alive: true });
+        assert!(pool.take(&google).is_none());
+    }
+
+    #[test]
+    fn test_read_reply() {
+        let mut upstream = Buffered::new(Cursor::new(b"HTTP/1.1 100 Continue\r\n\r\nHTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nhi".to_vec()));
+
+        let reply = read_reply(&mut upstream).unwrap();
+        assert_eq!(reply.code, 100);
+        assert!(reply.is_interim());
+
+        let reply = read_reply(&mut upstream).unwrap();
+        assert_eq!(reply.code, 200);
+        assert_eq!(upstream.buffered(), b"hi");
+    }
+
+    #[test]
+    fn test_read_reply_eof() {
+        let mut upstream = Buffered::new(Cursor::new(b"HTTP/1.1 200 OK\r\nContent-Le".to_vec()));
+
+        assert!(read_reply(&mut upstream).is_err());
+    }
+
+    #[test]
+    fn test_read_reply_invalid() {
+        let mut upstream = Buffered::new(Cursor::new(b"HTTP/1.1 200 OK\r\nHost: a\r\nHost: b\r\n\r\n".to_vec()));
+
+        match read_reply(&mut upstream) {
+            Err(e) => assert_eq!(e.status().0, 502),
+            Ok(reply) => panic!("expected an error, got {:?}", reply),
+        }
+    }
+
+    #[test]
+    fn test_route() {
+        let mut config = Config::default();
+        config.peers.push(Peer {
+            address: String::from("parent:3128"),
+            domains: vec![String::from("example.com")],
+        });
+        let client = Client::new(&config);
+
+        let (next_hop, via_peer) = client.route(&url::Url::parse("http://www.example.com/a").unwrap());
+        assert!(via_peer);
+        assert_eq!(next_hop.as_str(), "http://parent:3128/");
+
+        let url = url::Url::parse("http://example.org/a").unwrap();
+        assert_eq!(client.route(&url), (url.clone(), false));
+    }
+
+    #[test]
+    fn test_serve_cached_range() {
+        let client = Client::new(&Config::default());
+        let mut upstream = Buffered::new(Cursor::new(b"HTTP/1.1 200 OK\r\nETag: \"v1\"\r\nContent-Length: 10\r\n\r\n".to_vec()));
+        let entry = Entry::new(read_reply(&mut upstream).unwrap(), b"0123456789".to_vec(), SystemTime::now(), SystemTime::now());
+
+        let serve = |raw: &str| {
+            let mut out = Vec::new();
+            client.serve_cached(&mut out, &request(raw), &entry).unwrap();
+            String::from_utf8(out).unwrap()
+        };
+
+        let out = serve("GET http://example.com/ HTTP/1.1\r\nRange: bytes=-3\r\n\r\n");
+        assert!(out.starts_with("HTTP/1.1 206 Partial Content\r\n"));
+        assert!(out.contains("Content-Range: bytes 7-9/10\r\n"));
+        assert!(out.ends_with("\r\n\r\n789"));
+
+        let out = serve("HEAD http://example.com/ HTTP/1.1\r\nRange: bytes=-3\r\n\r\n");
+        assert!(out.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(out.ends_with("\r\n\r\n"));
+
+        let out = serve("GET http://example.com/ HTTP/1.1\r\nRange: bytes=-3\r\nIf-Range: \"v0\"\r\n\r\n");
+        assert!(out.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(out.ends_with("\r\n\r\n0123456789"));
+
+        let out = serve("GET http://example.com/ HTTP/1.1\r\nRange: bytes=10-\r\n\r\n");
+        assert!(out.starts_with("HTTP/1.1 416 Range Not Satisfiable\r\n"));
+    }
+
+    #[test]
+    fn test_forward_chunked_with_length() {
+        let reply = b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\nContent-Length: 100\r\nCache-Control: no-store\r\n\r\n5\r\nHello\r\n0\r\n\r\n";
+
+        let out = forward(format!("GET http://{}/ HTTP/1.1\r\n\r\n", origin(reply)), b"", false);
+        assert!(out.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(out.contains("Transfer-Encoding: chunked\r\n"));
+        assert!(!out.contains("Content-Length"));
+        assert!(out.ends_with("\r\n\r\n5\r\nHello\r\n0\r\n\r\n"));
+
+        let out = forward(format!("GET http://{}/ HTTP/1.0\r\n\r\n", origin(reply)), b"", false);
+        assert!(!out.contains("Transfer-Encoding"));
+        assert!(!out.contains("Content-Length"));
+        assert!(out.contains("Connection: close\r\n"));
+        assert!(out.ends_with("\r\n\r\nHello"));
+    }
+
+    #[test]
+    fn test_forward_body_cut_short() {
+        // The origin's reply gets through even if the body doesn't.
+        let reply = b"HTTP/1.1 413 Payload Too Large\r\nContent-Length: 0\r\n\r\n";
+        let out = forward(format!("POST http://{}/ HTTP/1.1\r\nContent-Length: 10\r\n\r\n", origin(reply)), b"abc", false);
+        assert!(out.starts_with("HTTP/1.1 413 Payload Too Large\r\n"));
+        assert!(out.contains("Connection: close\r\n"));
+
+        // Otherwise the client is told what happened.
+        let out = forward(format!("POST http://{}/ HTTP/1.1\r\nContent-Length: 10\r\n\r\n", origin(b"")), b"abc", false);
+        assert!(out.starts_with("HTTP/1.1 502 Bad Gateway\r\n"));
+    }
+
+    #[test]
+    fn test_forward_body_timed_out() {
+        let reply = b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n";
+        let out = forward(format!("POST http://{}/ HTTP/1.1\r\nContent-Length: 10\r\n\r\n", origin(reply)), b"abc", true);
+        assert!(out.starts_with("HTTP/1.1 408 Request Timeout\r\n"));
+    }
 
-        Err(io::Error::new(io::ErrorKind::NotFound, "No suitable host could be found"))
+    #[test]
+    fn test_forward_switching_protocols() {
+        let reply = b"HTTP/1.1 101 Switching Protocols\r\nConnection: upgrade\r\nUpgrade: websocket\r\n\r\n";
+        let out = forward(format!("GET http://{}/ HTTP/1.1\r\nConnection: upgrade\r\nUpgrade: websocket\r\n\r\n", origin(reply)), b"", false);
+        assert!(out.starts_with("HTTP/1.1 502 Bad Gateway\r\n"));
+        assert!(out.contains("Connection: close\r\n"));
     }
 }
@@ -0,0 +1,160 @@
+use std::net::IpAddr;
+
+use cidr::Cidr;
+
+use super::headers::Headers;
+use super::request::Request;
+
+/// Record where `request` came from in the X-Forwarded-For,
+/// X-Forwarded-Proto, X-Forwarded-Host and Forwarded (RFC 7239) headers.
+///
+/// `peer` is the address the request was received from, over `proto`, or
+/// None if it doesn't have one, like a client on a Unix domain socket. If it's
+/// one of `trusted_proxies`, the values it sent are kept and `peer` is added to
+/// the end of the chain. Otherwise they can't be believed, so they're
+/// replaced outright.
+pub fn add_forwarded_headers(request: &mut Request, peer: Option<IpAddr>, proto: &str, trusted_proxies: &[Cidr]) {
+    let trusted = peer.map_or(false, |peer| trusted_proxies.iter().any(|cidr| cidr.contains(&peer)));
+
+    let host = match request.headers.get("host") {
+        Some(host) => String::from_utf8_lossy(host).into_owned(),
+        None => {
+            let host = request.url.host_str().unwrap_or("");
+            match request.url.port() {
+                Some(port) => format!("{}:{}", host, port),
+                None => String::from(host),
+            }
+        }
+    };
+
+    let mut forwarded_for = if trusted {
+        values(&request.headers, "x-forwarded-for")
+    } else {
+        Vec::new()
+    };
+    forwarded_for.push(peer.map_or(String::from("unknown"), |peer| peer.to_string()));
+    set(&mut request.headers, "X-Forwarded-For", &forwarded_for.join(", "));
+
+    if !trusted || request.headers.get("x-forwarded-proto").is_none() {
+        set(&mut request.headers, "X-Forwarded-Proto", proto);
+    }
+
+    if !trusted || request.headers.get("x-forwarded-host").is_none() {
+        set(&mut request.headers, "X-Forwarded-Host", &host);
+    }
+
+    let mut forwarded = if trusted {
+        values(&request.headers, "forwarded")
+    } else {
+        Vec::new()
+    };
+    forwarded.push(format!("for={};host={};proto={}", node(peer), quote(&host), quote(proto)));
+    set(&mut request.headers, "Forwarded", &forwarded.join(", "));
+}
+
+/// Every element of the comma-separated `name` headers, as one list.
+fn values(headers: &Headers, name: &str) -> Vec<String> {
+    headers.get_all(name)
+        .iter()
+        .map(|value| String::from_utf8_lossy(value).into_owned())
+        .filter(|value| !value.trim().is_empty())
+        .collect()
+}
+
+fn set(headers: &mut Headers, name: &str, value: &str) {
+    headers.remove(name);
+    headers.insert(name, &value.as_bytes().to_vec());
+}
+
+/// Format an address as a Forwarded node, which for IPv6 means bracketed and
+/// quoted, and without one means `unknown`.
+fn node(address: Option<IpAddr>) -> String {
+    match address {
+        Some(IpAddr::V4(v4)) => v4.to_string(),
+        Some(IpAddr::V6(v6)) => format!("\"[{}]\"", v6),
+        None => String::from("unknown"),
+    }
+}
+
+/// Quote `value` if it isn't a valid token on its own.
+fn quote(value: &str) -> String {
+    let is_token = !value.is_empty() && value.bytes().all(|c| {
+        match c {
+            b'!' | b'#' | b'$' | b'%' | b'&' | b'\'' | b'*' | b'+' | b'-' | b'.' |
+            b'^' | b'_' | b'`' | b'|' | b'~' => true,
+            _ => (c as char).is_ascii_alphanumeric(),
+        }
+    });
+
+    if is_token {
+        return String::from(value);
+    }
+
+    format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::IpAddr;
+    use std::str::FromStr;
+
+    use cidr::Cidr;
+
+    use super::add_forwarded_headers;
+    use super::super::request::Request;
+    use super::super::test_support::request;
+
+    fn header(request: &Request, name: &str) -> String {
+        String::from_utf8(request.headers.get(name).unwrap().clone()).unwrap()
+    }
+
+    const SPOOFED: &'static [u8] = b"GET / HTTP/1.1\r\nHost: example.com:8080\r\nX-Forwarded-For: 1.1.1.1\r\nX-Forwarded-Proto: https\r\nX-Forwarded-Host: evil.com\r\nForwarded: for=1.1.1.1\r\n\r\n";
+
+    #[test]
+    fn test_untrusted_peer() {
+        let mut req = request(SPOOFED);
+        let trusted = vec![Cidr::from_str("10.0.0.0/8").unwrap()];
+
+        add_forwarded_headers(&mut req, Some(IpAddr::from_str("192.0.2.1").unwrap()), "http", &trusted);
+
+        assert_eq!(header(&req, "X-Forwarded-For"), "192.0.2.1");
+        assert_eq!(header(&req, "X-Forwarded-Proto"), "http");
+        assert_eq!(header(&req, "X-Forwarded-Host"), "example.com:8080");
+        assert_eq!(header(&req, "Forwarded"), "for=192.0.2.1;host=\"example.com:8080\";proto=http");
+    }
+
+    #[test]
+    fn test_trusted_peer() {
+        let mut req = request(SPOOFED);
+        let trusted = vec![Cidr::from_str("10.0.0.0/8").unwrap()];
+
+        add_forwarded_headers(&mut req, Some(IpAddr::from_str("10.1.1.1").unwrap()), "http", &trusted);
+
+        assert_eq!(header(&req, "X-Forwarded-For"), "1.1.1.1, 10.1.1.1");
+        assert_eq!(header(&req, "X-Forwarded-Proto"), "https");
+        assert_eq!(header(&req, "X-Forwarded-Host"), "evil.com");
+        assert_eq!(header(&req, "Forwarded"), "for=1.1.1.1, for=10.1.1.1;host=\"example.com:8080\";proto=http");
+    }
+
+    #[test]
+    fn test_ipv6_peer_without_host() {
+        let mut req = request(b"GET http://example.com/ HTTP/1.1\r\n\r\n");
+
+        add_forwarded_headers(&mut req, Some(IpAddr::from_str("2001:db8::1").unwrap()), "http", &[]);
+
+        assert_eq!(header(&req, "X-Forwarded-For"), "2001:db8::1");
+        assert_eq!(header(&req, "X-Forwarded-Host"), "example.com");
+        assert_eq!(header(&req, "Forwarded"), "for=\"[2001:db8::1]\";host=example.com;proto=http");
+    }
+
+    #[test]
+    fn test_unknown_peer() {
+        let mut req = request(SPOOFED);
+
+        add_forwarded_headers(&mut req, None, "http", &[Cidr::from_str("0.0.0.0/0").unwrap()]);
+
+        assert_eq!(header(&req, "X-Forwarded-For"), "unknown");
+        assert_eq!(header(&req, "X-Forwarded-Host"), "example.com:8080");
+        assert_eq!(header(&req, "Forwarded"), "for=unknown;host=\"example.com:8080\";proto=http");
+    }
+}
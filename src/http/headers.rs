@@ -1,11 +1,12 @@
 extern crate httparse;
 
-use std::io;
 use std::str;
 use std::collections::{HashMap, LinkedList};
 use std::collections::hash_map::Entry;
 use std::clone::Clone;
 
+use error::{Error, Result};
+
 pub const DEFAULT_INTO_BUFFER_CAPACITY: usize = 65536;
 pub const DEFAULT_HEADER_ROW_CAPACITY: usize = 256;
 
@@ -15,6 +16,27 @@ const HEADER_EXTRA_BYTES: usize = 4;
 const HEADER_SEPARATOR: &'static [u8] = b": ";
 const HEADER_NEWLINE: &'static [u8] = b"\r\n";
 
+// Headers that only apply to a single connection, as per RFC 7230 section 6.1,
+// plus the non-standard Proxy-Connection. Transfer-Encoding and Trailer aren't
+// included, as bodies are forwarded with the transfer-coding they arrived with.
+const HOP_BY_HOP_HEADERS: &'static [&'static str] = &[
+    "connection",
+    "keep-alive",
+    "proxy-authenticate",
+    "proxy-authorization",
+    "proxy-connection",
+    "te",
+    "upgrade",
+];
+
+// Headers that are never removed just because Connection names them, as that
+// would change how the message is framed or routed.
+const PROTECTED_HEADERS: &'static [&'static str] = &[
+    "content-length",
+    "host",
+    "transfer-encoding",
+];
+
 #[derive(Debug)]
 struct OctopusHeader {
     // Original header name with case intact. This is different to the keys in
@@ -37,7 +59,9 @@ impl OctopusHeader {
         OctopusHeader {
             original_name: original,
             value: contents.clone(),
-            value_str: String::from_utf8(contents.clone()).unwrap(),
+            // Header values aren't guaranteed to be UTF-8, so this is only
+            // good for display.
+            value_str: String::from_utf8_lossy(contents).into_owned(),
             order: order,
             length_hint: length_hint,
         }
@@ -98,7 +122,7 @@ impl<'a> Headers {
         }
     }
 
-    pub fn from_raw(raw: &[httparse::Header]) -> io::Result<Headers> {
+    pub fn from_raw(raw: &[httparse::Header]) -> Result<Headers> {
         let mut headers = Headers::new();
         headers.total_count = raw.len();
 
@@ -107,19 +131,34 @@ impl<'a> Headers {
         }
 
         // Perform some basic verification.
-        if headers.validate() {
-            Ok(headers)
-        } else {
-            Err(io::Error::new(io::ErrorKind::Other, "header validation failed"))
-        }
+        try!(headers.validate());
+        Ok(headers)
     }
 
-    pub fn content_length(&self) -> Option<usize> {
-        match self.get("content-length") {
-            Some(value) => {
-                Some(str::from_utf8(value).unwrap().parse().unwrap())
-            },
-            None => None
+    /// The value of Content-Length, if there is one. Anything other than a
+    /// plain decimal number is an error, as there's no telling where the
+    /// message ends then.
+    pub fn content_length(&self) -> Result<Option<usize>> {
+        let value = match self.get("content-length") {
+            Some(value) => value,
+            None => return Ok(None),
+        };
+
+        let invalid = || Error::Header(format!("invalid Content-Length {:?}", String::from_utf8_lossy(value)));
+
+        let value = match str::from_utf8(value) {
+            Ok(value) => value.trim(),
+            Err(_) => return Err(invalid()),
+        };
+
+        // usize's parser would also take a leading +.
+        if value.is_empty() || !value.bytes().all(|b| b.is_ascii_digit()) {
+            return Err(invalid());
+        }
+
+        match value.parse() {
+            Ok(length) => Ok(Some(length)),
+            Err(_) => Err(invalid()),
         }
     }
 
@@ -136,6 +175,38 @@ impl<'a> Headers {
         }
     }
 
+    /// Every value given for `name`, in the order they were inserted.
+    pub fn get_all(&'a self, name: &str) -> Vec<&'a Vec<u8>> {
+        let name_lower = String::from(name).to_lowercase();
+        match self.data.get(&name_lower) {
+            Some(headers) => headers.iter().map(|header| header.value()).collect(),
+            None => Vec::new(),
+        }
+    }
+
+    /// Whether any of the comma-separated `name` headers contain `token`,
+    /// compared case-insensitively, e.g. `close` in `Connection: foo, close`.
+    pub fn contains_token(&self, name: &str, token: &str) -> bool {
+        self.get_all(name).iter().any(|value| {
+            match str::from_utf8(value) {
+                Ok(value) => value.split(',').any(|t| t.trim().eq_ignore_ascii_case(token)),
+                Err(_) => false,
+            }
+        })
+    }
+
+    /// Whether a message with these headers leaves its connection open
+    /// afterwards, as per RFC 7230 section 6.3. `version` is the minor HTTP
+    /// version of the message.
+    pub fn is_persistent(&self, version: u8) -> bool {
+        if self.contains_token("connection", "close") {
+            return false;
+        }
+
+        // HTTP/1.0 connections are only persistent if explicitly asked for.
+        version >= 1 || self.contains_token("connection", "keep-alive")
+    }
+
     pub fn insert(&mut self, name: &str, value: &Vec<u8>) {
         // Lowercase the header name for easier matching.
         let name_string = String::from(name);
@@ -152,18 +223,87 @@ impl<'a> Headers {
         self.total_count += 1;
     }
 
-    fn validate(&self) -> bool {
-        let host_ok = match self.data.get("host") {
-            Some(list) => list.len() <= 1,
-            None => true,
-        };
+    /// Remove every header called `name`, returning whether there were any.
+    pub fn remove(&mut self, name: &str) -> bool {
+        let name_lower = String::from(name).to_lowercase();
+        self.data.remove(&name_lower).is_some()
+    }
 
-        let length_ok = match self.data.get("content-length") {
-            Some(list) => list.len() <= 1,
-            None => true,
-        };
+    /// Replace every header that `other` also has with the values from
+    /// `other`, leaving the rest alone.
+    pub fn update(&mut self, other: &Headers) {
+        let mut replacements: Vec<&OctopusHeader> = other.data.values().flat_map(|headers| headers.iter()).collect();
+        replacements.sort_by_key(|header| header.order());
 
-        host_ok && length_ok
+        for name in other.data.keys() {
+            self.data.remove(name);
+        }
+
+        for header in replacements {
+            self.insert(header.original_name(), header.value());
+        }
+    }
+
+    /// Remove hop-by-hop headers, including any named by Connection, so the
+    /// rest can be forwarded.
+    pub fn strip_hop_by_hop(&mut self) {
+        let mut named = Vec::new();
+
+        for value in self.get_all("connection") {
+            if let Ok(value) = str::from_utf8(value) {
+                for token in value.split(',') {
+                    let token = token.trim().to_lowercase();
+                    if !token.is_empty() && !PROTECTED_HEADERS.contains(&&token[..]) {
+                        named.push(token);
+                    }
+                }
+            }
+        }
+
+        for name in HOP_BY_HOP_HEADERS {
+            self.remove(name);
+        }
+
+        for name in named {
+            self.remove(&name);
+        }
+    }
+
+    /// Append a Via entry for `pseudonym`, which received this message over
+    /// HTTP/1.`version`.
+    pub fn append_via(&mut self, version: u8, pseudonym: &str) {
+        let value = format!("1.{} {}", version, pseudonym);
+        self.insert("Via", &value.into_bytes());
+    }
+
+    /// Whether a Via entry names `pseudonym` as the recipient, i.e. the
+    /// message has already passed through it once.
+    pub fn via_contains(&self, pseudonym: &str) -> bool {
+        self.get_all("via").iter().any(|value| {
+            match str::from_utf8(value) {
+                Ok(value) => {
+                    value.split(',').any(|entry| {
+                        match entry.split_whitespace().nth(1) {
+                            Some(received_by) => received_by.eq_ignore_ascii_case(pseudonym),
+                            None => false,
+                        }
+                    })
+                },
+                Err(_) => false,
+            }
+        })
+    }
+
+    fn validate(&self) -> Result<()> {
+        if self.data.get("host").map_or(false, |list| list.len() > 1) {
+            return Err(Error::Header(String::from("more than one Host")));
+        }
+
+        if self.data.get("content-length").map_or(false, |list| list.len() > 1) {
+            return Err(Error::Header(String::from("more than one Content-Length")));
+        }
+
+        Ok(())
     }
 
     // Yields the UTF-8 version of the headers without a move.
@@ -260,6 +400,83 @@ mod tests {
         assert_eq!(headers.get("Most"), None);
     }
 
+    #[test]
+    fn test_contains_token() {
+        let mut headers = Headers::new();
+
+        headers.insert("Connection", &b"Keep-Alive, X-Foo".to_vec());
+        headers.insert("connection", &b"close".to_vec());
+
+        assert_eq!(headers.get_all("CONNECTION").len(), 2);
+        assert!(headers.contains_token("Connection", "keep-alive"));
+        assert!(headers.contains_token("Connection", "x-foo"));
+        assert!(headers.contains_token("Connection", "close"));
+        assert!(!headers.contains_token("Connection", "upgrade"));
+        assert!(!headers.contains_token("Upgrade", "close"));
+    }
+
+    #[test]
+    fn test_strip_hop_by_hop() {
+        let headers_buf = b"Host: foo.bar\r\nConnection: keep-alive, X-Secret, Content-Length\r\nKeep-Alive: timeout=5\r\nTE: trailers\r\nUpgrade: websocket\r\nProxy-Authorization: Basic Zm9vOmJhcg==\r\nX-Secret: hunter2\r\nContent-Length: 10\r\nAccept: *\r\n\r\n";
+        let mut headers = [httparse::EMPTY_HEADER; 16];
+        let (_, parsed) = httparse::parse_headers(headers_buf, &mut headers).unwrap().unwrap();
+
+        let mut headers = Headers::from_raw(parsed).unwrap();
+        headers.strip_hop_by_hop();
+
+        let buffer: Vec<u8> = headers.into();
+        assert_eq!(String::from_utf8(buffer).unwrap(), "Host: foo.bar\r\nContent-Length: 10\r\nAccept: *\r\n\r\n");
+    }
+
+    #[test]
+    fn test_via() {
+        let mut headers = Headers::new();
+
+        headers.insert("Via", &b"1.0 fred, 1.1 p.example.net (octopus)".to_vec());
+        assert!(!headers.via_contains("octopus"));
+        assert!(headers.via_contains("fred"));
+        assert!(headers.via_contains("P.example.NET"));
+
+        headers.append_via(1, "octopus");
+        assert!(headers.via_contains("octopus"));
+        assert_eq!(headers.get_all("via")[1], &b"1.1 octopus".to_vec());
+    }
+
+    #[test]
+    fn test_remove() {
+        let mut headers = Headers::new();
+
+        let value: Vec<u8> = "chunked".as_bytes().iter().cloned().collect();
+
+        headers.insert("Transfer-Encoding", &value);
+        headers.insert("transfer-encoding", &value);
+
+        assert!(headers.remove("TRANSFER-ENCODING"));
+        assert!(headers.get("Transfer-Encoding").is_none());
+        assert!(!headers.remove("Transfer-Encoding"));
+
+        let buffer: Vec<u8> = headers.into();
+        assert_eq!(buffer, b"\r\n");
+    }
+
+    #[test]
+    fn test_update() {
+        let mut headers = Headers::new();
+        headers.insert("ETag", &b"\"old\"".to_vec());
+        headers.insert("Content-Type", &b"text/plain".to_vec());
+
+        let mut other = Headers::new();
+        other.insert("etag", &b"\"new\"".to_vec());
+        other.insert("Expires", &b"never".to_vec());
+
+        headers.update(&other);
+
+        assert_eq!(headers.get("ETag").unwrap(), b"\"new\"");
+        assert_eq!(headers.get_all("etag").len(), 1);
+        assert_eq!(headers.get("Content-Type").unwrap(), b"text/plain");
+        assert_eq!(headers.get("Expires").unwrap(), b"never");
+    }
+
     #[test]
     fn test_multiple_content_length() {
         let mut headers = Headers::new();
@@ -270,7 +487,30 @@ mod tests {
         headers.insert("Content-Length", &value1);
         headers.insert("Content-Length", &value2);
 
-        assert_eq!(headers.content_length(), Some(1234));
+        assert_eq!(headers.content_length().unwrap(), Some(1234));
+    }
+
+    #[test]
+    fn test_invalid_content_length() {
+        for value in &[&b"abc"[..], b"-1", b"+5", b"", b"99999999999999999999999", b"\xff"] {
+            let mut headers = Headers::new();
+            headers.insert("Content-Length", &value.to_vec());
+            assert!(headers.content_length().is_err());
+        }
+
+        let mut headers = Headers::new();
+        headers.insert("Content-Length", &b" 42 ".to_vec());
+        assert_eq!(headers.content_length().unwrap(), Some(42));
+    }
+
+    #[test]
+    fn test_non_utf8_value() {
+        let mut headers = Headers::new();
+        headers.insert("X-Binary", &vec![0xff, 0xfe, b'a']);
+        assert_eq!(headers.get("X-Binary").unwrap(), &vec![0xff, 0xfe, b'a']);
+
+        let buffer: Vec<u8> = headers.into();
+        assert_eq!(buffer, b"X-Binary: \xff\xfea\r\n\r\n".to_vec());
     }
 
     #[test]
@@ -2,6 +2,72 @@ pub mod headers;
 
 pub mod request;
 pub mod reply;
+pub mod body;
+pub mod buffered;
+pub mod chunked;
+pub mod forwarded;
+pub mod pages;
+pub mod range;
 
+pub mod admin;
 pub mod client;
 pub mod server;
+pub mod timeout;
+pub mod tunnel;
+
+#[cfg(test)]
+pub mod test_support;
+
+use std::io::{self, Read};
+
+/// Read from the given stream into the given buffer.
+/// Interally this will perform a read for up to 65536 bytes of data, and
+/// append it to the end of the given buffer.
+pub fn read_into_buffer<R: Read>(stream: &mut R, buffer: &mut Vec<u8>) -> io::Result<usize> {
+
+    // XXX: it would be nice to benchmark how this compares to reading directly
+    // into the given buffer.
+
+    // We do it this way to ensure we only put data into `buffer` that was
+    // actually read from the stream, and not accidentally leave the buffer
+    // filled with nulls from a resize.
+
+    let mut read_buf = vec![0; 65536];
+    match stream.read(&mut read_buf) {
+        Ok(n) if n > 0 => {
+            unsafe {
+                read_buf.set_len(n);
+            }
+            buffer.append(&mut read_buf);
+            Ok(n)
+        }
+        r => r,
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::read_into_buffer;
+
+    #[test]
+    fn test_read_into_buffer() {
+        let mut s = Cursor::new("Hello world");
+        let mut buf = Vec::with_capacity(5);
+
+        assert_eq!(11, read_into_buffer(&mut s, &mut buf).unwrap());
+        assert_eq!(&buf[..], b"Hello world");
+
+        let mut s = Cursor::new("!");
+
+        assert_eq!(1, read_into_buffer(&mut s, &mut buf).unwrap());
+
+        assert_eq!(&buf, b"Hello world!");
+
+        assert_eq!(0, read_into_buffer(&mut s, &mut buf).unwrap());
+
+        assert_eq!(&buf, b"Hello world!");
+    }
+}
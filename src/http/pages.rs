@@ -0,0 +1,248 @@
+use std::io::{self, Write};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use cache::freshness::format_date;
+use error::Error;
+
+use super::request::Request;
+
+pub const DEFAULT_HTML_TEMPLATE: &'static str = "<!DOCTYPE html>
+<html>
+<head><title>{code} {reason}</title></head>
+<body>
+<h1>{code} {reason}</h1>
+<p>{message}</p>
+<hr>
+<p><small>{url}<br>Request {request_id} at {timestamp}</small></p>
+</body>
+</html>
+";
+
+pub const DEFAULT_TEXT_TEMPLATE: &'static str = "{code} {reason}
+
+{message}
+
+{url}
+Request {request_id} at {timestamp}
+";
+
+static NEXT_REQUEST_ID: AtomicUsize = AtomicUsize::new(0);
+
+/// The templates pages generated by the proxy itself are made from.
+///
+/// `{code}`, `{reason}`, `{message}`, `{url}`, `{request_id}` and
+/// `{timestamp}` are replaced with the details of the page. They're escaped
+/// in the HTML template.
+#[derive(Debug, Clone)]
+pub struct Templates {
+    pub html: String,
+    pub text: String,
+}
+
+impl Default for Templates {
+    fn default() -> Templates {
+        Templates {
+            html: String::from(DEFAULT_HTML_TEMPLATE),
+            text: String::from(DEFAULT_TEXT_TEMPLATE),
+        }
+    }
+}
+
+/// A reply the proxy makes up itself to say why a request failed.
+#[derive(Debug, Clone)]
+pub struct ErrorPage {
+    pub code: u16,
+    pub reason: &'static str,
+    pub message: String,
+    /// Identifies this particular failure, so it can be found in the logs.
+    pub request_id: String,
+    pub timestamp: SystemTime,
+}
+
+impl ErrorPage {
+    pub fn new(code: u16, reason: &'static str, message: &str) -> ErrorPage {
+        ErrorPage {
+            code: code,
+            reason: reason,
+            message: String::from(message),
+            request_id: next_request_id(),
+            timestamp: SystemTime::now(),
+        }
+    }
+
+    /// The page for `error`.
+    ///
+    /// Failures on our side or upstream are described only in the log, under
+    /// the page's request ID, rather than showing clients internal details
+    /// like addresses or resolver messages.
+    pub fn for_error(error: &Error) -> ErrorPage {
+        let (code, reason) = error.status();
+
+        if code >= 500 {
+            ErrorPage::new(code, reason, "The request could not be completed. Quote the request ID below when reporting this.")
+        } else {
+            ErrorPage::new(code, reason, &error.to_string())
+        }
+    }
+
+    /// Fill in `templates` for `request`. HTML is given to clients that say
+    /// they accept it, and plain text to anything else.
+    ///
+    /// Returns the content type and the body.
+    pub fn render(&self, templates: &Templates, request: Option<&Request>) -> (&'static str, String) {
+        let url = request.map_or(String::new(), |request| request.url.to_string());
+        let html = request.map_or(false, |request| request.headers.contains_token("accept", "text/html"));
+
+        let (content_type, template) = if html {
+            ("text/html; charset=utf-8", &templates.html)
+        } else {
+            ("text/plain; charset=utf-8", &templates.text)
+        };
+
+        let escape = |s: &str| if html { escape_html(s) } else { String::from(s) };
+
+        let body = template
+            .replace("{code}", &self.code.to_string())
+            .replace("{reason}", &escape(self.reason))
+            .replace("{message}", &escape(&self.message))
+            .replace("{url}", &escape(&url))
+            .replace("{request_id}", &escape(&self.request_id))
+            .replace("{timestamp}", &format_date(self.timestamp));
+
+        (content_type, body)
+    }
+
+    /// Answer `request` with this page. `request` is None if the client's
+    /// request couldn't even be parsed.
+    ///
+    /// The connection is closed afterwards unless `keep_alive` is set and the
+    /// client wants it kept open. Returns whether it can be used for another
+    /// request.
+    pub fn send<W: Write>(&self, stream: &mut W, templates: &Templates, request: Option<&Request>, keep_alive: bool) -> io::Result<bool> {
+        let keep_alive = keep_alive && request.map_or(false, |request| request.keep_alive());
+        let (content_type, body) = self.render(templates, request);
+
+        let mut head = format!("HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nDate: {}\r\nCache-Control: no-store\r\nX-Request-Id: {}\r\n",
+                               self.code, self.reason, content_type, body.len(), format_date(self.timestamp), self.request_id);
+        if !keep_alive {
+            head.push_str("Connection: close\r\n");
+        } else if request.map_or(false, |request| request.version == 0) {
+            head.push_str("Connection: keep-alive\r\n");
+        }
+        head.push_str("\r\n");
+
+        try!(stream.write_all(head.as_bytes()));
+        if request.map_or(true, |request| request.method != "HEAD") {
+            try!(stream.write_all(body.as_bytes()));
+        }
+
+        Ok(keep_alive)
+    }
+}
+
+/// Answer with the page for `error` and log it, closing the connection
+/// afterwards.
+pub fn send_error<W: Write>(stream: &mut W, templates: &Templates, request: Option<&Request>, error: &Error) -> io::Result<()> {
+    let page = ErrorPage::for_error(error);
+    info!("Request {} failed with {}: {}", page.request_id, page.code, error);
+    try!(page.send(stream, templates, request, false));
+    Ok(())
+}
+
+/// A new ID, unique to this process, for identifying a request.
+pub fn next_request_id() -> String {
+    let count = NEXT_REQUEST_ID.fetch_add(1, Ordering::Relaxed);
+    let secs = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+    format!("{:x}-{:x}", secs, count)
+}
+
+fn escape_html(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+
+    for c in s.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str;
+
+    use error::Error;
+    use http::test_support::request;
+
+    use super::{next_request_id, send_error, ErrorPage, Templates};
+
+    #[test]
+    fn test_render() {
+        let templates = Templates::default();
+        let page = ErrorPage::new(502, "Bad Gateway", "<oops>");
+
+        let req = request("GET http://example.com/?a<b HTTP/1.1\r\nAccept: text/html, */*\r\n\r\n");
+        let (content_type, body) = page.render(&templates, Some(&req));
+        assert!(content_type.starts_with("text/html"));
+        assert!(body.contains("<h1>502 Bad Gateway</h1>"));
+        assert!(body.contains("&lt;oops&gt;"));
+        assert!(body.contains(&format!("Request {} at ", page.request_id)));
+        assert!(!body.contains("{"));
+
+        let req = request("GET http://example.com/ HTTP/1.1\r\n\r\n");
+        let (content_type, body) = page.render(&templates, Some(&req));
+        assert!(content_type.starts_with("text/plain"));
+        assert!(body.starts_with("502 Bad Gateway\n\n<oops>\n\nhttp://example.com/\n"));
+
+        let custom = Templates {
+            html: String::new(),
+            text: String::from("{code}|{message}|{url}"),
+        };
+        assert_eq!(page.render(&custom, None).1, "502|<oops>|");
+    }
+
+    #[test]
+    fn test_send() {
+        let templates = Templates::default();
+
+        let mut out = Vec::new();
+        send_error(&mut out, &templates, None, &Error::Parse(String::from("garbage"))).unwrap();
+        let out = str::from_utf8(&out).unwrap();
+        assert!(out.starts_with("HTTP/1.1 400 Bad Request\r\n"));
+        assert!(out.contains("\r\nConnection: close\r\n"));
+        assert!(out.contains("\r\nX-Request-Id: "));
+        assert!(out.contains("\r\n\r\n400 Bad Request\n\nparse error: garbage\n\n\nRequest "));
+
+        let mut out = Vec::new();
+        send_error(&mut out, &templates, None, &Error::Connect(String::from("10.0.0.1:80: connection refused"))).unwrap();
+        let out = str::from_utf8(&out).unwrap();
+        assert!(out.starts_with("HTTP/1.1 502 Bad Gateway\r\n"));
+        assert!(!out.contains("10.0.0.1"));
+
+        let page = ErrorPage::new(504, "Gateway Timeout", "Not cached");
+
+        let mut out = Vec::new();
+        let req = request("HEAD http://example.com/ HTTP/1.1\r\n\r\n");
+        assert!(page.send(&mut out, &templates, Some(&req), true).unwrap());
+        let out = str::from_utf8(&out).unwrap();
+        assert!(out.starts_with("HTTP/1.1 504 Gateway Timeout\r\n"));
+        assert!(!out.contains("Connection:"));
+        assert!(out.ends_with("\r\n\r\n"));
+
+        let mut out = Vec::new();
+        let req = request("GET http://example.com/ HTTP/1.0\r\n\r\n");
+        assert!(!page.send(&mut out, &templates, Some(&req), true).unwrap());
+    }
+
+    #[test]
+    fn test_request_ids_differ() {
+        assert!(next_request_id() != next_request_id());
+    }
+}
@@ -0,0 +1,335 @@
+use std::str;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use cache::freshness::parse_date;
+
+use super::headers::Headers;
+use super::reply::Reply;
+
+// Requests for more ranges than this are answered with the whole thing, as
+// they're more likely to be an attack than anything useful.
+const MAX_RANGES: usize = 32;
+
+static BOUNDARIES: AtomicUsize = AtomicUsize::new(0);
+
+/// One range of a byte range request, as per RFC 7233 section 2.1.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ByteRange {
+    /// `first-last`, inclusive.
+    FromTo(u64, u64),
+    /// `first-`, to the end.
+    From(u64),
+    /// `-length`, the last `length` bytes.
+    Suffix(u64),
+}
+
+impl ByteRange {
+    /// The inclusive offsets this range covers in something `length` bytes
+    /// long, or None if it doesn't overlap it at all.
+    pub fn resolve(&self, length: u64) -> Option<(u64, u64)> {
+        match *self {
+            ByteRange::FromTo(first, last) if first < length => {
+                Some((first, if last < length { last } else { length - 1 }))
+            },
+            ByteRange::From(first) if first < length => Some((first, length - 1)),
+            ByteRange::Suffix(suffix) if suffix > 0 && length > 0 => {
+                Some((if suffix < length { length - suffix } else { 0 }, length - 1))
+            },
+            _ => None,
+        }
+    }
+}
+
+/// The byte ranges asked for by the Range header in `headers`.
+///
+/// Returns None if there isn't one, or if it's invalid or uses some other
+/// unit, in which case it's to be ignored.
+pub fn parse_range(headers: &Headers) -> Option<Vec<ByteRange>> {
+    let value = match headers.get("range").and_then(|value| str::from_utf8(value).ok()) {
+        Some(value) => value.trim(),
+        None => return None,
+    };
+
+    let eq = match value.find('=') {
+        Some(eq) => eq,
+        None => return None,
+    };
+
+    if !value[..eq].trim().eq_ignore_ascii_case("bytes") {
+        return None;
+    }
+
+    let mut ranges = Vec::new();
+
+    for spec in value[eq + 1..].split(',') {
+        let spec = spec.trim();
+        if spec.is_empty() {
+            continue;
+        }
+
+        let dash = match spec.find('-') {
+            Some(dash) => dash,
+            None => return None,
+        };
+
+        let (first, last) = (spec[..dash].trim(), spec[dash + 1..].trim());
+
+        let range = if first.is_empty() {
+            match last.parse() {
+                Ok(suffix) => ByteRange::Suffix(suffix),
+                Err(_) => return None,
+            }
+        } else {
+            let first = match first.parse() {
+                Ok(first) => first,
+                Err(_) => return None,
+            };
+
+            if last.is_empty() {
+                ByteRange::From(first)
+            } else {
+                match last.parse() {
+                    Ok(last) if last >= first => ByteRange::FromTo(first, last),
+                    _ => return None,
+                }
+            }
+        };
+
+        ranges.push(range);
+    }
+
+    if ranges.is_empty() || ranges.len() > MAX_RANGES {
+        None
+    } else {
+        Some(ranges)
+    }
+}
+
+/// Whether the If-Range condition in `request_headers`, if any, holds for
+/// `reply`, as per RFC 7233 section 3.2. The Range header is to be ignored
+/// if not.
+pub fn if_range_matches(request_headers: &Headers, reply: &Reply) -> bool {
+    let condition = match request_headers.get("if-range").and_then(|value| str::from_utf8(value).ok()) {
+        Some(condition) => condition.trim(),
+        None => return true,
+    };
+
+    if condition.starts_with('"') || condition.starts_with("W/") {
+        // Entity-tags are compared strongly, so weak ones never match.
+        match reply.headers.get("etag").and_then(|etag| str::from_utf8(etag).ok()) {
+            Some(etag) => !condition.starts_with("W/") && !etag.trim().starts_with("W/") && etag.trim() == condition,
+            None => false,
+        }
+    } else {
+        match (parse_date(request_headers, "if-range"), parse_date(&reply.headers, "last-modified")) {
+            (Some(since), Some(modified)) => since == modified,
+            _ => false,
+        }
+    }
+}
+
+/// The result of applying a Range header to a full reply.
+#[derive(Debug)]
+pub enum Ranged {
+    /// The whole reply is to be sent as is.
+    Full,
+    /// A 206 Partial Content reply, and its body.
+    Partial(Reply, Vec<u8>),
+    /// A 416 Range Not Satisfiable reply, which has no body.
+    Unsatisfiable(Reply),
+}
+
+/// Answer the Range and If-Range headers in `request_headers` from `reply`,
+/// a 200 OK whose body is `body`.
+pub fn apply(request_headers: &Headers, reply: &Reply, body: &[u8]) -> Ranged {
+    if reply.code != 200 {
+        return Ranged::Full;
+    }
+
+    let ranges = match parse_range(request_headers) {
+        Some(ranges) => ranges,
+        None => return Ranged::Full,
+    };
+
+    if !if_range_matches(request_headers, reply) {
+        return Ranged::Full;
+    }
+
+    let length = body.len() as u64;
+    let resolved: Vec<(u64, u64)> = ranges.iter().filter_map(|range| range.resolve(length)).collect();
+
+    let mut partial = reply.clone();
+    partial.headers.remove("content-length");
+    partial.headers.remove("transfer-encoding");
+
+    if resolved.is_empty() {
+        partial.code = 416;
+        partial.reason = String::from("Range Not Satisfiable");
+        partial.headers.remove("content-type");
+        partial.headers.insert("Content-Range", &format!("bytes */{}", length).into_bytes());
+        partial.headers.insert("Content-Length", &b"0".to_vec());
+        return Ranged::Unsatisfiable(partial);
+    }
+
+    partial.code = 206;
+    partial.reason = String::from("Partial Content");
+
+    let content = if resolved.len() == 1 {
+        let (first, last) = resolved[0];
+        partial.headers.insert("Content-Range", &format!("bytes {}-{}/{}", first, last, length).into_bytes());
+        body[first as usize..last as usize + 1].to_vec()
+    } else {
+        let content_type = partial.headers.get("content-type").cloned();
+        let boundary = boundary();
+
+        let mut content = Vec::new();
+        for &(first, last) in &resolved {
+            content.extend(format!("\r\n--{}\r\n", boundary).as_bytes());
+            if let Some(ref content_type) = content_type {
+                content.extend(b"Content-Type: ");
+                content.extend(content_type);
+                content.extend(b"\r\n");
+            }
+            content.extend(format!("Content-Range: bytes {}-{}/{}\r\n\r\n", first, last, length).as_bytes());
+            content.extend(&body[first as usize..last as usize + 1]);
+        }
+        content.extend(format!("\r\n--{}--\r\n", boundary).as_bytes());
+
+        partial.headers.remove("content-type");
+        partial.headers.insert("Content-Type", &format!("multipart/byteranges; boundary={}", boundary).into_bytes());
+        content
+    };
+
+    partial.headers.insert("Content-Length", &content.len().to_string().into_bytes());
+    Ranged::Partial(partial, content)
+}
+
+/// A multipart boundary that's vanishingly unlikely to turn up in a body.
+fn boundary() -> String {
+    let count = BOUNDARIES.fetch_add(1, Ordering::Relaxed);
+    let nanos = match SystemTime::now().duration_since(UNIX_EPOCH) {
+        Ok(since) => since.as_secs() * 1000000000 + since.subsec_nanos() as u64,
+        Err(_) => 0,
+    };
+
+    format!("octopus-{:016x}-{:x}", nanos, count)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str;
+
+    use http::headers::Headers;
+    use http::reply::Reply;
+
+    use super::{apply, if_range_matches, parse_range, ByteRange, Ranged};
+
+    fn headers(headers: &[(&str, &str)]) -> Headers {
+        let mut h = Headers::new();
+        for &(name, value) in headers {
+            h.insert(name, &value.as_bytes().to_vec());
+        }
+        h
+    }
+
+    fn reply() -> Reply {
+        Reply {
+            version: 1,
+            code: 200,
+            reason: String::from("OK"),
+            headers: headers(&[("ETag", "\"v1\""), ("Last-Modified", "Sat, 01 Jan 2000 00:00:00 GMT"),
+                               ("Content-Type", "text/plain"), ("Content-Length", "10")]),
+        }
+    }
+
+    fn range(value: &str) -> Option<Vec<ByteRange>> {
+        parse_range(&headers(&[("Range", value)]))
+    }
+
+    #[test]
+    fn test_parse_range() {
+        assert_eq!(range("bytes=0-499"), Some(vec![ByteRange::FromTo(0, 499)]));
+        assert_eq!(range("bytes=500-, -200 , 1-1"), Some(vec![ByteRange::From(500), ByteRange::Suffix(200), ByteRange::FromTo(1, 1)]));
+        assert_eq!(range("Bytes = 1-2"), Some(vec![ByteRange::FromTo(1, 2)]));
+
+        assert_eq!(range("bytes=5-4"), None);
+        assert_eq!(range("bytes=a-b"), None);
+        assert_eq!(range("bytes="), None);
+        assert_eq!(range("items=0-1"), None);
+        assert_eq!(parse_range(&headers(&[])), None);
+    }
+
+    #[test]
+    fn test_resolve() {
+        assert_eq!(ByteRange::FromTo(2, 5).resolve(10), Some((2, 5)));
+        assert_eq!(ByteRange::FromTo(2, 50).resolve(10), Some((2, 9)));
+        assert_eq!(ByteRange::FromTo(10, 50).resolve(10), None);
+        assert_eq!(ByteRange::From(7).resolve(10), Some((7, 9)));
+        assert_eq!(ByteRange::Suffix(3).resolve(10), Some((7, 9)));
+        assert_eq!(ByteRange::Suffix(30).resolve(10), Some((0, 9)));
+        assert_eq!(ByteRange::Suffix(0).resolve(10), None);
+        assert_eq!(ByteRange::Suffix(3).resolve(0), None);
+    }
+
+    #[test]
+    fn test_if_range() {
+        assert!(if_range_matches(&headers(&[]), &reply()));
+        assert!(if_range_matches(&headers(&[("If-Range", "\"v1\"")]), &reply()));
+        assert!(!if_range_matches(&headers(&[("If-Range", "\"v2\"")]), &reply()));
+        assert!(!if_range_matches(&headers(&[("If-Range", "W/\"v1\"")]), &reply()));
+        assert!(if_range_matches(&headers(&[("If-Range", "Sat, 01 Jan 2000 00:00:00 GMT")]), &reply()));
+        assert!(!if_range_matches(&headers(&[("If-Range", "Sun, 02 Jan 2000 00:00:00 GMT")]), &reply()));
+    }
+
+    #[test]
+    fn test_single_range() {
+        match apply(&headers(&[("Range", "bytes=2-4")]), &reply(), b"0123456789") {
+            Ranged::Partial(partial, body) => {
+                assert_eq!(partial.code, 206);
+                assert_eq!(partial.headers.get("content-range").unwrap(), b"bytes 2-4/10");
+                assert_eq!(partial.headers.get("content-length").unwrap(), b"3");
+                assert_eq!(partial.headers.get("content-type").unwrap(), b"text/plain");
+                assert_eq!(body, b"234");
+            },
+            other => panic!("expected a partial reply, got {:?}", other),
+        }
+
+        // A failed If-Range means the whole thing.
+        assert!(match apply(&headers(&[("Range", "bytes=2-4"), ("If-Range", "\"v0\"")]), &reply(), b"0123456789") {
+            Ranged::Full => true,
+            _ => false,
+        });
+    }
+
+    #[test]
+    fn test_multiple_ranges() {
+        match apply(&headers(&[("Range", "bytes=0-1, -2")]), &reply(), b"0123456789") {
+            Ranged::Partial(partial, body) => {
+                let content_type = str::from_utf8(partial.headers.get("content-type").unwrap()).unwrap().to_string();
+                assert!(content_type.starts_with("multipart/byteranges; boundary="));
+                let boundary = &content_type["multipart/byteranges; boundary=".len()..];
+
+                let expected = format!("\r\n--{b}\r\nContent-Type: text/plain\r\nContent-Range: bytes 0-1/10\r\n\r\n01\
+                                        \r\n--{b}\r\nContent-Type: text/plain\r\nContent-Range: bytes 8-9/10\r\n\r\n89\
+                                        \r\n--{b}--\r\n", b = boundary);
+                assert_eq!(str::from_utf8(&body).unwrap(), expected);
+                assert_eq!(partial.headers.get("content-length").unwrap(), &body.len().to_string().into_bytes());
+                assert!(partial.headers.get("content-range").is_none());
+            },
+            other => panic!("expected a partial reply, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_unsatisfiable() {
+        match apply(&headers(&[("Range", "bytes=20-30")]), &reply(), b"0123456789") {
+            Ranged::Unsatisfiable(reply) => {
+                assert_eq!(reply.code, 416);
+                assert_eq!(reply.headers.get("content-range").unwrap(), b"bytes */10");
+                assert_eq!(reply.headers.get("content-length").unwrap(), b"0");
+            },
+            other => panic!("expected an unsatisfiable reply, got {:?}", other),
+        }
+    }
+}
@@ -1,8 +1,10 @@
 extern crate httparse;
 
+use error::{Error, Result};
+
 use super::headers::Headers;
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Reply {
     pub version: u8,
     pub code: u16,
@@ -10,15 +12,140 @@ pub struct Reply {
     pub headers: Headers,
 }
 
+/// Attempt to parse a Reply object from a given buffer.
+///
+/// The returned Vec<u8> contains any leftover data from the buffer that was
+/// not parsed as the reply, i.e. you should treat it as the beginning of the
+/// reply body.
+pub fn parse<'a>(buffer: &'a Vec<u8>, mut headers: &mut [httparse::Header<'a>], total_read: usize) -> Result<Option<(Reply, Vec<u8>)>> {
+    let mut response = httparse::Response::new(&mut headers);
+
+    let res = match response.parse(&buffer) {
+        Ok(res) => res,
+        Err(e) => {
+            return Err(Error::Parse(format!("{:?}", e)));
+        },
+    };
+
+    match res {
+        httparse::Status::Complete(n) => {
+            let body = buffer[n..total_read].iter().cloned().collect();
+
+            match Reply::from_raw(response) {
+                Ok(reply) => {
+                    return Ok(Some((reply, body)));
+                },
+                Err(e) => {
+                    return Err(e);
+                }
+            }
+        },
+        httparse::Status::Partial => {
+            Ok(None)
+        }
+    }
+}
+
+impl Into<Vec<u8>> for Reply {
+    fn into(self) -> Vec<u8> {
+        let mut out = Vec::<u8>::with_capacity(65536);
+
+        let statusline = format!("HTTP/1.{} {} {}\r\n", self.version, self.code, self.reason);
+        out.extend(statusline.as_bytes());
+        let headers: Vec<u8> = self.headers.into();
+        out.extend(headers);
+        out
+    }
+}
+
 impl Reply {
-    pub fn from_raw(response: httparse::Response) -> Reply {
-        let headers = Headers::from_raw(response.headers).unwrap();
-
-        Reply {
-            version: response.version.unwrap(),
-            code: response.code.unwrap(),
-            reason: String::from(response.reason.unwrap()),
-            headers: headers,
+    pub fn from_raw(response: httparse::Response) -> Result<Reply> {
+        let headers = try!(Headers::from_raw(response.headers));
+
+        // These are always there once httparse has a complete reply.
+        match (response.version, response.code, response.reason) {
+            (Some(version), Some(code), Some(reason)) => {
+                Ok(Reply {
+                    version: version,
+                    code: code,
+                    reason: String::from(reason),
+                    headers: headers,
+                })
+            },
+            _ => Err(Error::Parse(String::from("incomplete status line"))),
         }
     }
+
+    /// Whether the origin is willing to keep the connection open after this
+    /// reply.
+    pub fn keep_alive(&self) -> bool {
+        self.headers.is_persistent(self.version)
+    }
+
+    /// Whether this is an interim (1xx) reply that will be followed by
+    /// another reply for the same request.
+    ///
+    /// 101 Switching Protocols is deliberately excluded, as nothing follows it
+    /// on the same protocol.
+    pub fn is_interim(&self) -> bool {
+        self.code >= 100 && self.code < 200 && self.code != 101
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate httparse;
+
+    #[test]
+    fn test_parse() {
+        use super::parse;
+
+        let buf = b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\nServer: foo\r\n\r\nHello".to_vec();
+        let mut headers = [httparse::EMPTY_HEADER; 16];
+        let total_read = buf.len();
+
+        let (reply, body) = parse(&buf, &mut headers, total_read).unwrap().unwrap();
+
+        assert_eq!(reply.version, 1u8);
+        assert_eq!(reply.code, 200);
+        assert_eq!(reply.reason, "OK");
+        assert_eq!(reply.headers.content_length().unwrap(), Some(5));
+        assert_eq!(body, b"Hello");
+    }
+
+    #[test]
+    fn test_parse_partial() {
+        use super::parse;
+
+        let buf = b"HTTP/1.1 200 OK\r\nContent-Len".to_vec();
+        let mut headers = [httparse::EMPTY_HEADER; 16];
+        let total_read = buf.len();
+
+        assert!(parse(&buf, &mut headers, total_read).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_parse_on_nonhttp() {
+        use super::parse;
+
+        let buf = b"frozen brains tell no tales\r\n".to_vec();
+        let mut headers = [httparse::EMPTY_HEADER; 16];
+        let total_read = buf.len();
+
+        assert!(parse(&buf, &mut headers, total_read).is_err());
+    }
+
+    #[test]
+    fn test_into() {
+        use super::parse;
+
+        let buf = b"HTTP/1.0 404 Not Found\r\nContent-Length: 0\r\n\r\n".to_vec();
+        let mut headers = [httparse::EMPTY_HEADER; 16];
+        let total_read = buf.len();
+
+        let (reply, _) = parse(&buf, &mut headers, total_read).unwrap().unwrap();
+        let serialized: Vec<u8> = reply.into();
+
+        assert_eq!(String::from_utf8(serialized).unwrap(), String::from_utf8(buf).unwrap());
+    }
 }
@@ -1,11 +1,14 @@
 extern crate httparse;
 extern crate url;
 
+use std::cmp;
 use std::str;
 
+use error::{Error, Result};
+
 use super::headers::Headers;
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Request {
     pub method: String,
     pub url: url::Url,
@@ -18,14 +21,16 @@ pub struct Request {
 /// The returned Vec<u8> contains any leftover data from the buffer that was
 /// not parsed as the request, i.e. you should treat it as the beginning of the
 /// request body.
-pub fn parse<'a>(buffer: &'a Vec<u8>, mut headers: &mut [httparse::Header<'a>], total_read: usize) -> Result<Option<(Request, Vec<u8>)>, String> {
+pub fn parse<'a>(buffer: &'a Vec<u8>, mut headers: &mut [httparse::Header<'a>], total_read: usize) -> Result<Option<(Request, Vec<u8>)>> {
     let mut request = httparse::Request::new(&mut headers);
 
     let res = match request.parse(&buffer) {
         Ok(res) => res,
+        Err(httparse::Error::TooManyHeaders) => {
+            return Err(Error::HeadersTooLarge(format!("more than {} headers", request.headers.len())));
+        },
         Err(e) => {
-            let error = format!("{:?}", e);
-            return Err(error);
+            return Err(Error::Parse(format!("{:?}", e)));
         },
     };
 
@@ -48,66 +53,158 @@ pub fn parse<'a>(buffer: &'a Vec<u8>, mut headers: &mut [httparse::Header<'a>],
     }
 }
 
+// How many headers there's room for to begin with. Most requests have fewer.
+const INITIAL_HEADERS: usize = 32;
+
+/// Like `parse`, but with room for as many as `max_headers` headers, and
+/// refusing heads bigger than `max_bytes`, complete or not.
+pub fn parse_limited(buffer: &Vec<u8>, total_read: usize, max_headers: usize, max_bytes: usize) -> Result<Option<(Request, Vec<u8>)>> {
+    let mut room = cmp::min(INITIAL_HEADERS, max_headers);
+
+    loop {
+        let mut headers = vec![httparse::EMPTY_HEADER; room];
+
+        match parse(buffer, &mut headers, total_read) {
+            Ok(Some((request, body))) => {
+                if total_read - body.len() > max_bytes {
+                    return Err(Error::HeadersTooLarge(format!("request head is over {} bytes", max_bytes)));
+                }
+                return Ok(Some((request, body)));
+            },
+            Ok(None) => {
+                if total_read > max_bytes {
+                    return Err(Error::HeadersTooLarge(format!("request head is over {} bytes", max_bytes)));
+                }
+                return Ok(None);
+            },
+            Err(Error::HeadersTooLarge(_)) if room < max_headers => {
+                room = cmp::min(room * 2, max_headers);
+            },
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Parse an authority-form request-target, e.g. `example.com:443`, as used by
+/// CONNECT.
+///
+/// The port is mandatory. The result is represented as a URL with no path so
+/// the destination can be connected to like any other request's; use
+/// `port_or_known_default()` to get the port back out.
+pub fn parse_authority(target: &str) -> Result<url::Url> {
+    let invalid = || Err(Error::Parse(format!("Invalid CONNECT target {}", target)));
+
+    if target.contains(|c| c == '/' || c == '?' || c == '#' || c == '@') {
+        return invalid();
+    }
+
+    let (host, port) = match target.rfind(':') {
+        Some(i) => (&target[..i], &target[i + 1..]),
+        None => return invalid(),
+    };
+
+    if host.is_empty() || (host.starts_with('[') != host.ends_with(']')) {
+        return invalid();
+    }
+
+    if port.parse::<u16>().is_err() {
+        return invalid();
+    }
+
+    match url::Url::parse(&format!("https://{}/", target)) {
+        Ok(url) => Ok(url),
+        Err(_) => invalid(),
+    }
+}
+
 impl Into<Vec<u8>> for Request {
     fn into(self) -> Vec<u8> {
+        self.serialize(false)
+    }
+}
+
+impl Request {
+    /// The request as it's sent upstream. The request-target is in absolute
+    /// form, i.e. the whole URL, if `absolute_form` is set, as when sending
+    /// to another proxy, and just the path and query otherwise.
+    pub fn serialize(self, absolute_form: bool) -> Vec<u8> {
         let mut out = Vec::<u8>::with_capacity(65536);
 
-        let reqline = format!("{} {} HTTP/1.{}\r\n", self.method, self.url.path(), self.version);
+        let start = if absolute_form { url::Position::BeforeScheme } else { url::Position::BeforePath };
+        let target = &self.url[start..url::Position::AfterQuery];
+        let reqline = format!("{} {} HTTP/1.{}\r\n", self.method, target, self.version);
         out.extend(reqline.as_bytes());
         let headers: Vec<u8> = self.headers.into();
         out.extend(headers);
-        out.extend(b"\r\n");
-        println!("Returning {:?}", out);
+        debug!("Returning {:?}", out);
         out
     }
-}
 
-impl Request {
-    pub fn from_raw(request: httparse::Request) -> Result<Request, String> {
-        let headers = Headers::from_raw(request.headers).unwrap();
-
-        let url = match url::Url::parse(&request.path.unwrap()) {
-            Ok(url) => url,
-            Err(url::ParseError::RelativeUrlWithoutBase) => {
-                let mut absolute_url = Vec::new();
-
-                // FIXME: from the listening port, tell if it's secure or not for
-                // the correct scheme.
-                let secure = false;
-                if secure {
-                    absolute_url.extend("https://".as_bytes());
-                } else {
-                    absolute_url.extend("http://".as_bytes());
-                }
+    /// Whether the client wants the connection kept open after this request.
+    pub fn keep_alive(&self) -> bool {
+        self.headers.is_persistent(self.version)
+    }
+
+    pub fn from_raw(request: httparse::Request) -> Result<Request> {
+        let headers = try!(Headers::from_raw(request.headers));
+
+        // These are always there once httparse has a complete request.
+        let (method, path, version) = match (request.method, request.path, request.version) {
+            (Some(method), Some(path), Some(version)) => (method, path, version),
+            _ => return Err(Error::Parse(String::from("incomplete request line"))),
+        };
 
-                match headers.get("Host") {
-                    Some(host) => absolute_url.extend(host),
-                    None => {
-                        return Err("Host header missing".to_owned());
+        let url = if method == "CONNECT" {
+            try!(parse_authority(path))
+        } else {
+            match url::Url::parse(path) {
+                Ok(url) => url,
+                Err(url::ParseError::RelativeUrlWithoutBase) => {
+                    let mut absolute_url = Vec::new();
+
+                    // FIXME: from the listening port, tell if it's secure or not for
+                    // the correct scheme.
+                    let secure = false;
+                    if secure {
+                        absolute_url.extend("https://".as_bytes());
+                    } else {
+                        absolute_url.extend("http://".as_bytes());
                     }
-                }
 
-                absolute_url.extend(request.path.unwrap().as_bytes());
+                    match headers.get("Host") {
+                        Some(host) => absolute_url.extend(host),
+                        None => {
+                            return Err(Error::Header(String::from("Host header missing")));
+                        }
+                    }
 
-                let absolute_url = str::from_utf8(&absolute_url).unwrap();
+                    absolute_url.extend(path.as_bytes());
 
-                match url::Url::parse(&absolute_url) {
-                    Ok(url) => url,
-                    Err(e) => {
-                        return Err(format!("Could not parse {}: {}", absolute_url, e).to_owned());
+                    let absolute_url = match str::from_utf8(&absolute_url) {
+                        Ok(absolute_url) => absolute_url,
+                        Err(_) => {
+                            return Err(Error::Header(String::from("Host header isn't UTF-8")));
+                        }
+                    };
+
+                    match url::Url::parse(&absolute_url) {
+                        Ok(url) => url,
+                        Err(e) => {
+                            return Err(Error::Parse(format!("Could not parse {}: {}", absolute_url, e)));
+                        }
                     }
+                },
+                Err(e) => {
+                    return Err(Error::Parse(format!("Could not parse {}: {}", path, e)));
                 }
-            },
-            Err(e) => {
-                return Err(format!("Could not parse {}: {}", request.path.unwrap(), e).to_owned());
             }
         };
 
         Ok(Request {
             headers: headers,
             url: url,
-            method: String::from(request.method.unwrap()),
-            version: request.version.unwrap(),
+            method: String::from(method),
+            version: version,
         })
     }
 }
@@ -159,6 +256,84 @@ mod tests {
         assert!(parse(&buf, &mut headers, total_read).is_err());
     }
 
+    #[test]
+    fn test_into() {
+        use super::parse;
+
+        let buf = b"POST /submit?q=octopus HTTP/1.1\r\nHost: google.com\r\nTransfer-Encoding: chunked\r\n\r\n".to_vec();
+        let mut headers = [httparse::EMPTY_HEADER; 16];
+        let total_read = buf.len();
+
+        let (req, _) = parse(&buf, &mut headers, total_read).unwrap().unwrap();
+        let serialized: Vec<u8> = req.into();
+
+        // The header block ends with a single blank line, the body follows.
+        assert_eq!(String::from_utf8(serialized).unwrap(), String::from_utf8(buf).unwrap());
+    }
+
+    #[test]
+    fn test_serialize_absolute_form() {
+        use super::parse;
+
+        let buf = b"GET /a?b HTTP/1.1\r\nHost: google.com\r\n\r\n".to_vec();
+        let mut headers = [httparse::EMPTY_HEADER; 16];
+        let total_read = buf.len();
+
+        let (req, _) = parse(&buf, &mut headers, total_read).unwrap().unwrap();
+        let serialized = String::from_utf8(req.serialize(true)).unwrap();
+        assert!(serialized.starts_with("GET http://google.com/a?b HTTP/1.1\r\n"));
+    }
+
+    #[test]
+    fn test_keep_alive() {
+        use super::parse;
+
+        let cases: Vec<(&[u8], bool)> = vec![
+            (b"GET / HTTP/1.1\r\nHost: google.com\r\n\r\n", true),
+            (b"GET / HTTP/1.1\r\nHost: google.com\r\nConnection: Close\r\n\r\n", false),
+            (b"GET / HTTP/1.0\r\nHost: google.com\r\n\r\n", false),
+            (b"GET / HTTP/1.0\r\nHost: google.com\r\nConnection: keep-alive\r\n\r\n", true),
+        ];
+
+        for (buf, expected) in cases {
+            let buf = buf.to_vec();
+            let mut headers = [httparse::EMPTY_HEADER; 16];
+            let total_read = buf.len();
+
+            let (req, _) = parse(&buf, &mut headers, total_read).unwrap().unwrap();
+            assert_eq!(req.keep_alive(), expected);
+        }
+    }
+
+    #[test]
+    fn test_parse_on_connect() {
+        use super::parse;
+        let buf = b"CONNECT google.com:443 HTTP/1.1\r\nHost: google.com:443\r\n\r\n".to_vec();
+        let mut headers = [httparse::EMPTY_HEADER; 16];
+        let total_read = buf.len();
+
+        let (req, _) = parse(&buf, &mut headers, total_read).unwrap().unwrap();
+        assert_eq!(req.method, "CONNECT");
+        assert_eq!(req.url.host_str(), Some("google.com"));
+        assert_eq!(req.url.port_or_known_default(), Some(443));
+    }
+
+    #[test]
+    fn test_parse_authority() {
+        use super::parse_authority;
+
+        let url = parse_authority("[::1]:8443").unwrap();
+        assert_eq!(url.host_str(), Some("[::1]"));
+        assert_eq!(url.port_or_known_default(), Some(8443));
+
+        assert!(parse_authority("google.com").is_err());
+        assert!(parse_authority("google.com:").is_err());
+        assert!(parse_authority("google.com:99999").is_err());
+        assert!(parse_authority(":443").is_err());
+        assert!(parse_authority("google.com:443/path").is_err());
+        assert!(parse_authority("user@google.com:443").is_err());
+        assert!(parse_authority("[::1:443").is_err());
+    }
 
     #[test]
     fn test_parse_on_nonhttp() {
@@ -169,4 +344,65 @@ mod tests {
 
         assert!(parse(&buf, &mut headers, total_read).is_err());
     }
+
+    #[test]
+    fn test_parse_limited() {
+        use super::parse_limited;
+        use error::Error;
+
+        let mut buf = b"GET / HTTP/1.1\r\nHost: google.com\r\n".to_vec();
+        for i in 0..99 {
+            buf.extend(format!("X-Header-{}: value\r\n", i).as_bytes());
+        }
+        buf.extend(b"\r\n");
+
+        let (req, _) = parse_limited(&buf, buf.len(), 100, 64 * 1024).unwrap().unwrap();
+        assert_eq!(req.headers.get("X-Header-98").unwrap(), b"value");
+
+        match parse_limited(&buf, buf.len(), 99, 64 * 1024) {
+            Err(Error::HeadersTooLarge(_)) => {},
+            other => panic!("expected headers to be too large, got {:?}", other),
+        }
+
+        match parse_limited(&buf, buf.len(), 100, 1024) {
+            Err(Error::HeadersTooLarge(_)) => {},
+            other => panic!("expected headers to be too large, got {:?}", other),
+        }
+
+        // An incomplete head is refused as soon as it's too big, too.
+        let partial = buf[..2000].to_vec();
+        assert!(parse_limited(&partial, partial.len(), 100, 64 * 1024).unwrap().is_none());
+        assert!(parse_limited(&partial, partial.len(), 100, 1024).is_err());
+    }
+
+    #[test]
+    fn test_parse_on_malicious_headers() {
+        use super::parse;
+        use error::Error;
+
+        let cases: Vec<&[u8]> = vec![
+            b"GET / HTTP/1.1\r\nHost: a\r\nHost: b\r\n\r\n",
+            b"GET / HTTP/1.1\r\nHost: \xff\xfe\r\n\r\n",
+            b"GET / HTTP/1.1\r\nHost: a\r\nContent-Length: 1\r\nContent-Length: 2\r\n\r\n",
+        ];
+
+        for buf in cases {
+            let buf = buf.to_vec();
+            let mut headers = [httparse::EMPTY_HEADER; 16];
+            let total_read = buf.len();
+
+            match parse(&buf, &mut headers, total_read) {
+                Err(Error::Header(_)) => {},
+                other => panic!("expected a header error, got {:?}", other),
+            }
+        }
+
+        // Binary header values are passed along untouched.
+        let buf = b"GET / HTTP/1.1\r\nHost: a\r\nX-Binary: \xff\r\n\r\n".to_vec();
+        let mut headers = [httparse::EMPTY_HEADER; 16];
+        let total_read = buf.len();
+
+        let (req, _) = parse(&buf, &mut headers, total_read).unwrap().unwrap();
+        assert_eq!(req.headers.get("X-Binary").unwrap(), &vec![0xff]);
+    }
 }
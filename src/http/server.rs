@@ -1,170 +1,788 @@
-extern crate httparse;
 extern crate mioco;
 
-use std::io::{self, Read, Write};
-use std::net;
-use std::str::FromStr;
+extern crate url;
 
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::io::{self, BufRead, Write};
+use std::net;
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::path::Path;
+use std::sync::{Arc, Mutex, RwLock};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::time::{Duration, Instant, SystemTime};
+
+use cache::{Cache, Lookup};
+use cache::control::CacheControl;
+use cidr::{self, Cidr};
+use config::{Config, ListenAddress, Role};
+use error::{Error, Result};
+
+use super::admin;
+use super::body::Framing;
+use super::buffered::Buffered;
 use super::client::Client;
+use super::forwarded;
 use super::request::{self, Request};
+use super::timeout::{Timed, Trigger};
+use super::tunnel::{self, Duplex};
+use super::pages::{self, ErrorPage};
+use super::read_into_buffer;
+
+/// Handles for the same server can be cloned freely, e.g. to reload its
+/// configuration or shut it down from another coroutine.
+#[derive(Clone)]
+pub struct Server {
+    shared: Arc<Shared>,
+}
 
-pub struct Server<'interface> {
-    interface: &'interface str,
-    port: u16,
+/// State shared between every connection a server handles.
+///
+/// It's never changed once made. Reloading the configuration makes a new one
+/// for requests from then on, while requests already being handled carry on
+/// with the one they started with.
+struct Context {
+    config: Config,
+    client: Client,
+    cache: Arc<Cache>,
 }
 
-impl<'interface> Server<'interface> {
-    pub fn new(interface: &'interface str, port: u16) -> Server {
+/// What the server as a whole is up to.
+struct Shared {
+    /// The context new requests are handled with.
+    current: RwLock<Arc<Context>>,
+    /// The addresses actually being listened on.
+    bound: Mutex<Vec<ListenAddress>>,
+    /// Set once the server is shutting down.
+    draining: AtomicBool,
+    /// How many client connections are open.
+    active: AtomicUsize,
+    next_id: AtomicUsize,
+    /// Client connections waiting for their next request, which can be
+    /// closed straight away at shutdown.
+    idle: Mutex<HashMap<usize, Trigger>>,
+}
+
+impl Shared {
+    fn context(&self) -> Arc<Context> {
+        self.current.read().unwrap().clone()
+    }
+
+    fn draining(&self) -> bool {
+        self.draining.load(Ordering::SeqCst)
+    }
+}
+
+/// Keeps track of a client connection for as long as it's open.
+struct Connection {
+    shared: Arc<Shared>,
+    id: usize,
+}
+
+impl Connection {
+    fn open(shared: &Arc<Shared>) -> Connection {
+        shared.active.fetch_add(1, Ordering::SeqCst);
+
+        Connection {
+            shared: shared.clone(),
+            id: shared.next_id.fetch_add(1, Ordering::SeqCst),
+        }
+    }
+
+    /// Mark the connection as waiting for another request, which `trigger`
+    /// stops it doing.
+    ///
+    /// Returns false if the server is shutting down, in which case the
+    /// connection should be closed instead.
+    fn idle(&self, trigger: Trigger) -> bool {
+        let mut idle = self.shared.idle.lock().unwrap();
+
+        // Checked with the lock held, so shutting down can't miss this
+        // connection.
+        if self.shared.draining() {
+            return false;
+        }
+
+        idle.insert(self.id, trigger);
+        true
+    }
+
+    fn busy(&self) {
+        self.shared.idle.lock().unwrap().remove(&self.id);
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        self.busy();
+        self.shared.active.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl Server {
+    pub fn new(config: Config) -> Server {
+        let client = Client::new(&config);
+        let cache = match Cache::new(&config) {
+            Ok(cache) => cache,
+            Err(e) => fatal!("Could not open the cache: {}", e)
+        };
+
         Server {
-            interface: interface,
-            port: port,
+            shared: Arc::new(Shared {
+                current: RwLock::new(Arc::new(Context {
+                    config: config,
+                    client: client,
+                    cache: Arc::new(cache),
+                })),
+                bound: Mutex::new(Vec::new()),
+                draining: AtomicBool::new(false),
+                active: AtomicUsize::new(0),
+                next_id: AtomicUsize::new(0),
+                idle: Mutex::new(HashMap::new()),
+            }),
         }
     }
 
-    pub fn start(&self) -> io::Result<()> {
-        let ip = net::IpAddr::from_str(self.interface).unwrap();
-        let addr = net::SocketAddr::new(ip, self.port);
+    fn context(&self) -> Arc<Context> {
+        self.shared.context()
+    }
+
+    /// Use `config` for every request from now on.
+    ///
+    /// The cache and listeners stay as they are, so changes to their settings
+    /// only take effect on a restart. Idle upstream connections are dropped.
+    pub fn reload(&self, mut config: Config) {
+        let old = self.context();
 
-        let listener = match mioco::tcp::TcpListener::bind(&addr) {
-            Ok(v) => v,
-            Err(e) => fatal!("Could not bind listener to port {}: {}", self.port, e)
+        if config.listeners != old.config.listeners {
+            warn!("Listeners can't be changed without a restart, keeping the old ones");
+            config.listeners = old.config.listeners.clone();
+        }
+
+        if config.cache_max_size != old.config.cache_max_size
+            || config.cache_max_object_size != old.config.cache_max_object_size
+            || config.cache_dir != old.config.cache_dir
+            || config.cache_disk_max_size != old.config.cache_disk_max_size {
+            warn!("Cache settings can't be changed without a restart, keeping the old ones");
+            config.cache_max_size = old.config.cache_max_size;
+            config.cache_max_object_size = old.config.cache_max_object_size;
+            config.cache_dir = old.config.cache_dir.clone();
+            config.cache_disk_max_size = old.config.cache_disk_max_size;
+        }
+
+        let context = Context {
+            client: Client::new(&config),
+            cache: old.cache.clone(),
+            config: config,
         };
 
-        println!("Starting tcp echo server on {:?}", try!(listener.local_addr()));
+        *self.shared.current.write().unwrap() = Arc::new(context);
+        info!("Configuration reloaded");
+    }
+
+    /// Accept connections on every configured listener. Returns once the
+    /// server is shutting down, or if accepting fails on one of them.
+    pub fn start(&self) -> io::Result<()> {
+        let mut listeners = Vec::new();
+
+        // Bind everything up front, so a bad address is reported before any
+        // connections are accepted.
+        let context = self.context();
+
+        for listener in &context.config.listeners {
+            let bound = match listener.address {
+                ListenAddress::Tcp(ref address) => mioco::tcp::TcpListener::bind(address).map(Bound::Tcp),
+                ListenAddress::Unix { ref path, mode } => bind_unix(path, mode).map(Bound::Unix),
+            };
+
+            match bound {
+                Ok(bound) => listeners.push((bound, listener.clone())),
+                Err(e) => fatal!("Could not bind listener to {}: {}", listener.address, e)
+            };
+        }
 
-        loop {
-            let conn = try!(listener.accept());
+        let (sender, receiver) = mioco::sync::mpsc::channel();
 
-            mioco::spawn(move || -> io::Result<()> {
-                handle_client(conn)
+        for (bound, listener) in listeners {
+            // Binding to port 0 picks a port, which is the one to report.
+            let address = match bound {
+                Bound::Tcp(ref tcp) => ListenAddress::Tcp(try!(tcp.local_addr())),
+                Bound::Unix(_) => listener.address.clone(),
+            };
+
+            info!("Listening on {} as {}", address, match listener.role {
+                Role::Forward => String::from("a forward proxy"),
+                Role::Reverse(ref origin) => format!("a reverse proxy for {}", origin),
+                Role::Admin => String::from("the admin API"),
+            });
+            self.shared.bound.lock().unwrap().push(address.clone());
+
+            let shared = self.shared.clone();
+            let sender = sender.clone();
+            let role = listener.role;
+            mioco::spawn(move || {
+                let result = match bound {
+                    Bound::Tcp(tcp) => accept(tcp, address, role, shared),
+                    Bound::Unix(unix) => accept(unix, address, role, shared),
+                };
+                let _ = sender.send(result);
             });
+        }
+
+        match receiver.recv() {
+            Ok(result) => result,
+            Err(_) => Ok(()),
+        }
+    }
+
+    /// Start shutting down: stop accepting connections, and close those that
+    /// are waiting for another request. Requests already being handled carry
+    /// on; `active_connections()` says how many are left.
+    pub fn shutdown(&self) {
+        {
+            let idle = self.shared.idle.lock().unwrap();
+            self.shared.draining.store(true, Ordering::SeqCst);
 
-            println!("spawned");
+            for trigger in idle.values() {
+                trigger.fire();
+            }
         }
+
+        // Wake up each listener with a connection of our own, so it notices.
+        for address in self.shared.bound.lock().unwrap().iter() {
+            let woken = match *address {
+                ListenAddress::Tcp(mut address) => {
+                    if address.ip().is_unspecified() {
+                        let loopback = match address {
+                            net::SocketAddr::V4(_) => net::IpAddr::V4(net::Ipv4Addr::new(127, 0, 0, 1)),
+                            net::SocketAddr::V6(_) => net::IpAddr::V6(net::Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 1)),
+                        };
+                        address.set_ip(loopback);
+                    }
+
+                    mioco::tcp::TcpStream::connect(&address).map(|_| ())
+                },
+                ListenAddress::Unix { ref path, .. } => {
+                    let woken = mioco::unix::UnixStream::connect(path).map(|_| ());
+
+                    // The socket keeps working without its name, and nothing
+                    // new should find it.
+                    let _ = fs::remove_file(path);
+                    woken
+                },
+            };
+
+            if let Err(e) = woken {
+                warn!("Could not stop listening on {}: {}", address, e);
+            }
+        }
+    }
+
+    /// How many client connections are still open.
+    pub fn active_connections(&self) -> usize {
+        self.shared.active.load(Ordering::SeqCst)
     }
 }
 
+/// A bound listening socket of any kind.
+enum Bound {
+    Tcp(mioco::tcp::TcpListener),
+    Unix(mioco::unix::UnixListener),
+}
+
+/// Where a client connection is from, as far as ACLs and logging are
+/// concerned.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Peer {
+    Ip(net::IpAddr),
+    /// Something on the other end of a Unix domain socket, which has no
+    /// address to go by.
+    Unix,
+}
+
+impl Peer {
+    fn ip(&self) -> Option<net::IpAddr> {
+        match *self {
+            Peer::Ip(ip) => Some(ip),
+            Peer::Unix => None,
+        }
+    }
 
-fn handle_request<S: Write + Read>(mut stream: &mut S, request: Request, mut body: Vec<u8>) {
-    match request.headers.content_length() {
-        Some(n) => {
-            if body.len() == n {
-                println!("We have it all, no need to read");
-            } else if body.len() > n {
-                println!("We have too much, what?!");
-            } else {
-                println!("We have {} bytes of {}", body.len(), n);
-                // FIXME: What if it's a 50gb upload! It should read up to a
-                // maximum of 65536 bytes or something, otherwise stream it.
-                let i = body.len();
+    /// Whether this is one of `clients`, or on a Unix domain socket if `unix`
+    /// says those are allowed.
+    fn is_one_of(&self, clients: &[Cidr], unix: bool) -> bool {
+        match *self {
+            Peer::Ip(ref ip) => clients.iter().any(|cidr| cidr.contains(ip)),
+            Peer::Unix => unix,
+        }
+    }
+}
 
-                body.reserve(n);
-                body.resize(n, 0);
-                stream.read_exact(&mut body[i..n-i]).unwrap();
+impl fmt::Display for Peer {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Peer::Ip(ref ip) => write!(f, "{}", ip),
+            Peer::Unix => write!(f, "Unix domain socket client"),
+        }
+    }
+}
+
+/// A socket that client connections are accepted from.
+trait Listen: Send + 'static {
+    type Stream: Duplex;
+
+    fn accept_client(&self) -> io::Result<Self::Stream>;
+
+    fn peer(stream: &Self::Stream) -> io::Result<Peer>;
+}
+
+impl Listen for mioco::tcp::TcpListener {
+    type Stream = mioco::tcp::TcpStream;
+
+    fn accept_client(&self) -> io::Result<mioco::tcp::TcpStream> {
+        self.accept()
+    }
+
+    fn peer(stream: &mioco::tcp::TcpStream) -> io::Result<Peer> {
+        // Clients connecting over IPv4 to a dual-stack socket show up with
+        // IPv4-mapped addresses, which IPv4 ACLs wouldn't otherwise match.
+        stream.peer_addr().map(|peer| Peer::Ip(cidr::unmap(&peer.ip())))
+    }
+}
+
+impl Listen for mioco::unix::UnixListener {
+    type Stream = mioco::unix::UnixStream;
+
+    fn accept_client(&self) -> io::Result<mioco::unix::UnixStream> {
+        self.accept()
+    }
+
+    fn peer(_: &mioco::unix::UnixStream) -> io::Result<Peer> {
+        Ok(Peer::Unix)
+    }
+}
+
+/// Bind a Unix domain socket at `path`, replacing any left over from before,
+/// and give it `mode` if there is one.
+fn bind_unix(path: &Path, mode: Option<u32>) -> io::Result<mioco::unix::UnixListener> {
+    match fs::symlink_metadata(path) {
+        Ok(ref metadata) if metadata.file_type().is_socket() => try!(fs::remove_file(path)),
+        Ok(_) => return Err(io::Error::new(io::ErrorKind::AlreadyExists, "something other than a socket is there")),
+        Err(_) => {},
+    }
+
+    let listener = try!(mioco::unix::UnixListener::bind(path));
+
+    if let Some(mode) = mode {
+        try!(fs::set_permissions(path, fs::Permissions::from_mode(mode)));
+    }
+
+    Ok(listener)
+}
+
+fn accept<L: Listen>(listener: L, address: ListenAddress, role: Role, shared: Arc<Shared>) -> io::Result<()> {
+    loop {
+        let conn = try!(listener.accept_client());
+
+        if shared.draining() {
+            info!("Stopped listening on {}", address);
+            return Ok(());
+        }
+
+        let peer = match L::peer(&conn) {
+            Ok(peer) => peer,
+            Err(e) => {
+                // The client has already gone away.
+                warn!("Could not get peer address: {}", e);
+                continue;
             }
-        },
-        None => {
-            assert!(body.len() == 0);
+        };
+
+        let connection = Connection::open(&shared);
+        let role = role.clone();
+        mioco::spawn(move || -> Result<()> {
+            handle_client(conn, peer, role, connection)
+        });
+
+        debug!("spawned");
+    }
+}
+
+
+/// Forward a single request and relay the reply.
+///
+/// Returns whether the client connection can be used for another request.
+fn handle_request<S: BufRead + Write>(stream: &mut S, mut request: Request, peer: Peer, role: &Role, context: &Arc<Context>) -> Result<bool> {
+    if request.headers.via_contains(&context.config.via_pseudonym) {
+        let page = ErrorPage::new(508, "Loop Detected", "This request has already been through this proxy.");
+        warn!("Request {}: forwarding loop detected for {}", page.request_id, request.url);
+        try!(page.send(stream, &context.config.error_templates, Some(&request), false));
+        return Ok(false);
+    }
+
+    let framing = match Framing::for_request(&request) {
+        Ok(framing) => framing,
+        Err(e) => {
+            info!("{}", e);
+            try!(pages::send_error(stream, &context.config.error_templates, Some(&request), &e));
+            return Err(e);
         }
+    };
+
+    // Admin listeners take anything but PURGE to be for the admin API, as
+    // they don't forward anything.
+    let for_admin = request.url.host_str() == Some(&context.config.admin_host)
+        || (*role == Role::Admin && request.method != "PURGE");
+
+    if for_admin || request.method == "PURGE" {
+        if !peer.is_one_of(&context.config.admin_clients, context.config.admin_unix_clients) {
+            warn!("Refusing {} {} from {}", request.method, request.url, peer);
+            let e = Error::Policy(format!("{} is not an admin client", peer));
+            try!(pages::send_error(stream, &context.config.error_templates, Some(&request), &e));
+            return Ok(false);
+        }
+
+        // Nothing here needs a request body, so there's no point reading it.
+        if framing != Framing::Empty {
+            let e = Error::Parse(String::from("unexpected request body"));
+            try!(pages::send_error(stream, &context.config.error_templates, Some(&request), &e));
+            return Ok(false);
+        }
+
+        if for_admin {
+            return admin::handle(stream, &request, &context.cache);
+        } else {
+            return admin::purge(stream, &request, &context.cache);
+        }
+    }
+
+    if framing == Framing::Chunked {
+        // Transfer-Encoding overrides Content-Length, so make sure the two
+        // can't be interpreted differently further upstream.
+        request.headers.remove("content-length");
+    }
+
+    if context.config.forwarded_headers {
+        forwarded::add_forwarded_headers(&mut request, peer.ip(), "http", &context.config.trusted_proxies);
+    }
+
+    if let Role::Reverse(ref origin) = *role {
+        to_origin(&mut request, origin);
     }
 
-    println!("Handle this: {:?} {:?}", request, request.headers.content_length());
+    debug!("Handle this: {:?} {:?}", request, framing);
+
+    // Requests with a body aren't answered from the cache, as it would have to
+    // be read and thrown away first.
+    let mut stale = None;
+
+    if framing == Framing::Empty {
+        match context.cache.lookup(&request, SystemTime::now()) {
+            Lookup::Fresh(entry) => {
+                debug!("Cache hit for {}", request.url);
+                return context.client.serve_cached(stream, &request, &entry);
+            },
+            Lookup::StaleWhileRevalidate(entry) => {
+                debug!("Cache hit for stale {}, revalidating", request.url);
+                let keep_alive = try!(context.client.serve_cached(stream, &request, &entry));
+
+                let context = context.clone();
+                mioco::spawn(move || -> Result<()> {
+                    context.client.revalidate(request, entry, &context.cache)
+                });
 
-    let client = Client;
-    client.forward(&mut stream, request, body);
+                return Ok(keep_alive);
+            },
+            Lookup::Stale(entry) => {
+                stale = Some(entry);
+            },
+            Lookup::Miss => {},
+        }
+
+        if CacheControl::from_headers(&request.headers).only_if_cached {
+            let page = ErrorPage::new(504, "Gateway Timeout", "This isn't cached, and only-if-cached was asked for.");
+            return Ok(try!(page.send(stream, &context.config.error_templates, Some(&request), true)));
+        }
+    }
+
+    context.client.forward(stream, request, framing, &context.cache, stale)
+}
+
+/// Point a request to a reverse proxy at `origin`, keeping its path and
+/// query. The Host header is changed to match, as the request is now for
+/// `origin`; the one the client sent is in the forwarded headers.
+fn to_origin(request: &mut Request, origin: &url::Url) {
+    let path = format!("{}{}", origin.path().trim_end_matches('/'), request.url.path());
+
+    let mut url = origin.clone();
+    url.set_path(&path);
+    url.set_query(request.url.query());
+
+    let host = match (url.host_str(), url.port()) {
+        (Some(host), Some(port)) => format!("{}:{}", host, port),
+        (Some(host), None) => String::from(host),
+        (None, _) => String::new(),
+    };
+    request.headers.remove("host");
+    request.headers.insert("Host", &host.into_bytes());
+
+    request.url = url;
+}
+
+/// Establish a tunnel to the destination of a CONNECT request. The client
+/// connection is given over to the tunnel for the rest of its life.
+fn handle_connect<S: Duplex>(stream: &mut S, request: Request, early_data: Vec<u8>, context: &Context) -> Result<()> {
+    let port = request.url.port_or_known_default().unwrap_or(0);
+
+    if !context.config.connect_ports.contains(&port) {
+        warn!("Refusing to CONNECT to {}: port {} is not allowed", request.url, port);
+        let e = Error::Policy(format!("CONNECT to port {} is not allowed", port));
+        try!(pages::send_error(stream, &context.config.error_templates, Some(&request), &e));
+        return Ok(());
+    }
+
+    let mut upstream = match context.client.connect(&request.url) {
+        Ok(upstream) => upstream,
+        Err(e) => {
+            warn!("Error connecting upstream: {}", e);
+            try!(pages::send_error(stream, &context.config.error_templates, Some(&request), &e));
+            return Ok(());
+        }
+    };
+
+    try!(stream.write_all(b"HTTP/1.1 200 Connection Established\r\n\r\n"));
+
+    // Anything the client sent straight after the CONNECT head, e.g. an
+    // eagerly sent TLS ClientHello, belongs in the tunnel.
+    try!(upstream.write_all(&early_data));
+
+    let (sent, received) = try!(tunnel::tunnel(stream, &upstream, context.config.tunnel_idle_timeout));
+    debug!("Tunnel to {} closed after sending {} and receiving {} bytes", request.url, sent, received);
+
+    Ok(())
+}
+
+/// Give the client until `timeout` from now to get a request head in, however
+/// it trickles in.
+fn expect_head<S: Duplex>(stream: &mut Timed<S>, timeout: Duration) {
+    stream.set_timeout(None);
+    stream.set_deadline(Some(Instant::now() + timeout));
 }
 
-fn handle_client<S: Write + Read>(mut stream: S) -> io::Result<()> {
+fn handle_client<S: Duplex>(stream: S, peer: Peer, role: Role, connection: Connection) -> Result<()> {
+    // Each request is handled with the configuration as it was when the
+    // request started.
+    let mut context = connection.shared.context();
+
+    // Anything read past the end of one request, e.g. the start of a
+    // pipelined request, stays buffered in here for the next.
+    let mut stream = Buffered::new(Timed::new(stream, context.config.client_read_timeout));
+    let trigger = stream.get_ref().trigger();
     let mut buffer = Vec::with_capacity(65536);
-    let mut headers = [httparse::EMPTY_HEADER; 16];
     let mut total_read = 0;
 
+    // Whether we're between requests, rather than part way through reading
+    // one. The first request gets the header timeout from the start.
+    let mut idle = false;
+    expect_head(stream.get_mut(), context.config.header_read_timeout);
+
+    // Until it's sent something, the connection can be closed at shutdown
+    // like any other waiting for a request.
+    if !connection.idle(trigger.clone()) {
+        return Ok(());
+    }
+
     loop {
         match read_into_buffer(&mut stream, &mut buffer) {
             Ok(0) => {
-                println!("empty read, bailing");
+                debug!("empty read, bailing");
                 return Ok(());
             },
             Ok(n) => {
+                if total_read == 0 {
+                    connection.busy();
+                }
+
                 total_read += n;
-                println!("Did a read {}", n);
+                debug!("Did a read {}", n);
+
+                // The next request has started, and has to arrive in good
+                // time.
+                if idle {
+                    idle = false;
+                    expect_head(stream.get_mut(), context.config.header_read_timeout);
+                }
             },
-            Err(e) => {
-                println!("Error occurred while reading {}", e);
+            Err(ref e) if e.kind() == io::ErrorKind::TimedOut => {
+                if idle || (total_read == 0 && connection.shared.draining()) {
+                    debug!("Closing idle client connection");
+                    return Ok(());
+                }
+
+                let e = Error::ClientTimeout(format!("no request within {}s", context.config.header_read_timeout.as_secs()));
+                try!(pages::send_error(&mut stream, &context.config.error_templates, None, &e));
                 return Err(e);
+            },
+            Err(e) => {
+                info!("Error occurred while reading {}", e);
+                return Err(Error::from(e));
             }
         }
 
-        match request::parse(&buffer, &mut headers, total_read) {
+        match request::parse_limited(&buffer, total_read, context.config.max_request_headers, context.config.max_request_header_bytes) {
             Ok(Some((request, partial_body))) => {
-                handle_request(&mut stream, request, partial_body);
+                stream.unread(&partial_body);
+
+                if !peer.is_one_of(&context.config.allowed_clients, context.config.allowed_unix_clients) {
+                    warn!("Refusing {} {} from {}", request.method, request.url, peer);
+                    let e = Error::Policy(format!("{} is not allowed to use this proxy", peer));
+                    try!(pages::send_error(&mut stream, &context.config.error_templates, Some(&request), &e));
+                    return Err(e);
+                }
+
+                if request.method == "CONNECT" {
+                    if role != Role::Forward {
+                        let e = Error::Policy(String::from("CONNECT is only allowed through a forward proxy"));
+                        try!(pages::send_error(&mut stream, &context.config.error_templates, Some(&request), &e));
+                        return Err(e);
+                    }
+
+                    let early_data = stream.take_buffered();
+                    let mut stream = stream.into_inner().into_inner();
+                    return handle_connect(&mut stream, request, early_data, &context);
+                }
+
+                // From here on the client only has to keep any body coming.
+                {
+                    let timed = stream.get_mut();
+                    timed.set_deadline(None);
+                    timed.set_timeout(Some(context.config.client_read_timeout));
+                }
+
+                // Requests are handled strictly one after the other, so
+                // pipelined requests are answered in the order they arrived.
+                let keep_alive = try!(handle_request(&mut stream, request, peer, &role, &context));
+
+                if !keep_alive {
+                    debug!("Closing client connection");
+                    return Ok(());
+                }
             }
             Ok(None) => {
                 continue;
             },
             Err(e) => {
-                let reason = format!("Error parsing request: {}", e);
-                println!("{}", reason);
-
-                let error = io::Error::new(io::ErrorKind::Other, reason);
-                return Err(error);
+                info!("Error parsing request: {}", e);
+                try!(pages::send_error(&mut stream, &context.config.error_templates, None, &e));
+                return Err(e);
             }
         }
 
-        // reset the buffer so we have a clean slate for keep-alive.
+        // reset the buffer so we have a clean slate for keep-alive. Anything
+        // past the end of the request was put back into the stream above.
         buffer.truncate(0);
         total_read = 0;
-    }
-}
-
-/// Read from the given stream into the given buffer.
-/// Interally this will perform a read for up to 65536 bytes of data, and
-/// append it to the end of the given buffer.
-fn read_into_buffer<R: Read>(stream: &mut R, buffer: &mut Vec<u8>) -> io::Result<usize> {
-
-    // XXX: it would be nice to benchmark how this compares to reading directly
-    // into the given buffer.
 
-    // We do it this way to ensure we only put data into `buffer` that was
-    // actually read from the stream, and not accidentally leave the buffer
-    // filled with nulls from a resize.
+        idle = true;
+        context = connection.shared.context();
+        expect_head(stream.get_mut(), context.config.keep_alive_timeout);
 
-    let mut read_buf = vec![0; 65536];
-    match stream.read(&mut read_buf) {
-        Ok(n) if n > 0 => {
-            unsafe {
-                read_buf.set_len(n);
-            }
-            buffer.append(&mut read_buf);
-            Ok(n)
+        if !connection.idle(trigger.clone()) {
+            debug!("Closing client connection to shut down");
+            return Ok(());
         }
-        r => r,
     }
 }
 
-
 #[cfg(test)]
 mod tests {
-    use std::io::Cursor;
+    extern crate mioco;
+    extern crate url;
+
+    use std::io::{self, Read};
+    use std::net::{IpAddr, SocketAddr};
+    use std::str::FromStr;
+    use std::sync::Arc;
+    use std::time::Duration;
 
-    use super::read_into_buffer;
+    use config::Config;
+    use http::test_support::request;
+    use http::timeout::Timed;
+
+    use super::{to_origin, Connection, Peer, Server};
 
     #[test]
-    fn test_read_into_buffer() {
-        let mut s = Cursor::new("Hello world");
-        let mut buf = Vec::with_capacity(5);
+    fn test_peer_acls() {
+        let config = Config::default();
 
-        assert_eq!(11, read_into_buffer(&mut s, &mut buf).unwrap());
-        assert_eq!(&buf[..], b"Hello world");
+        let local = Peer::Ip(IpAddr::from_str("127.0.0.1").unwrap());
+        assert!(local.is_one_of(&config.admin_clients, config.admin_unix_clients));
 
-        let mut s = Cursor::new("!");
+        // Unix domain socket clients aren't taken to be local.
+        assert!(!Peer::Unix.is_one_of(&config.admin_clients, config.admin_unix_clients));
+        assert!(Peer::Unix.is_one_of(&config.allowed_clients, config.allowed_unix_clients));
+        assert!(!Peer::Unix.is_one_of(&config.allowed_clients, false));
+        assert_eq!(Peer::Unix.ip(), None);
+    }
 
-        assert_eq!(1, read_into_buffer(&mut s, &mut buf).unwrap());
+    #[test]
+    fn test_to_origin() {
+        let mut req = request("GET /a/b?c=d HTTP/1.1\r\nHost: www.example.com\r\n\r\n");
 
-        assert_eq!(&buf, b"Hello world!");
+        to_origin(&mut req, &url::Url::parse("http://10.0.0.5:8080/app/").unwrap());
+        assert_eq!(req.url.as_str(), "http://10.0.0.5:8080/app/a/b?c=d");
+        assert_eq!(req.headers.get("host").unwrap(), b"10.0.0.5:8080");
+    }
 
-        assert_eq!(0, read_into_buffer(&mut s, &mut buf).unwrap());
+    #[test]
+    fn test_reload() {
+        let server = Server::new(Config::default());
+        let before = server.context();
+
+        let mut config = Config::default();
+        config.via_pseudonym = String::from("squid");
+        config.cache_max_size = 1024;
+        config.listeners.clear();
+        server.clone().reload(config);
+
+        // Whatever already had the old configuration keeps it.
+        let after = server.context();
+        assert_eq!(before.config.via_pseudonym, "octopus");
+        assert_eq!(after.config.via_pseudonym, "squid");
+        assert!(Arc::ptr_eq(&before.cache, &after.cache));
+
+        // Settings that need a restart aren't taken on.
+        assert_eq!(after.config.cache_max_size, before.config.cache_max_size);
+        assert_eq!(after.config.listeners, before.config.listeners);
+    }
 
-        assert_eq!(&buf, b"Hello world!");
+    #[test]
+    fn test_shutdown() {
+        mioco::start(|| {
+            let listener = mioco::tcp::TcpListener::bind(&SocketAddr::from_str("127.0.0.1:0").unwrap()).unwrap();
+            let _client = mioco::tcp::TcpStream::connect(&listener.local_addr().unwrap()).unwrap();
+            let mut conn = Timed::new(listener.accept().unwrap(), Duration::from_secs(60));
+
+            let server = Server::new(Config::default());
+            let busy = Connection::open(&server.shared);
+            let idle = Connection::open(&server.shared);
+            assert_eq!(server.active_connections(), 2);
+
+            assert!(idle.idle(conn.trigger()));
+
+            server.shutdown();
+            assert!(!busy.idle(conn.trigger()));
+
+            // The idle connection is cut off, and goes away.
+            let mut buf = [0; 16];
+            assert_eq!(conn.read(&mut buf).unwrap_err().kind(), io::ErrorKind::TimedOut);
+            assert!(conn.expired());
+            drop(idle);
+            assert_eq!(server.active_connections(), 1);
+
+            drop(busy);
+            assert_eq!(server.active_connections(), 0);
+        }).unwrap();
     }
 }
@@ -0,0 +1,18 @@
+extern crate httparse;
+
+use super::reply::{self, Reply};
+use super::request::{self, Request};
+
+/// Parse `raw`, which has to be a whole request head.
+pub fn request<B: AsRef<[u8]>>(raw: B) -> Request {
+    let buffer = raw.as_ref().to_vec();
+    let mut headers = [httparse::EMPTY_HEADER; 16];
+    request::parse(&buffer, &mut headers, buffer.len()).unwrap().unwrap().0
+}
+
+/// Parse `raw`, which has to be a whole reply head.
+pub fn reply<B: AsRef<[u8]>>(raw: B) -> Reply {
+    let buffer = raw.as_ref().to_vec();
+    let mut headers = [httparse::EMPTY_HEADER; 16];
+    reply::parse(&buffer, &mut headers, buffer.len()).unwrap().unwrap().0
+}
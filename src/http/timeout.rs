@@ -0,0 +1,287 @@
+extern crate mioco;
+
+use std::cmp;
+use std::io::{self, Read, Write};
+use std::time::{Duration, Instant};
+
+use self::mioco::Evented;
+use self::mioco::sync::mpsc::{channel, Receiver, Sender};
+use self::mioco::timer::Timer;
+
+use super::tunnel::Duplex;
+
+/// A connection that gives up on reads that take too long.
+///
+/// Each read has to make progress within the timeout and finish by the
+/// deadline, whichever of them is set. One that doesn't fails with `TimedOut`,
+/// and so does every read after it. Writing is unaffected, so there's still a
+/// chance to say why.
+pub struct Timed<S> {
+    inner: S,
+    timeout: Option<Duration>,
+    deadline: Option<Instant>,
+    expired: bool,
+    wake: Receiver<()>,
+    waker: Sender<()>,
+}
+
+impl<S: Duplex> Timed<S> {
+    pub fn new(inner: S, timeout: Duration) -> Timed<S> {
+        let (waker, wake) = channel();
+
+        Timed {
+            inner: inner,
+            timeout: Some(timeout),
+            deadline: None,
+            expired: false,
+            wake: wake,
+            waker: waker,
+        }
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.inner
+    }
+
+    pub fn into_inner(self) -> S {
+        self.inner
+    }
+
+    /// Give each read from now on `timeout` to make progress.
+    pub fn set_timeout(&mut self, timeout: Option<Duration>) {
+        self.timeout = timeout;
+        self.discard_triggers();
+    }
+
+    /// Give every read from now on until `deadline` to finish.
+    pub fn set_deadline(&mut self, deadline: Option<Instant>) {
+        self.deadline = deadline;
+        self.discard_triggers();
+    }
+
+    /// Whether a read was cut off, leaving the connection unreadable.
+    pub fn expired(&self) -> bool {
+        self.expired
+    }
+
+    /// A handle for cutting reads off from elsewhere.
+    pub fn trigger(&self) -> Trigger {
+        Trigger {
+            waker: self.waker.clone(),
+        }
+    }
+
+    // A trigger fired for one stage of the connection shouldn't cut off the
+    // next.
+    fn discard_triggers(&mut self) {
+        while self.wake.try_recv().is_ok() {}
+    }
+
+    fn wait(&self) -> Option<Duration> {
+        let left = self.deadline.map(|deadline| {
+            let now = Instant::now();
+            if deadline > now { deadline - now } else { Duration::from_secs(0) }
+        });
+
+        match (self.timeout, left) {
+            (Some(timeout), Some(left)) => Some(cmp::min(timeout, left)),
+            (timeout, left) => timeout.or(left),
+        }
+    }
+}
+
+/// Cuts a timed connection off early.
+#[derive(Clone)]
+pub struct Trigger {
+    waker: Sender<()>,
+}
+
+impl Trigger {
+    /// Make the read in progress fail now, or the next one if there isn't one,
+    /// unless the timeout or deadline is changed first.
+    pub fn fire(&self) {
+        let _ = self.waker.send(());
+    }
+}
+
+impl<S: Duplex> Read for Timed<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if !self.expired {
+            if let Some(wait) = self.wait() {
+                let mut timer = Timer::new();
+                timer.set_timeout(millis(wait) as _);
+
+                let inner = &self.inner;
+                let wake = &self.wake;
+                let mut ready = false;
+                select!(
+                    r:inner => { ready = true; },
+                    r:timer => {},
+                    r:wake => {},
+                );
+                self.expired = !ready;
+            }
+        }
+
+        if self.expired {
+            return Err(io::Error::new(io::ErrorKind::TimedOut, "nothing read in time"));
+        }
+
+        self.inner.read(buf)
+    }
+}
+
+impl<S: Write> Write for Timed<S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.inner.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Wait up to `timeout` for there to be something to read from `stream`, or
+/// for it to be closed. Returns false if there isn't by then.
+///
+/// `stream` can be anything readiness can be waited on for, like a channel.
+pub fn readable<S: Evented>(stream: &S, timeout: Duration) -> bool {
+    let mut timer = Timer::new();
+    timer.set_timeout(millis(timeout) as _);
+
+    let mut ready = false;
+    select!(
+        r:stream => { ready = true; },
+        r:timer => {},
+    );
+    ready
+}
+
+/// Run `f` in a coroutine of its own, giving up on it after `timeout`.
+///
+/// Returns None if it took too long, in which case whatever it eventually
+/// returns is thrown away.
+pub fn with_timeout<F, T>(timeout: Duration, f: F) -> Option<T>
+    where F: FnOnce() -> T + Send + 'static, T: Send + 'static
+{
+    let (sender, receiver) = channel();
+
+    mioco::spawn(move || {
+        let _ = sender.send(f());
+    });
+
+    let mut timer = Timer::new();
+    timer.set_timeout(millis(timeout) as _);
+
+    let mut result = None;
+    select!(
+        r:receiver => { result = receiver.recv().ok(); },
+        r:timer => {},
+    );
+    result
+}
+
+// Rounded up, so a timer never goes off before the time is really up.
+fn millis(duration: Duration) -> u64 {
+    duration.as_secs() * 1000 + (duration.subsec_nanos() as u64 + 999_999) / 1_000_000
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate mioco;
+
+    use std::io::{self, Read, Write};
+    use std::net::SocketAddr;
+    use std::str::FromStr;
+    use std::time::{Duration, Instant};
+
+    use super::{readable, with_timeout, Timed};
+
+    fn pair() -> (mioco::tcp::TcpStream, mioco::tcp::TcpStream) {
+        let listener = mioco::tcp::TcpListener::bind(&SocketAddr::from_str("127.0.0.1:0").unwrap()).unwrap();
+        let client = mioco::tcp::TcpStream::connect(&listener.local_addr().unwrap()).unwrap();
+        let server = listener.accept().unwrap();
+        (client, server)
+    }
+
+    #[test]
+    fn test_timed() {
+        mioco::start(|| {
+            let (mut client, server) = pair();
+            let mut server = Timed::new(server, Duration::from_millis(50));
+
+            client.write_all(b"hi").unwrap();
+
+            let mut buf = [0; 16];
+            assert_eq!(server.read(&mut buf).unwrap(), 2);
+            assert!(!server.expired());
+
+            // Nothing else is coming, so the read is cut off, and so is every
+            // one after it.
+            assert_eq!(server.read(&mut buf).unwrap_err().kind(), io::ErrorKind::TimedOut);
+            assert!(server.expired());
+            client.write_all(b"late").unwrap();
+            assert_eq!(server.read(&mut buf).unwrap_err().kind(), io::ErrorKind::TimedOut);
+
+            // There's still time to tell the client.
+            server.write_all(b"bye").unwrap();
+            assert_eq!(client.read(&mut buf).unwrap(), 3);
+        }).unwrap();
+    }
+
+    #[test]
+    fn test_deadline() {
+        mioco::start(|| {
+            let (mut client, server) = pair();
+            let mut server = Timed::new(server, Duration::from_secs(60));
+            server.set_timeout(None);
+            server.set_deadline(Some(Instant::now() + Duration::from_millis(50)));
+
+            client.write_all(b"hi").unwrap();
+
+            let mut buf = [0; 16];
+            assert_eq!(server.read(&mut buf).unwrap(), 2);
+            assert_eq!(server.read(&mut buf).unwrap_err().kind(), io::ErrorKind::TimedOut);
+        }).unwrap();
+    }
+
+    #[test]
+    fn test_trigger() {
+        mioco::start(|| {
+            let (mut client, server) = pair();
+            let mut server = Timed::new(server, Duration::from_secs(60));
+            let trigger = server.trigger();
+
+            // Changing the deadline discards anything fired before.
+            trigger.fire();
+            server.set_deadline(None);
+            client.write_all(b"hi").unwrap();
+
+            let mut buf = [0; 16];
+            assert_eq!(server.read(&mut buf).unwrap(), 2);
+
+            trigger.fire();
+            assert_eq!(server.read(&mut buf).unwrap_err().kind(), io::ErrorKind::TimedOut);
+            assert!(server.expired());
+        }).unwrap();
+    }
+
+    #[test]
+    fn test_readable() {
+        mioco::start(|| {
+            let (mut client, server) = pair();
+
+            assert!(!readable(&server, Duration::from_millis(10)));
+            client.write_all(b"hi").unwrap();
+            assert!(readable(&server, Duration::from_secs(10)));
+        }).unwrap();
+    }
+
+    #[test]
+    fn test_with_timeout() {
+        mioco::start(|| {
+            assert_eq!(with_timeout(Duration::from_secs(10), || 42), Some(42));
+            assert_eq!(with_timeout(Duration::from_millis(10), || mioco::sleep(Duration::from_secs(1))), None);
+        }).unwrap();
+    }
+}
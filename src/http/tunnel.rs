@@ -0,0 +1,148 @@
+extern crate mioco;
+
+use std::io::{self, Read, Write};
+use std::net::Shutdown;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use self::mioco::Evented;
+
+use super::timeout;
+
+/// A bidirectional stream whose two directions can be serviced by separate
+/// coroutines.
+pub trait Duplex: Read + Write + Evented + Send + Sized + 'static {
+    fn try_clone(&self) -> io::Result<Self>;
+    fn shutdown(&self, how: Shutdown) -> io::Result<()>;
+}
+
+impl Duplex for mioco::tcp::TcpStream {
+    fn try_clone(&self) -> io::Result<Self> {
+        mioco::tcp::TcpStream::try_clone(self)
+    }
+
+    fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        mioco::tcp::TcpStream::shutdown(self, how)
+    }
+}
+
+impl Duplex for mioco::unix::UnixStream {
+    fn try_clone(&self) -> io::Result<Self> {
+        mioco::unix::UnixStream::try_clone(self)
+    }
+
+    fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        mioco::unix::UnixStream::shutdown(self, how)
+    }
+}
+
+/// Shovel bytes between `client` and `upstream` in both directions until both
+/// sides have finished sending, or nothing has gone either way for `idle`.
+///
+/// Returns the number of bytes sent upstream and downstream respectively.
+pub fn tunnel<C: Duplex, U: Duplex>(client: &C, upstream: &U, idle: Duration) -> io::Result<(u64, u64)> {
+    let client_reader = try!(client.try_clone());
+    let upstream_writer = try!(upstream.try_clone());
+    let last_active = Arc::new(Mutex::new(Instant::now()));
+    let sent_active = last_active.clone();
+
+    let sent = mioco::spawn(move || -> io::Result<u64> {
+        pipe(client_reader, upstream_writer, idle, &sent_active)
+    });
+
+    let received = pipe(try!(upstream.try_clone()), try!(client.try_clone()), idle, &last_active);
+
+    let sent = match sent.join() {
+        Ok(result) => result,
+        Err(_) => Err(io::Error::new(io::ErrorKind::Other, "tunnel coroutine panicked")),
+    };
+
+    Ok((try!(sent), try!(received)))
+}
+
+/// Copy everything from `from` into `to`, then signal the end of the stream
+/// to whoever is reading from `to`.
+///
+/// `last_active` is shared with the other direction, so a tunnel that's busy
+/// one way isn't closed for being quiet the other.
+fn pipe<R: Duplex, W: Duplex>(mut from: R, mut to: W, idle: Duration, last_active: &Mutex<Instant>) -> io::Result<u64> {
+    let result = copy(&mut from, &mut to, idle, last_active);
+
+    // On error tear down both sockets entirely, otherwise the coroutine
+    // servicing the other direction could be left waiting forever.
+    match result {
+        Ok(_) => {
+            let _ = to.shutdown(Shutdown::Write);
+        },
+        Err(_) => {
+            let _ = from.shutdown(Shutdown::Both);
+            let _ = to.shutdown(Shutdown::Both);
+        }
+    }
+
+    result
+}
+
+fn copy<R: Duplex, W: Duplex>(from: &mut R, to: &mut W, idle: Duration, last_active: &Mutex<Instant>) -> io::Result<u64> {
+    let mut buf = [0; 16384];
+    let mut copied = 0;
+
+    loop {
+        let quiet = last_active.lock().unwrap().elapsed();
+        if quiet >= idle {
+            return Err(io::Error::new(io::ErrorKind::TimedOut, format!("tunnel idle for {}s", idle.as_secs())));
+        }
+
+        // The other direction may have been busy meanwhile, so look again
+        // rather than giving up.
+        if !timeout::readable(from, idle - quiet) {
+            continue;
+        }
+
+        let n = try!(from.read(&mut buf));
+        if n == 0 {
+            return Ok(copied);
+        }
+
+        try!(to.write_all(&buf[..n]));
+        *last_active.lock().unwrap() = Instant::now();
+        copied += n as u64;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate mioco;
+
+    use std::io::{self, Read, Write};
+    use std::net::SocketAddr;
+    use std::str::FromStr;
+    use std::time::Duration;
+
+    use super::tunnel;
+
+    fn pair() -> (mioco::tcp::TcpStream, mioco::tcp::TcpStream) {
+        let listener = mioco::tcp::TcpListener::bind(&SocketAddr::from_str("127.0.0.1:0").unwrap()).unwrap();
+        let client = mioco::tcp::TcpStream::connect(&listener.local_addr().unwrap()).unwrap();
+        let server = listener.accept().unwrap();
+        (client, server)
+    }
+
+    #[test]
+    fn test_idle_tunnel() {
+        mioco::start(|| {
+            let (mut client, proxy_client) = pair();
+            let (proxy_upstream, mut upstream) = pair();
+
+            client.write_all(b"hello").unwrap();
+            let result = tunnel(&proxy_client, &proxy_upstream, Duration::from_millis(50));
+            assert_eq!(result.unwrap_err().kind(), io::ErrorKind::TimedOut);
+
+            // What did get sent went through before both ends were hung up on.
+            let mut buf = Vec::new();
+            upstream.read_to_end(&mut buf).unwrap();
+            assert_eq!(buf, b"hello");
+            assert_eq!(client.read(&mut [0; 16]).unwrap(), 0);
+        }).unwrap();
+    }
+}
@@ -1,6 +1,17 @@
 #![cfg_attr(feature = "unstable", feature(test))]
 
+#[macro_use]
+extern crate mioco;
+
 #[macro_use]
 pub mod macros;
 
+pub mod cache;
+pub mod cidr;
+pub mod config;
+pub mod error;
 pub mod http;
+pub mod logging;
+pub mod signals;
+
+pub use error::{Error, Result};
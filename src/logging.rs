@@ -0,0 +1,101 @@
+use std::fmt;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// How much to log. Each level includes everything above it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    Error = 0,
+    Warn = 1,
+    Info = 2,
+    Debug = 3,
+}
+
+static LEVEL: AtomicUsize = AtomicUsize::new(Level::Info as usize);
+
+pub fn set_level(level: Level) {
+    LEVEL.store(level as usize, Ordering::Relaxed);
+}
+
+pub fn level() -> Level {
+    Level::from_index(LEVEL.load(Ordering::Relaxed))
+}
+
+/// Whether messages at `level` are logged.
+pub fn enabled(level: Level) -> bool {
+    level <= self::level()
+}
+
+impl Level {
+    fn from_index(index: usize) -> Level {
+        match index {
+            0 => Level::Error,
+            1 => Level::Warn,
+            2 => Level::Info,
+            _ => Level::Debug,
+        }
+    }
+
+    /// The level `by` steps more verbose than this one, or less if `by` is
+    /// negative, going no further than `Error` or `Debug`.
+    pub fn adjust(self, by: isize) -> Level {
+        let index = self as isize + by;
+        if index < 0 {
+            Level::Error
+        } else {
+            Level::from_index(index as usize)
+        }
+    }
+}
+
+impl FromStr for Level {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Level, String> {
+        match &*s.to_lowercase() {
+            "error" => Ok(Level::Error),
+            "warn" | "warning" => Ok(Level::Warn),
+            "info" => Ok(Level::Info),
+            "debug" => Ok(Level::Debug),
+            _ => Err(format!("Unknown log level {}, expected error, warn, info or debug", s)),
+        }
+    }
+}
+
+impl fmt::Display for Level {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match *self {
+            Level::Error => "error",
+            Level::Warn => "warn",
+            Level::Info => "info",
+            Level::Debug => "debug",
+        };
+
+        write!(f, "{}", name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use super::Level;
+
+    #[test]
+    fn test_levels() {
+        assert!(Level::Error < Level::Warn);
+        assert!(Level::Info < Level::Debug);
+        assert_eq!(Level::from_str("WARNING").unwrap(), Level::Warn);
+        assert_eq!(Level::from_str("debug").unwrap().to_string(), "debug");
+        assert!(Level::from_str("loud").is_err());
+    }
+
+    #[test]
+    fn test_adjust() {
+        assert_eq!(Level::Info.adjust(1), Level::Debug);
+        assert_eq!(Level::Info.adjust(5), Level::Debug);
+        assert_eq!(Level::Info.adjust(-1), Level::Warn);
+        assert_eq!(Level::Warn.adjust(-3), Level::Error);
+        assert_eq!(Level::Warn.adjust(0), Level::Warn);
+    }
+}
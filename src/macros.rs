@@ -10,3 +10,35 @@ macro_rules! fatal {
         }
     }
 }
+
+/// Log a message at the given `logging::Level`, if that level is enabled.
+#[macro_export]
+macro_rules! log {
+    ( $level:expr, $( $x:expr ),* ) => {
+        {
+            if $crate::logging::enabled($level) {
+                println!( $($x,)* );
+            }
+        }
+    }
+}
+
+#[macro_export]
+macro_rules! error {
+    ( $( $x:expr ),* ) => { log!($crate::logging::Level::Error, $($x),*) }
+}
+
+#[macro_export]
+macro_rules! warn {
+    ( $( $x:expr ),* ) => { log!($crate::logging::Level::Warn, $($x),*) }
+}
+
+#[macro_export]
+macro_rules! info {
+    ( $( $x:expr ),* ) => { log!($crate::logging::Level::Info, $($x),*) }
+}
+
+#[macro_export]
+macro_rules! debug {
+    ( $( $x:expr ),* ) => { log!($crate::logging::Level::Debug, $($x),*) }
+}
@@ -3,11 +3,140 @@ extern crate mioco;
 #[macro_use]
 extern crate octopus;
 
-use std::io;
+mod cli;
+
+use std::env;
+use std::process;
+use std::time::{Duration, Instant};
+
+use octopus::config::{Config, Listener, Role};
+use octopus::http::server::Server;
+use octopus::signals::{self, Signals};
+
+use cli::{Command, Options};
+
+/// The exit status when connections had to be cut off at shutdown.
+const EXIT_NOT_DRAINED: i32 = 2;
+
+/// Put together the configuration from the file and command line given in
+/// `options`.
+fn load_config(options: &Options) -> Result<Config, String> {
+    let mut config = match options.config {
+        Some(ref path) => match Config::load(path) {
+            Ok(config) => config,
+            Err(e) => return Err(format!("Invalid configuration in {}: {}", path.display(), e)),
+        },
+        None => Config::default(),
+    };
+
+    if !options.listen.is_empty() {
+        config.listeners.clear();
+
+        for address in &options.listen {
+            if config.listeners.iter().any(|listener| listener.address == *address) {
+                return Err(format!("--listen {} is given more than once", address));
+            }
+
+            config.listeners.push(Listener {
+                address: address.clone(),
+                role: Role::Forward,
+            });
+        }
+    }
+
+    config.log_level = config.log_level.adjust(options.verbosity);
+
+    Ok(config)
+}
+
+/// Reload the configuration whenever SIGHUP arrives. A configuration that
+/// doesn't load is logged and otherwise ignored.
+///
+/// SIGTERM or SIGINT shut the server down, giving requests in progress until
+/// the drain timeout to finish. The process then exits, successfully only if
+/// they all did.
+fn handle_signals(signals: Signals, options: Options, server: Server, mut drain_timeout: Duration) -> ! {
+    let poll_interval = Duration::from_millis(signals::POLL_INTERVAL_MS);
+
+    loop {
+        mioco::sleep(poll_interval);
+
+        if signals.take_terminate() {
+            break;
+        }
+
+        if signals.take_hangup() {
+            info!("Reloading configuration");
+
+            match load_config(&options) {
+                Ok(config) => {
+                    octopus::logging::set_level(config.log_level);
+                    drain_timeout = config.drain_timeout;
+                    server.reload(config);
+                },
+                Err(e) => error!("Not reloading: {}", e),
+            }
+        }
+    }
+
+    info!("Shutting down, waiting up to {}s for {} connections", drain_timeout.as_secs(), server.active_connections());
+    server.shutdown();
+
+    let deadline = Instant::now() + drain_timeout;
+    while server.active_connections() > 0 && Instant::now() < deadline {
+        // Asking again means not waiting any longer.
+        if signals.take_terminate() {
+            break;
+        }
+
+        mioco::sleep(poll_interval);
+    }
+
+    let remaining = server.active_connections();
+    if remaining > 0 {
+        warn!("Exiting with {} connections still open", remaining);
+        process::exit(EXIT_NOT_DRAINED);
+    }
+
+    info!("All connections closed, exiting");
+    process::exit(0);
+}
 
 fn main() {
-    mioco::start(|| -> io::Result<()> {
-        let server = octopus::http::server::Server::new("127.0.0.1", 8000);
-        server.start()
-    }).unwrap().unwrap();
+    let options = cli::parse(env::args_os());
+
+    let config = match load_config(&options) {
+        Ok(config) => config,
+        Err(e) => fatal!("{}", e),
+    };
+
+    if options.command == Command::Check {
+        match options.config {
+            Some(ref path) => println!("{}: configuration is valid", path.display()),
+            None => println!("No configuration file given, the defaults are valid"),
+        }
+        return;
+    }
+
+    octopus::logging::set_level(config.log_level);
+
+    let signals = match Signals::catch() {
+        Ok(signals) => signals,
+        Err(e) => fatal!("Could not set up signal handlers: {}", e),
+    };
+
+    mioco::start(move || {
+        let drain_timeout = config.drain_timeout;
+        let server = Server::new(config);
+
+        let accepting = server.clone();
+        mioco::spawn(move || {
+            if let Err(e) = accepting.start() {
+                fatal!("Could not accept connections: {}", e);
+            }
+        });
+
+        // This is what keeps the process running, until it's time to exit.
+        handle_signals(signals, options, server, drain_timeout)
+    }).unwrap();
 }
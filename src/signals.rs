@@ -0,0 +1,46 @@
+extern crate signal_hook;
+
+use std::io;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// How often coroutines waiting on signals should check for them.
+pub const POLL_INTERVAL_MS: u64 = 250;
+
+/// Records signals sent to the process, to be dealt with later.
+///
+/// Signal handlers can't safely do much of anything, let alone switch
+/// coroutines, so all they do is set a flag that's polled for.
+pub struct Signals {
+    hangup: Arc<AtomicBool>,
+    terminate: Arc<AtomicBool>,
+}
+
+impl Signals {
+    /// Start catching signals. Until now they had their default effect.
+    pub fn catch() -> io::Result<Signals> {
+        let hangup = Arc::new(AtomicBool::new(false));
+        try!(signal_hook::flag::register(signal_hook::SIGHUP, hangup.clone()));
+
+        let terminate = Arc::new(AtomicBool::new(false));
+        try!(signal_hook::flag::register(signal_hook::SIGTERM, terminate.clone()));
+        try!(signal_hook::flag::register(signal_hook::SIGINT, terminate.clone()));
+
+        Ok(Signals {
+            hangup: hangup,
+            terminate: terminate,
+        })
+    }
+
+    /// Whether SIGHUP, asking for the configuration to be reloaded, has
+    /// arrived since this was last called.
+    pub fn take_hangup(&self) -> bool {
+        self.hangup.swap(false, Ordering::SeqCst)
+    }
+
+    /// Whether SIGTERM or SIGINT, asking for the process to exit, has arrived
+    /// since this was last called.
+    pub fn take_terminate(&self) -> bool {
+        self.terminate.swap(false, Ordering::SeqCst)
+    }
+}